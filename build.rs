@@ -1,18 +1,56 @@
 use anyhow::*;
-use fs_extra::copy_items;
-use fs_extra::dir::CopyOptions;
-use std::env;
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+// Files under `dir`, recursively
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
 
 fn main() -> Result<()> {
-    // // This tells Cargo to rerun this script if something in /res/ changes.
-    println!("cargo:rerun-if-changed=res/*");
+    // This tells Cargo to rerun this script if something in /res/ changes.
+    println!("cargo:rerun-if-changed=res");
+
+    // With the `embed-assets` feature `res/` is compiled into the binary, see
+    // `wgpu_things::vfs`
+    if env::var_os("CARGO_FEATURE_EMBED_ASSETS").is_some() {
+        let res_dir = Path::new(&env::var("CARGO_MANIFEST_DIR")?).join("res");
+        let mut files = Vec::new();
+        collect_files(&res_dir, &mut files)?;
+        files.sort();
 
-    let out_dir = env::var("OUT_DIR")?;
-    let mut copy_options = CopyOptions::new();
-    copy_options.overwrite = true;
-    let mut paths_to_copy = Vec::new();
-    paths_to_copy.push("res/");
-    copy_items(&paths_to_copy, out_dir, &copy_options)?;
+        let mut code = String::from("&[\n");
+        for file in &files {
+            let name = file
+                .strip_prefix(&res_dir)?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            writeln!(
+                code,
+                "    ({name:?}, include_bytes!({:?})),",
+                file.display()
+            )?;
+        }
+        code.push(']');
+        fs::write(
+            Path::new(&env::var("OUT_DIR")?).join("embedded_assets.rs"),
+            code,
+        )?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,59 @@
+//! Writes the mesh caches of every OBJ file under the asset root ahead of
+//! time, so even the first run loads them from their cache. Caches that are
+//! already up to date are left alone.
+//!
+//! `cargo run --bin bake_mesh_cache -- [asset root] [cache directory]`
+
+use gui::wgpu_things::{mesh_cache, vfs};
+use std::path::{Path, PathBuf};
+
+// OBJ files under `dir`, recursively
+fn collect_objs(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_objs(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "obj") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let root = PathBuf::from(args.next().unwrap_or_else(|| "res".to_string()));
+    let mut asset_vfs = vfs::Vfs::new();
+    asset_vfs.set_asset_root(&root);
+    vfs::configure(asset_vfs);
+    if let Some(dir) = args.next() {
+        mesh_cache::set_cache_dir(dir);
+    }
+
+    let mut files = Vec::new();
+    collect_objs(&root, &mut files)?;
+    files.sort();
+    for file in files {
+        // Named the way the loaders name it, relative to the root
+        let name = file
+            .strip_prefix(&root)?
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if mesh_cache::read(&name).is_ok() {
+            println!("{name}: up to date");
+            continue;
+        }
+        match pollster::block_on(mesh_cache::build(&name)) {
+            Ok((cache, _)) => {
+                mesh_cache::write(&name, &cache)?;
+                let path = mesh_cache::cache_path(&name).unwrap_or_default();
+                println!("{name}: wrote {}", path.display());
+            }
+            // Broken files are left for the load at run time to report
+            Err(err) => eprintln!("{name}: {err:#}"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,159 @@
+//! Writes simplified copies of an OBJ file next to it, one per ratio, named the
+//! way `resources::load_model_with_lods` looks for them.
+//!
+//! `cargo run --bin simplify_obj -- res/cube.obj 0.5 0.25 0.1`
+
+use gui::wgpu_things::{model::ModelVertex, simplify};
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_RATIOS: [f32; 3] = [0.5, 0.25, 0.1];
+
+struct ObjMesh {
+    name: String,
+    material: Option<String>,
+    // Missing attributes are left out of the output instead of written as zeros
+    has_tex_coords: bool,
+    has_normals: bool,
+    vertices: Vec<ModelVertex>,
+    indices: Vec<u32>,
+}
+
+fn load(path: &Path) -> anyhow::Result<(Vec<ObjMesh>, Option<String>)> {
+    let (models, materials) = tobj::load_obj(
+        path,
+        &tobj::LoadOptions {
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        },
+    )?;
+    // Only the names are needed, a missing MTL file isn't an error here
+    let materials = materials.unwrap_or_default();
+    let meshes = models
+        .into_iter()
+        .map(|m| {
+            let mesh = m.mesh;
+            let vertices = (0..mesh.positions.len() / 3)
+                .map(|i| ModelVertex {
+                    position: [
+                        mesh.positions[i * 3],
+                        mesh.positions[i * 3 + 1],
+                        mesh.positions[i * 3 + 2],
+                    ],
+                    tex_coords: match mesh.texcoords.get(i * 2..i * 2 + 2) {
+                        Some(uv) => [uv[0], uv[1]],
+                        None => [0.0, 0.0],
+                    },
+                    normal: match mesh.normals.get(i * 3..i * 3 + 3) {
+                        Some(n) => [n[0], n[1], n[2]],
+                        None => [0.0, 0.0, 0.0],
+                    },
+                    // Not written to the OBJ file
+                    tangent: [0.0; 4],
+                })
+                .collect();
+            ObjMesh {
+                name: m.name,
+                material: mesh
+                    .material_id
+                    .and_then(|id| materials.get(id))
+                    .map(|material| material.name.clone()),
+                has_tex_coords: !mesh.texcoords.is_empty(),
+                has_normals: !mesh.normals.is_empty(),
+                vertices,
+                indices: mesh.indices,
+            }
+        })
+        .collect();
+
+    let mtllib = std::fs::read_to_string(path)?
+        .lines()
+        .find_map(|line| line.strip_prefix("mtllib "))
+        .map(|name| name.trim().to_string());
+    Ok((meshes, mtllib))
+}
+
+fn to_obj(meshes: &[ObjMesh], mtllib: Option<&str>, comment: &str) -> String {
+    let mut obj = format!("# {comment}\n");
+    if let Some(mtllib) = mtllib {
+        writeln!(obj, "mtllib {mtllib}").unwrap();
+    }
+    // OBJ indices are 1 based and shared by all the objects of the file
+    let mut offset = 1;
+    for mesh in meshes {
+        writeln!(obj, "o {}", mesh.name).unwrap();
+        for v in &mesh.vertices {
+            let [x, y, z] = v.position;
+            writeln!(obj, "v {x} {y} {z}").unwrap();
+        }
+        if mesh.has_tex_coords {
+            for v in &mesh.vertices {
+                let [u, v] = v.tex_coords;
+                writeln!(obj, "vt {u} {v}").unwrap();
+            }
+        }
+        if mesh.has_normals {
+            for v in &mesh.vertices {
+                let [x, y, z] = v.normal;
+                writeln!(obj, "vn {x} {y} {z}").unwrap();
+            }
+        }
+        if let Some(material) = &mesh.material {
+            writeln!(obj, "usemtl {material}").unwrap();
+        }
+        let corner = |i: u32| match (mesh.has_tex_coords, mesh.has_normals) {
+            (true, true) => format!("{i}/{i}/{i}"),
+            (true, false) => format!("{i}/{i}"),
+            (false, true) => format!("{i}//{i}"),
+            (false, false) => i.to_string(),
+        };
+        for tri in mesh.indices.chunks_exact(3) {
+            let [a, b, c] = [tri[0], tri[1], tri[2]].map(|i| corner(i + offset));
+            writeln!(obj, "f {a} {b} {c}").unwrap();
+        }
+        offset += mesh.vertices.len() as u32;
+    }
+    obj
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let Some(input) = args.next().map(PathBuf::from) else {
+        anyhow::bail!("usage: simplify_obj <file.obj> [ratio...]");
+    };
+    let mut ratios = args
+        .map(|arg| arg.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    if ratios.is_empty() {
+        ratios = DEFAULT_RATIOS.to_vec();
+    }
+
+    let (meshes, mtllib) = load(&input)?;
+    for (index, ratio) in ratios.into_iter().enumerate() {
+        let simplified = meshes
+            .iter()
+            .map(|mesh| {
+                let (vertices, indices) = simplify::simplify(&mesh.vertices, &mesh.indices, ratio);
+                ObjMesh {
+                    name: mesh.name.clone(),
+                    material: mesh.material.clone(),
+                    has_tex_coords: mesh.has_tex_coords,
+                    has_normals: mesh.has_normals,
+                    vertices,
+                    indices,
+                }
+            })
+            .collect::<Vec<_>>();
+        let output = simplify::lod_file_name(&input.to_string_lossy(), index + 1);
+        let comment = format!("{} simplified to {ratio} of its triangles", input.display());
+        std::fs::write(&output, to_obj(&simplified, mtllib.as_deref(), &comment))?;
+
+        let before: usize = meshes.iter().map(|mesh| mesh.indices.len() / 3).sum();
+        let after: usize = simplified.iter().map(|mesh| mesh.indices.len() / 3).sum();
+        println!("{output}: {before} -> {after} triangles");
+    }
+    Ok(())
+}
@@ -1,5 +1,42 @@
-use gui::run;
+use gui::{
+    run_with,
+    wgpu_things::{camera::DepthMode, mesh_cache, vfs, viewport::ViewportLayout},
+    CullingMode, RenderSettings,
+};
 
 fn main() {
-    pollster::block_on(run()).unwrap();
+    if let Some(root) = std::env::args()
+        .skip_while(|arg| arg != "--asset-root")
+        .nth(1)
+    {
+        let mut asset_vfs = vfs::Vfs::default();
+        asset_vfs.set_asset_root(root);
+        vfs::configure(asset_vfs);
+    }
+    if let Some(dir) = std::env::args()
+        .skip_while(|arg| arg != "--mesh-cache-dir")
+        .nth(1)
+    {
+        mesh_cache::set_cache_dir(dir);
+    }
+    if std::env::args().any(|arg| arg == "--no-mesh-cache") {
+        mesh_cache::set_enabled(false);
+    }
+    let mut settings = RenderSettings::default();
+    if std::env::args().any(|arg| arg == "--reverse-z") {
+        settings.depth_mode = DepthMode::ReverseZ;
+    }
+    if std::env::args().any(|arg| arg == "--split-screen") {
+        settings.viewport_layout = ViewportLayout::SideBySide;
+    } else if std::env::args().any(|arg| arg == "--minimap") {
+        settings.viewport_layout = ViewportLayout::Inset;
+    }
+    if std::env::args().any(|arg| arg == "--no-culling") {
+        settings.culling = CullingMode::None;
+    } else if std::env::args().any(|arg| arg == "--gpu-culling") {
+        settings.culling = CullingMode::Gpu;
+    }
+    settings.robot_arm = std::env::args().any(|arg| arg == "--robot-arm");
+    settings.hot_reload = std::env::args().any(|arg| arg == "--hot-reload");
+    pollster::block_on(run_with(settings)).unwrap();
 }
@@ -1,2 +1,2 @@
-mod wgpu_things;
-pub use wgpu_things::renderer::run;
+pub mod wgpu_things;
+pub use wgpu_things::renderer::{run, run_with, CullingMode, RenderSettings};
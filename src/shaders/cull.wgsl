@@ -0,0 +1,54 @@
+// Frustum culling of instances, the count is copied into the arguments of
+// every draw_indexed_indirect
+struct InstanceRaw {
+    model: mat4x4<f32>,
+    tint: vec4<f32>,
+    data: vec4<f32>,
+};
+
+struct CullParams {
+    // xyz is the normal, w the distance, points inside have dot(n, p) + w >= 0
+    planes: array<vec4<f32>, 6>,
+    // Bounding sphere of the mesh in model space, w is the radius
+    sphere: vec4<f32>,
+    instance_count: u32,
+};
+
+@group(0) @binding(0)
+var<storage, read> instances: array<InstanceRaw>;
+@group(0) @binding(1)
+var<uniform> params: CullParams;
+@group(0) @binding(2)
+var<storage, read_write> visible_instances: array<u32>;
+@group(0) @binding(3)
+var<storage, read_write> visible_count: atomic<u32>;
+
+@compute @workgroup_size(64)
+fn cs_main(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+) {
+    // Big instance counts are split in rows of workgroups
+    let index = id.x + id.y * num_workgroups.x * 64u;
+    if index >= params.instance_count {
+        return;
+    }
+
+    let model = instances[index].model;
+    let center = (model * vec4<f32>(params.sphere.xyz, 1.0)).xyz;
+    let scale = sqrt(max(
+        max(dot(model[0].xyz, model[0].xyz), dot(model[1].xyz, model[1].xyz)),
+        dot(model[2].xyz, model[2].xyz),
+    ));
+    let radius = params.sphere.w * scale;
+
+    for (var i = 0u; i < 6u; i++) {
+        let plane = params.planes[i];
+        if dot(plane.xyz, center) + plane.w < -radius {
+            return;
+        }
+    }
+
+    let slot = atomicAdd(&visible_count, 1u);
+    visible_instances[slot] = index;
+}
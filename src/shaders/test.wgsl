@@ -2,6 +2,15 @@
 // Vertex shader
 struct CameraUniform {
     view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+    proj: mat4x4<f32>,
+    inv_view: mat4x4<f32>,
+    inv_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+    position: vec4<f32>,
+    viewport_size: vec2<f32>,
+    znear: f32,
+    zfar: f32,
 };
 @group(1) @binding(0) // 1.
 var<uniform> camera: CameraUniform;
@@ -9,6 +18,9 @@ var<uniform> camera: CameraUniform;
 struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+    // Handedness of the bitangent in w
+    @location(3) tangent: vec4<f32>,
 }
 
 struct InstanceInput {
@@ -16,11 +28,61 @@ struct InstanceInput {
     @location(6) model_matrix_1: vec4<f32>,
     @location(7) model_matrix_2: vec4<f32>,
     @location(8) model_matrix_3: vec4<f32>,
+    @location(9) tint: vec4<f32>,
+    @location(10) data: vec4<f32>,
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
+    @location(1) tint: vec4<f32>,
+    @location(2) @interpolate(flat) data: vec4<f32>,
+    @location(3) world_position: vec3<f32>,
+    @location(4) world_normal: vec3<f32>,
+    @location(5) world_tangent: vec4<f32>,
+}
+
+// Instances as laid out by InstanceRaw, for the GPU culled path
+struct InstanceRaw {
+    model: mat4x4<f32>,
+    tint: vec4<f32>,
+    data: vec4<f32>,
+};
+@group(2) @binding(0)
+var<storage, read> instances: array<InstanceRaw>;
+// Indices into `instances` of the instances that passed the culling
+@group(2) @binding(1)
+var<storage, read> visible_instances: array<u32>;
+
+fn transform_vertex(
+    model: VertexInput,
+    model_matrix: mat4x4<f32>,
+    tint: vec4<f32>,
+    data: vec4<f32>,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = model.tex_coords;
+    out.tint = tint;
+    out.data = data;
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    out.world_position = world_position.xyz;
+    // Inverse transpose of the upper 3x3, scaled by its determinant which the
+    // normalization in the fragment shader removes again. The sign is kept so
+    // mirrored instances still get outward normals.
+    let x = model_matrix[0].xyz;
+    let y = model_matrix[1].xyz;
+    let z = model_matrix[2].xyz;
+    let normal_matrix = mat3x3<f32>(cross(y, z), cross(z, x), cross(x, y));
+    let handedness = sign(dot(x, cross(y, z)));
+    out.world_normal = normal_matrix * model.normal * handedness;
+    // Tangents lie in the surface so they transform like positions. Mirroring
+    // flips the bitangent, which the sign in w makes up for.
+    out.world_tangent = vec4<f32>(
+        (model_matrix * vec4<f32>(model.tangent.xyz, 0.0)).xyz,
+        model.tangent.w * handedness,
+    );
+    out.clip_position = camera.view_proj * world_position;
+    return out;
 }
 
 @vertex
@@ -34,21 +96,56 @@ fn vs_main(
         instance.model_matrix_2,
         instance.model_matrix_3,
     );
-    var out: VertexOutput;
-    out.tex_coords = model.tex_coords;
-    out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
-    return out;
+    return transform_vertex(model, model_matrix, instance.tint, instance.data);
+}
+
+@vertex
+fn vs_main_indirect(
+    model: VertexInput,
+    @builtin(instance_index) instance_index: u32,
+) -> VertexOutput {
+    let instance = instances[visible_instances[instance_index]];
+    return transform_vertex(model, instance.model, instance.tint, instance.data);
 }
  
 
  // Fragment shader
 
+// Laid out by MaterialUniform in model.rs
+struct MaterialUniform {
+    base_color: vec4<f32>,
+    ambient: vec4<f32>,
+    // Shininess in w
+    specular: vec4<f32>,
+    emissive: vec4<f32>,
+    // Metallic and roughness in x and y
+    pbr: vec4<f32>,
+};
+
 @group(0) @binding(0)
 var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
+@group(0) @binding(2)
+var<uniform> material: MaterialUniform;
+
+// A fixed white light coming from above
+const LIGHT_DIRECTION = vec3<f32>(0.3713907, 0.7427814, 0.557086);
+const AMBIENT_STRENGTH = 0.2;
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let albedo = textureSample(t_diffuse, s_diffuse, in.tex_coords) * material.base_color * in.tint;
+
+    // Blinn-Phong with the MTL colours
+    let normal = normalize(in.world_normal);
+    let view_direction = normalize(camera.position.xyz - in.world_position);
+    let half_direction = normalize(view_direction + LIGHT_DIRECTION);
+    let diffuse = max(dot(normal, LIGHT_DIRECTION), 0.0);
+    let specular = pow(max(dot(normal, half_direction), 0.0), max(material.specular.w, 1.0));
+
+    let color = albedo.rgb * (material.ambient.rgb * AMBIENT_STRENGTH + diffuse)
+        + material.specular.rgb * specular
+        + material.emissive.rgb;
+    return vec4<f32>(color, albedo.a);
 }
\ No newline at end of file
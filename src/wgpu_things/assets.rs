@@ -0,0 +1,326 @@
+//! Shared, reference counted assets cached by path.
+//!
+//! The manager only keeps weak references: an asset is freed, with its GPU
+//! buffers and textures, once the last `Handle` to it is dropped, and the next
+//! request for the same path loads it again.
+
+use super::{
+    model::{Material, Mesh, Model},
+    resources, vfs, Texture,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::Deref,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// Shared reference to an asset of type `T`, cloning it is cheap.
+pub struct Handle<T>(Arc<T>);
+
+impl<T> Handle<T> {
+    /// Handle to an asset the manager doesn't know about.
+    pub fn new(asset: T) -> Self {
+        Self(Arc::new(asset))
+    }
+
+    /// Number of handles to the asset, this one included.
+    pub fn ref_count(&self) -> usize {
+        Arc::strong_count(&self.0)
+    }
+
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        Arc::ptr_eq(&a.0, &b.0)
+    }
+
+    /// Mutable access to the asset, which is cloned first when other handles
+    /// share it.
+    pub fn make_mut(this: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        Arc::make_mut(&mut this.0)
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Deref for Handle<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handle({:p})", Arc::as_ptr(&self.0))
+    }
+}
+
+struct Entry<T> {
+    asset: Weak<T>,
+    // Files the asset was loaded from
+    files: Vec<PathBuf>,
+}
+
+struct Cache<T> {
+    entries: Mutex<HashMap<String, Entry<T>>>,
+}
+
+impl<T> Cache<T> {
+    fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reports the files of the asset to the current `vfs::Recording`.
+    fn get(&self, key: &str) -> Option<Handle<T>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        let handle = entry.asset.upgrade().map(Handle)?;
+        vfs::note_reads(&entry.files);
+        Some(handle)
+    }
+
+    // Loads racing for the same key all get the asset of the first one to
+    // finish, the others are dropped
+    fn insert(&self, key: &str, asset: T, files: Vec<PathBuf>) -> Handle<T> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(handle) = entries.get(key).and_then(|entry| entry.asset.upgrade()) {
+            return Handle(handle);
+        }
+        let handle = Handle::new(asset);
+        let entry = Entry {
+            asset: Arc::downgrade(&handle.0),
+            files,
+        };
+        entries.insert(key.to_string(), entry);
+        handle
+    }
+
+    // Forgets the assets loaded from one of `files`, returns how many there were
+    fn forget_files(&self, files: &[PathBuf]) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|_, entry| !entry.files.iter().any(|file| files.contains(file)));
+        len - entries.len()
+    }
+
+    // Forgets the freed assets, returns how many there were
+    fn purge(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|_, entry| entry.asset.strong_count() > 0);
+        len - entries.len()
+    }
+
+    fn live(&self) -> usize {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .values()
+            .filter(|entry| entry.asset.strong_count() > 0)
+            .count()
+    }
+}
+
+// Files of an asset inserted directly, `key` up to any `#` is its file name
+fn key_files(key: &str) -> Vec<PathBuf> {
+    let file_name = key.split('#').next().unwrap_or(key);
+    vfs::resolve(file_name).into_iter().collect()
+}
+
+/// Number of assets of each type still in use.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct AssetStats {
+    pub textures: usize,
+    pub meshes: usize,
+    pub materials: usize,
+    pub models: usize,
+}
+
+// Key of the 1x1 white texture used by materials without one
+const WHITE_TEXTURE: &str = "#white";
+
+/// Cache of textures, meshes, materials and models. Paths are relative to the
+/// `res` directory, assets that don't come from a file of their own use keys
+/// such as `"scene.glb#image0"`. All the methods take `&self` so the manager
+/// can be shared between threads.
+pub struct AssetManager {
+    textures: Cache<Texture>,
+    meshes: Cache<Mesh>,
+    materials: Cache<Material>,
+    models: Cache<Model>,
+}
+
+impl AssetManager {
+    pub fn new() -> Self {
+        Self {
+            textures: Cache::new(),
+            meshes: Cache::new(),
+            materials: Cache::new(),
+            models: Cache::new(),
+        }
+    }
+
+    pub fn texture(&self, key: &str) -> Option<Handle<Texture>> {
+        self.textures.get(key)
+    }
+
+    /// Caches `texture` under `key`, unless a live one is already cached there,
+    /// which is returned instead. The same goes for the other `insert_` methods.
+    pub fn insert_texture(&self, key: &str, texture: Texture) -> Handle<Texture> {
+        self.textures.insert(key, texture, key_files(key))
+    }
+
+    pub async fn load_texture(
+        &self,
+        file_name: &str,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<Handle<Texture>> {
+        if let Some(texture) = self.texture(file_name) {
+            return Ok(texture);
+        }
+        let recording = vfs::Recording::start();
+        let texture = resources::load_texture(file_name, device, queue).await?;
+        Ok(self.textures.insert(file_name, texture, recording.finish()))
+    }
+
+    /// Shared 1x1 white texture.
+    pub fn white_texture(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Handle<Texture> {
+        self.texture(WHITE_TEXTURE).unwrap_or_else(|| {
+            let texture = Texture::from_color(device, queue, [255; 4], "white");
+            self.insert_texture(WHITE_TEXTURE, texture)
+        })
+    }
+
+    pub fn mesh(&self, key: &str) -> Option<Handle<Mesh>> {
+        self.meshes.get(key)
+    }
+
+    /// `files` are the files the mesh was made from, see `forget_files`.
+    pub fn insert_mesh(&self, key: &str, mesh: Mesh, files: Vec<PathBuf>) -> Handle<Mesh> {
+        self.meshes.insert(key, mesh, files)
+    }
+
+    pub fn material(&self, key: &str) -> Option<Handle<Material>> {
+        self.materials.get(key)
+    }
+
+    /// `files` are the files the material was made from, its texture's included.
+    pub fn insert_material(
+        &self,
+        key: &str,
+        material: Material,
+        files: Vec<PathBuf>,
+    ) -> Handle<Material> {
+        self.materials.insert(key, material, files)
+    }
+
+    pub fn model(&self, key: &str) -> Option<Handle<Model>> {
+        self.models.get(key)
+    }
+
+    pub fn insert_model(&self, key: &str, model: Model) -> Handle<Model> {
+        self.models.insert(key, model, key_files(key))
+    }
+
+    /// Loads an OBJ or glTF model, with its meshes, materials and textures
+    /// shared with the other models.
+    pub async fn load_model(
+        &self,
+        file_name: &str,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> anyhow::Result<Handle<Model>> {
+        if let Some(model) = self.model(file_name) {
+            return Ok(model);
+        }
+        let recording = vfs::Recording::start();
+        let model = resources::load_model(file_name, device, queue, layout, self).await?;
+        Ok(self.models.insert(file_name, model, recording.finish()))
+    }
+
+    /// Forgets the assets loaded from any of `files` so the next request loads
+    /// them again. Handles to them stay valid. Returns how many there were.
+    pub fn forget_files(&self, files: &[PathBuf]) -> usize {
+        self.textures.forget_files(files)
+            + self.meshes.forget_files(files)
+            + self.materials.forget_files(files)
+            + self.models.forget_files(files)
+    }
+
+    /// Forgets the assets that were freed, returns how many there were.
+    pub fn purge(&self) -> usize {
+        self.textures.purge() + self.meshes.purge() + self.materials.purge() + self.models.purge()
+    }
+
+    pub fn stats(&self) -> AssetStats {
+        AssetStats {
+            textures: self.textures.live(),
+            meshes: self.meshes.live(),
+            materials: self.materials.live(),
+            models: self.models.live(),
+        }
+    }
+}
+
+impl Default for AssetManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn racing_inserts_share_the_first_asset() {
+        let cache = Cache::new();
+        let first = cache.insert("a", "first".to_string(), Vec::new());
+        let second = cache.insert("a", "second".to_string(), Vec::new());
+        assert!(Handle::ptr_eq(&first, &second));
+        assert_eq!(*second, "first");
+        assert_eq!(cache.live(), 1);
+    }
+
+    #[test]
+    fn freed_assets_are_purged() {
+        let cache = Cache::new();
+        let kept = cache.insert("kept", 1, Vec::new());
+        drop(cache.insert("freed", 2, Vec::new()));
+        assert!(cache.get("freed").is_none());
+        assert_eq!(cache.purge(), 1);
+        assert_eq!(cache.purge(), 0);
+        assert!(Handle::ptr_eq(&cache.get("kept").unwrap(), &kept));
+
+        // A freed entry doesn't stop a new asset from being cached
+        let again = cache.insert("freed", 3, Vec::new());
+        assert_eq!(*cache.get("freed").unwrap(), *again);
+    }
+
+    #[test]
+    fn forgotten_files_are_loaded_again() {
+        let cache = Cache::new();
+        let mtl = PathBuf::from("res/cube.mtl");
+        let material = cache.insert("material", 1, vec![mtl.clone()]);
+        let _mesh = cache.insert("mesh", 2, vec![PathBuf::from("res/cube.obj")]);
+        assert_eq!(cache.forget_files(&[mtl]), 1);
+        assert!(cache.get("material").is_none());
+        assert!(cache.get("mesh").is_some());
+
+        // The old handle stays valid next to the new asset
+        let reloaded = cache.insert("material", 10, Vec::new());
+        assert_eq!((*material, *reloaded), (1, 10));
+    }
+}
@@ -0,0 +1,152 @@
+use cgmath::prelude::*;
+
+/// Axis aligned bounding box.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: cgmath::Point3<f32>,
+    pub max: cgmath::Point3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: cgmath::Point3<f32>, max: cgmath::Point3<f32>) -> Self {
+        Self { min, max }
+    }
+
+    /// A box that contains nothing, growing it with any point gives a box around that point.
+    pub fn empty() -> Self {
+        Self {
+            min: cgmath::Point3::new(f32::MAX, f32::MAX, f32::MAX),
+            max: cgmath::Point3::new(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    pub fn from_points(points: impl IntoIterator<Item = cgmath::Point3<f32>>) -> Self {
+        points
+            .into_iter()
+            .fold(Self::empty(), |aabb, p| aabb.grow(p))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(self, point: cgmath::Point3<f32>) -> Self {
+        Self {
+            min: cgmath::Point3::new(
+                self.min.x.min(point.x),
+                self.min.y.min(point.y),
+                self.min.z.min(point.z),
+            ),
+            max: cgmath::Point3::new(
+                self.max.x.max(point.x),
+                self.max.y.max(point.y),
+                self.max.z.max(point.z),
+            ),
+        }
+    }
+
+    pub fn union(self, other: Aabb) -> Self {
+        if other.is_empty() {
+            return self;
+        }
+        self.grow(other.min).grow(other.max)
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x
+            && self.max.x >= other.min.x
+            && self.min.y <= other.max.y
+            && self.max.y >= other.min.y
+            && self.min.z <= other.max.z
+            && self.max.z >= other.min.z
+    }
+
+    /// Squared distance from `point` to the closest point of the box, 0 inside of it.
+    pub fn distance2(&self, point: cgmath::Point3<f32>) -> f32 {
+        let outside = |p: f32, min: f32, max: f32| (min - p).max(p - max).max(0.0);
+        cgmath::Vector3::new(
+            outside(point.x, self.min.x, self.max.x),
+            outside(point.y, self.min.y, self.max.y),
+            outside(point.z, self.min.z, self.max.z),
+        )
+        .magnitude2()
+    }
+
+    pub fn center(&self) -> cgmath::Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    pub fn extent(&self) -> cgmath::Vector3<f32> {
+        self.max - self.min
+    }
+
+    pub fn corners(&self) -> [cgmath::Point3<f32>; 8] {
+        let (min, max) = (self.min, self.max);
+        [
+            cgmath::Point3::new(min.x, min.y, min.z),
+            cgmath::Point3::new(max.x, min.y, min.z),
+            cgmath::Point3::new(min.x, max.y, min.z),
+            cgmath::Point3::new(max.x, max.y, min.z),
+            cgmath::Point3::new(min.x, min.y, max.z),
+            cgmath::Point3::new(max.x, min.y, max.z),
+            cgmath::Point3::new(min.x, max.y, max.z),
+            cgmath::Point3::new(max.x, max.y, max.z),
+        ]
+    }
+
+    /// Bounds of this box after going through `matrix`, still axis aligned.
+    pub fn transform(&self, matrix: &cgmath::Matrix4<f32>) -> Self {
+        if self.is_empty() {
+            return *self;
+        }
+        Self::from_points(
+            self.corners()
+                .iter()
+                .map(|corner| matrix.transform_point(*corner)),
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BoundingSphere {
+    pub center: cgmath::Point3<f32>,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    pub fn new(center: cgmath::Point3<f32>, radius: f32) -> Self {
+        Self { center, radius }
+    }
+
+    /// Sphere centered on the points bounding box, not the smallest one but
+    /// cheap and never worse than the box itself.
+    pub fn from_points(points: impl IntoIterator<Item = cgmath::Point3<f32>> + Clone) -> Self {
+        let aabb = Aabb::from_points(points.clone());
+        if aabb.is_empty() {
+            return Self::new(cgmath::Point3::origin(), 0.0);
+        }
+        let center = aabb.center();
+        let radius = points
+            .into_iter()
+            .map(|p| p.distance2(center))
+            .fold(0.0, f32::max)
+            .sqrt();
+        Self { center, radius }
+    }
+
+    /// Sphere containing this one after going through `matrix`, the radius grows
+    /// with the biggest scale of the matrix.
+    pub fn transform(&self, matrix: &cgmath::Matrix4<f32>) -> Self {
+        let scale = matrix
+            .x
+            .truncate()
+            .magnitude2()
+            .max(matrix.y.truncate().magnitude2())
+            .max(matrix.z.truncate().magnitude2())
+            .sqrt();
+        Self {
+            center: matrix.transform_point(self.center),
+            radius: self.radius * scale,
+        }
+    }
+}
@@ -0,0 +1,444 @@
+use super::{
+    bounds::Aabb,
+    culling::Frustum,
+    model::Mesh,
+    picking::{Ray, RayHit},
+    Instance, InstancesVec,
+};
+use std::ops::Range;
+
+const MAX_LEAF_SIZE: usize = 4;
+const NO_PARENT: usize = usize::MAX;
+const INNER_NODE: usize = usize::MAX;
+
+#[derive(Debug, Copy, Clone)]
+struct BvhNode {
+    aabb: Aabb,
+    parent: usize,
+    // Leaves cover `items[first..first + count]`. Inner nodes have a count of
+    // `INNER_NODE` and their children at `first` and `first + 1`.
+    first: usize,
+    count: usize,
+}
+
+impl BvhNode {
+    fn is_leaf(&self) -> bool {
+        self.count != INNER_NODE
+    }
+
+    fn items(&self) -> Range<usize> {
+        self.first..self.first + self.count
+    }
+
+    fn children(&self) -> [usize; 2] {
+        [self.first, self.first + 1]
+    }
+}
+
+/// Bounding volume hierarchy over the world space boxes of the instances of a mesh.
+///
+/// Moving instances only refits the boxes of the nodes above them, the tree
+/// itself is kept. After big changes in the layout the tree gets loose and
+/// queries slow down, `build` it again then.
+pub struct InstanceBvh {
+    mesh_aabb: Aabb,
+    // Parents always come before their children
+    nodes: Vec<BvhNode>,
+    // Instance indices, grouped by leaf
+    items: Vec<usize>,
+    // World bounds of each instance
+    bounds: Vec<Aabb>,
+    // Leaf holding each instance
+    leaves: Vec<usize>,
+}
+
+impl InstanceBvh {
+    /// Splits the instances at the median of the longest axis until the leaves
+    /// are small enough.
+    pub fn build(mesh_aabb: Aabb, instances: &[Instance]) -> Self {
+        let bounds = instances
+            .iter()
+            .map(|instance| mesh_aabb.transform(&instance.to_matrix()))
+            .collect::<Vec<_>>();
+        let len = bounds.len();
+        let mut bvh = Self {
+            mesh_aabb,
+            nodes: vec![BvhNode {
+                aabb: Aabb::empty(),
+                parent: NO_PARENT,
+                first: 0,
+                count: len,
+            }],
+            items: (0..len).collect(),
+            bounds,
+            leaves: vec![0; len],
+        };
+
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = bvh.nodes[index];
+            bvh.nodes[index].aabb = bvh.leaf_bounds(node.items());
+            if node.count <= MAX_LEAF_SIZE {
+                for &item in &bvh.items[node.items()] {
+                    bvh.leaves[item] = index;
+                }
+                continue;
+            }
+
+            let centers = Aabb::from_points(
+                bvh.items[node.items()]
+                    .iter()
+                    .map(|&item| bvh.bounds[item].center()),
+            );
+            let extent = centers.extent();
+            let axis = if extent.x >= extent.y && extent.x >= extent.z {
+                0
+            } else if extent.y >= extent.z {
+                1
+            } else {
+                2
+            };
+            let half = node.count / 2;
+            let bounds = &bvh.bounds;
+            bvh.items[node.items()].select_nth_unstable_by(half, |&a, &b| {
+                bounds[a].center()[axis].total_cmp(&bounds[b].center()[axis])
+            });
+
+            let left = bvh.nodes.len();
+            for (first, count) in [(node.first, half), (node.first + half, node.count - half)] {
+                bvh.nodes.push(BvhNode {
+                    aabb: Aabb::empty(),
+                    parent: index,
+                    first,
+                    count,
+                });
+            }
+            bvh.nodes[index].first = left;
+            bvh.nodes[index].count = INNER_NODE;
+            stack.extend([left, left + 1]);
+        }
+        bvh
+    }
+
+    pub fn len(&self) -> usize {
+        self.bounds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bounds.is_empty()
+    }
+
+    /// World bounds of an instance.
+    pub fn bounds(&self, index: usize) -> Aabb {
+        self.bounds[index]
+    }
+
+    /// Bounds of every instance.
+    pub fn root_bounds(&self) -> Aabb {
+        self.nodes[0].aabb
+    }
+
+    fn leaf_bounds(&self, items: Range<usize>) -> Aabb {
+        self.items[items]
+            .iter()
+            .fold(Aabb::empty(), |aabb, &item| aabb.union(self.bounds[item]))
+    }
+
+    fn node_bounds(&self, index: usize) -> Aabb {
+        let node = &self.nodes[index];
+        if node.is_leaf() {
+            self.leaf_bounds(node.items())
+        } else {
+            let [left, right] = node.children();
+            self.nodes[left].aabb.union(self.nodes[right].aabb)
+        }
+    }
+
+    /// Moves one instance and refits the nodes above it.
+    pub fn update(&mut self, index: usize, instance: &Instance) {
+        self.bounds[index] = self.mesh_aabb.transform(&instance.to_matrix());
+        let mut node = self.leaves[index];
+        while node != NO_PARENT {
+            let aabb = self.node_bounds(node);
+            if aabb == self.nodes[node].aabb {
+                // Nothing above this node changes either
+                break;
+            }
+            self.nodes[node].aabb = aabb;
+            node = self.nodes[node].parent;
+        }
+    }
+
+    /// Refits every node to the new instance transforms, rebuilds when
+    /// instances were added or removed.
+    pub fn refit(&mut self, instances: &[Instance]) {
+        if instances.len() != self.len() {
+            *self = Self::build(self.mesh_aabb, instances);
+            return;
+        }
+        for (bounds, instance) in self.bounds.iter_mut().zip(instances) {
+            *bounds = self.mesh_aabb.transform(&instance.to_matrix());
+        }
+        for index in (0..self.nodes.len()).rev() {
+            self.nodes[index].aabb = self.node_bounds(index);
+        }
+    }
+
+    /// Catches up with the instances changed since the last upload of `instances`,
+    /// must be called before `InstancesVec::upload` clears the dirty ranges.
+    pub fn sync(&mut self, instances: &InstancesVec) {
+        let dirty = instances.dirty_ranges();
+        if dirty.is_empty() && instances.len() == self.len() {
+            return;
+        }
+        let changed: usize = dirty.iter().map(|range| range.len()).sum();
+        // Walking up from many leaves costs more than one pass over the tree
+        if instances.len() != self.len() || changed > self.len() / 4 {
+            self.refit(instances.instances());
+            return;
+        }
+        for range in dirty {
+            for index in range.clone() {
+                self.update(index, &instances.instances()[index]);
+            }
+        }
+    }
+
+    /// Closest instance of `mesh` hit by a world space ray. Nodes are visited
+    /// front to back and skipped once they are further than the best hit.
+    pub fn pick(&self, ray: &Ray, mesh: &Mesh, instances: &[Instance]) -> Option<RayHit> {
+        let mut best: Option<RayHit> = None;
+        let mut stack = Vec::new();
+        if let Some(distance) = ray.intersect_aabb(&self.nodes[0].aabb) {
+            stack.push((0, distance));
+        }
+        while let Some((index, distance)) = stack.pop() {
+            if best.is_some_and(|hit| hit.distance < distance) {
+                continue;
+            }
+            let node = &self.nodes[index];
+            if node.is_leaf() {
+                for &item in &self.items[node.items()] {
+                    if ray.intersect_aabb(&self.bounds[item]).is_none() {
+                        continue;
+                    }
+                    if let Some(hit) = ray.intersect_instance(mesh, item, &instances[item]) {
+                        let closer = match best {
+                            Some(best) => hit.distance < best.distance,
+                            None => true,
+                        };
+                        if closer {
+                            best = Some(hit);
+                        }
+                    }
+                }
+                continue;
+            }
+            let mut children = node
+                .children()
+                .into_iter()
+                .filter_map(|child| Some((child, ray.intersect_aabb(&self.nodes[child].aabb)?)))
+                .collect::<Vec<_>>();
+            // The closest child is popped first
+            children.sort_by(|a, b| b.1.total_cmp(&a.1));
+            stack.extend(children);
+        }
+        best
+    }
+
+    /// Instances whose bounds touch the frustum.
+    pub fn query_frustum(&self, frustum: &Frustum) -> Vec<usize> {
+        self.query(|aabb| frustum.intersects_aabb(aabb))
+    }
+
+    /// Instances whose bounds touch `aabb`.
+    pub fn query_aabb(&self, aabb: &Aabb) -> Vec<usize> {
+        self.query(|bounds| bounds.intersects(aabb))
+    }
+
+    /// Instances whose bounds are at most `radius` away from `point`.
+    pub fn query_radius(&self, point: cgmath::Point3<f32>, radius: f32) -> Vec<usize> {
+        let radius2 = radius * radius;
+        self.query(|aabb| aabb.distance2(point) <= radius2)
+    }
+
+    fn query(&self, overlaps: impl Fn(&Aabb) -> bool) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !overlaps(&node.aabb) {
+                continue;
+            }
+            if node.is_leaf() {
+                found.extend(
+                    self.items[node.items()]
+                        .iter()
+                        .filter(|&&item| overlaps(&self.bounds[item])),
+                );
+            } else {
+                stack.extend(node.children());
+            }
+        }
+        found
+    }
+
+    /// Instance with the bounds closest to `point` and the distance to them,
+    /// 0 when the point is inside of the bounds.
+    pub fn nearest(&self, point: cgmath::Point3<f32>) -> Option<(usize, f32)> {
+        let mut best: Option<(usize, f32)> = None;
+        let mut stack = vec![(0, self.nodes[0].aabb.distance2(point))];
+        while let Some((index, distance2)) = stack.pop() {
+            if best.is_some_and(|(_, best)| best <= distance2) {
+                continue;
+            }
+            let node = &self.nodes[index];
+            if node.is_leaf() {
+                for &item in &self.items[node.items()] {
+                    let distance2 = self.bounds[item].distance2(point);
+                    let closer = match best {
+                        Some((_, best)) => distance2 < best,
+                        None => true,
+                    };
+                    if closer {
+                        best = Some((item, distance2));
+                    }
+                }
+                continue;
+            }
+            let [left, right] = node.children().map(|child| {
+                let distance2 = self.nodes[child].aabb.distance2(point);
+                (child, distance2)
+            });
+            // The closest child is popped first
+            if left.1 <= right.1 {
+                stack.extend([right, left]);
+            } else {
+                stack.extend([left, right]);
+            }
+        }
+        best.map(|(item, distance2)| (item, distance2.sqrt()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::prelude::*;
+
+    fn unit_box() -> Aabb {
+        Aabb::new(
+            cgmath::Point3::new(-0.5, -0.5, -0.5),
+            cgmath::Point3::new(0.5, 0.5, 0.5),
+        )
+    }
+
+    fn instance(x: f32, y: f32, z: f32) -> Instance {
+        Instance::new(cgmath::Vector3::new(x, y, z), cgmath::Quaternion::one())
+    }
+
+    // Scattered enough to get a few levels of nodes
+    fn instances() -> Vec<Instance> {
+        let mut seed = 12345_u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as f32 / (1 << 24) as f32 * 40.0 - 20.0
+        };
+        (0..100).map(|_| instance(next(), next(), next())).collect()
+    }
+
+    fn sorted(mut found: Vec<usize>) -> Vec<usize> {
+        found.sort_unstable();
+        found
+    }
+
+    fn brute_force(instances: &[Instance], overlaps: impl Fn(&Aabb) -> bool) -> Vec<usize> {
+        (0..instances.len())
+            .filter(|&i| overlaps(&unit_box().transform(&instances[i].to_matrix())))
+            .collect()
+    }
+
+    #[test]
+    fn queries_match_brute_force() {
+        let instances = instances();
+        let bvh = InstanceBvh::build(unit_box(), &instances);
+        assert_eq!(bvh.len(), instances.len());
+
+        let area = Aabb::new(
+            cgmath::Point3::new(-5.0, -20.0, -5.0),
+            cgmath::Point3::new(8.0, 20.0, 8.0),
+        );
+        let expected = brute_force(&instances, |bounds| bounds.intersects(&area));
+        assert!(!expected.is_empty());
+        assert_eq!(sorted(bvh.query_aabb(&area)), expected);
+
+        let point = cgmath::Point3::new(3.0, -2.0, 1.0);
+        let expected = brute_force(&instances, |bounds| bounds.distance2(point) <= 64.0);
+        assert!(!expected.is_empty());
+        assert_eq!(sorted(bvh.query_radius(point, 8.0)), expected);
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let instances = instances();
+        let bvh = InstanceBvh::build(unit_box(), &instances);
+        for point in [
+            cgmath::Point3::new(0.0, 0.0, 0.0),
+            cgmath::Point3::new(30.0, -25.0, 5.0),
+            cgmath::Point3::new(-7.5, 12.0, 3.0),
+        ] {
+            let (index, distance) = bvh.nearest(point).unwrap();
+            let closest = (0..instances.len())
+                .map(|i| bvh.bounds(i).distance2(point).sqrt())
+                .fold(f32::MAX, f32::min);
+            assert_eq!(distance, closest);
+            assert_eq!(bvh.bounds(index).distance2(point).sqrt(), closest);
+        }
+    }
+
+    #[test]
+    fn moved_instances_are_found_at_their_new_place() {
+        let mut instances = instances();
+        let mut bvh = InstanceBvh::build(unit_box(), &instances);
+        let far = cgmath::Point3::new(100.0, 100.0, 100.0);
+        assert!(bvh.query_radius(far, 1.0).is_empty());
+
+        instances[42] = instance(100.0, 100.0, 100.0);
+        bvh.update(42, &instances[42]);
+        assert_eq!(bvh.query_radius(far, 1.0), vec![42]);
+        assert!(bvh.root_bounds().distance2(far) == 0.0);
+
+        instances[42] = instance(0.0, 0.0, 0.0);
+        instances[7] = instance(-100.0, -100.0, -100.0);
+        bvh.refit(&instances);
+        assert!(bvh.query_radius(far, 1.0).is_empty());
+        assert_eq!(
+            bvh.query_radius(cgmath::Point3::new(-100.0, -100.0, -100.0), 1.0),
+            vec![7]
+        );
+    }
+
+    #[test]
+    fn refit_rebuilds_when_the_count_changes() {
+        let mut instances = instances();
+        let mut bvh = InstanceBvh::build(unit_box(), &instances);
+        instances.truncate(10);
+        instances.push(instance(50.0, 0.0, 0.0));
+        bvh.refit(&instances);
+        assert_eq!(bvh.len(), 11);
+        assert_eq!(
+            bvh.query_radius(cgmath::Point3::new(50.0, 0.0, 0.0), 1.0),
+            vec![10]
+        );
+    }
+
+    #[test]
+    fn empty_trees_find_nothing() {
+        let bvh = InstanceBvh::build(unit_box(), &[]);
+        assert!(bvh.is_empty());
+        assert!(bvh.nearest(cgmath::Point3::new(0.0, 0.0, 0.0)).is_none());
+        assert!(bvh
+            .query_radius(cgmath::Point3::new(0.0, 0.0, 0.0), 10.0)
+            .is_empty());
+    }
+}
@@ -1,3 +1,5 @@
+use super::{bounds::BoundingSphere, culling::Frustum, picking::Ray};
+use cgmath::SquareMatrix;
 use wgpu::{util::DeviceExt, BindingResource};
 use winit::{
     event::{ElementState, WindowEvent},
@@ -5,25 +7,91 @@ use winit::{
     platform::modifier_supplement::KeyEventExtModifierSupplement,
 };
 
+// cgmath matrices are column major, each line below is a column
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
     0.0, 1.0, 0.0, 0.0,
-    0.0, 0.0, 0.5, 0.5,
-    0.0, 0.0, 0.0, 1.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
 );
 
+/// How depth values are distributed in the depth buffer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum DepthMode {
+    /// Near plane at 0.0, far plane at `Camera::zfar` mapped to 1.0.
+    #[default]
+    Standard,
+    /// Near plane at 1.0 and an infinite far plane at 0.0. Floats are much more
+    /// precise close to 0.0, which makes up for the perspective division and
+    /// avoids z-fighting in large scenes.
+    ReverseZ,
+}
+
+impl DepthMode {
+    pub fn compare_function(self) -> wgpu::CompareFunction {
+        match self {
+            DepthMode::Standard => wgpu::CompareFunction::Less,
+            DepthMode::ReverseZ => wgpu::CompareFunction::Greater,
+        }
+    }
+
+    /// Compare function for samplers reading the depth texture.
+    pub fn sampler_compare_function(self) -> wgpu::CompareFunction {
+        match self {
+            DepthMode::Standard => wgpu::CompareFunction::LessEqual,
+            DepthMode::ReverseZ => wgpu::CompareFunction::GreaterEqual,
+        }
+    }
+
+    /// Value the depth buffer is cleared to, the farthest possible depth.
+    pub fn clear_value(self) -> f32 {
+        match self {
+            DepthMode::Standard => 1.0,
+            DepthMode::ReverseZ => 0.0,
+        }
+    }
+
+    pub fn near_depth(self) -> f32 {
+        match self {
+            DepthMode::Standard => 0.0,
+            DepthMode::ReverseZ => 1.0,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 struct CameraUniform {
     view_proj: [[f32; 4]; 4],
+    view: [[f32; 4]; 4],
+    proj: [[f32; 4]; 4],
+    inv_view: [[f32; 4]; 4],
+    inv_proj: [[f32; 4]; 4],
+    inv_view_proj: [[f32; 4]; 4],
+    // w is always 1.0, the vec4 keeps the WGSL struct aligned
+    position: [f32; 4],
+    viewport_size: [f32; 2],
+    znear: f32,
+    zfar: f32,
 }
 
 impl CameraUniform {
     fn from_camera(camera: &Camera) -> Self {
-        let view_proj = camera.build_view_projection_matrix();
+        let view = camera.build_view_matrix();
+        let proj = camera.build_projection_matrix();
+        let view_proj = proj * view;
         Self {
             view_proj: view_proj.into(),
+            view: view.into(),
+            proj: proj.into(),
+            inv_view: invert_or_identity(view).into(),
+            inv_proj: invert_or_identity(proj).into(),
+            inv_view_proj: invert_or_identity(view_proj).into(),
+            position: camera.eye.to_homogeneous().into(),
+            viewport_size: camera.viewport_size.into(),
+            znear: camera.znear,
+            zfar: camera.far_plane(),
         }
     }
 
@@ -32,6 +100,10 @@ impl CameraUniform {
     }
 }
 
+fn invert_or_identity(matrix: cgmath::Matrix4<f32>) -> cgmath::Matrix4<f32> {
+    matrix.invert().unwrap_or_else(cgmath::Matrix4::identity)
+}
+
 pub struct Camera {
     pub eye: cgmath::Point3<f32>,
     pub target: cgmath::Point3<f32>,
@@ -40,23 +112,119 @@ pub struct Camera {
     pub fovy: f32,
     pub znear: f32,
     pub zfar: f32,
+    /// Size in pixels of the area the camera renders into.
+    pub viewport_size: cgmath::Vector2<f32>,
+    pub depth_mode: DepthMode,
     pub camera_buffer: Option<wgpu::Buffer>,
 }
 
 impl Camera {
-    fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
-        // 1.
-        let view = cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up);
-        // 2.
-        let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
+    pub fn build_view_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up)
+    }
+
+    /// Projection matrix already converted to wgpu's 0..1 depth range.
+    pub fn build_projection_matrix(&self) -> cgmath::Matrix4<f32> {
+        match self.depth_mode {
+            DepthMode::Standard => {
+                let proj =
+                    cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
+                OPENGL_TO_WGPU_MATRIX * proj
+            }
+            DepthMode::ReverseZ => {
+                // depth = znear / distance, 1.0 on the near plane and 0.0 at infinity
+                let f = 1.0 / (cgmath::Rad::from(cgmath::Deg(self.fovy)) / 2.0).0.tan();
+                #[rustfmt::skip]
+                let proj = cgmath::Matrix4::new(
+                    f / self.aspect, 0.0, 0.0,        0.0,
+                    0.0,             f,   0.0,        0.0,
+                    0.0,             0.0, 0.0,        -1.0,
+                    0.0,             0.0, self.znear, 0.0,
+                );
+                proj
+            }
+        }
+    }
+
+    /// Distance to the far clipping plane, infinite with reverse-Z.
+    pub fn far_plane(&self) -> f32 {
+        match self.depth_mode {
+            DepthMode::Standard => self.zfar,
+            DepthMode::ReverseZ => f32::INFINITY,
+        }
+    }
 
-        // 3.
-        return OPENGL_TO_WGPU_MATRIX * proj * view;
+    pub fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
+        self.build_projection_matrix() * self.build_view_matrix()
+    }
+
+    pub fn frustum(&self) -> Frustum {
+        Frustum::from_view_projection(&self.build_view_projection_matrix())
+    }
+
+    /// Keeps the aspect ratio and the viewport size sent to the shaders in sync
+    /// with the surface.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if width > 0 && height > 0 {
+            self.viewport_size = cgmath::Vector2::new(width as f32, height as f32);
+            self.aspect = width as f32 / height as f32;
+        }
+    }
+
+    /// Converts a pixel position inside the viewport (origin at the top left corner)
+    /// to normalized device coordinates.
+    fn screen_to_ndc(&self, screen: cgmath::Point2<f32>) -> cgmath::Point2<f32> {
+        cgmath::Point2::new(
+            screen.x / self.viewport_size.x * 2.0 - 1.0,
+            1.0 - screen.y / self.viewport_size.y * 2.0,
+        )
+    }
+
+    /// World space position of the pixel `screen` for a given depth buffer value.
+    pub fn unproject(&self, screen: cgmath::Point2<f32>, depth: f32) -> cgmath::Point3<f32> {
+        let ndc = self.screen_to_ndc(screen);
+        let inv_view_proj = invert_or_identity(self.build_view_projection_matrix());
+        cgmath::Point3::from_homogeneous(
+            inv_view_proj * cgmath::Vector4::new(ndc.x, ndc.y, depth, 1.0),
+        )
+    }
+
+    /// Pixel position of a world space point, `z` holds the depth buffer value.
+    /// Returns `None` for points behind the camera.
+    pub fn project(&self, point: cgmath::Point3<f32>) -> Option<cgmath::Point3<f32>> {
+        let clip = self.build_view_projection_matrix() * point.to_homogeneous();
+        if clip.w <= 0.0 {
+            return None;
+        }
+        let ndc = clip.truncate() / clip.w;
+        Some(cgmath::Point3::new(
+            (ndc.x + 1.0) * 0.5 * self.viewport_size.x,
+            (1.0 - ndc.y) * 0.5 * self.viewport_size.y,
+            ndc.z,
+        ))
+    }
+
+    /// Height of the sphere on screen as a fraction of the viewport height,
+    /// infinite when the eye is inside of it.
+    pub fn screen_size(&self, sphere: &BoundingSphere) -> f32 {
+        use cgmath::MetricSpace;
+        let distance = self.eye.distance(sphere.center);
+        if distance <= sphere.radius {
+            return f32::INFINITY;
+        }
+        let half_fovy = cgmath::Rad::from(cgmath::Deg(self.fovy)) / 2.0;
+        sphere.radius / (distance * half_fovy.0.tan())
+    }
+
+    /// World space ray going from the eye through the pixel `screen`.
+    pub fn screen_to_ray(&self, screen: cgmath::Point2<f32>) -> Ray {
+        use cgmath::InnerSpace;
+        let on_near_plane = self.unproject(screen, self.depth_mode.near_depth());
+        Ray::new(self.eye, (on_near_plane - self.eye).normalize())
     }
 
     fn uniform(&self) -> CameraUniform {
-        let camera_uniform = CameraUniform::from_camera(&self);
-        return camera_uniform;
+        CameraUniform::from_camera(self)
     }
 
     pub fn create_binding_resource<'a>(&'a mut self, device: &wgpu::Device) -> BindingResource<'a> {
@@ -128,7 +296,7 @@ impl CameraController {
             }
             _ => false,
         };
-        return result;
+        result
     }
 
     pub fn update_camera(&mut self) {
@@ -165,9 +333,102 @@ impl CameraController {
     }
 
     pub fn update_camera_buffer(&self, queue: &wgpu::Queue) {
-        self.camera
-            .camera_buffer
-            .as_ref()
-            .map(|buff| queue.write_buffer(&buff, 0, &self.camera.uniform().slice()));
+        if let Some(buff) = self.camera.camera_buffer.as_ref() {
+            queue.write_buffer(buff, 0, &self.camera.uniform().slice());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_camera(depth_mode: DepthMode) -> Camera {
+        Camera {
+            eye: (0.0, 0.0, 5.0).into(),
+            target: (0.0, 0.0, 0.0).into(),
+            up: cgmath::Vector3::unit_y(),
+            aspect: 2.0,
+            fovy: 60.0,
+            znear: 0.1,
+            zfar: 100.0,
+            viewport_size: (200.0, 100.0).into(),
+            depth_mode,
+            camera_buffer: None,
+        }
+    }
+
+    // Depth buffer value of the point `distance` in front of the camera
+    fn depth_at(camera: &Camera, distance: f32) -> f32 {
+        camera
+            .project(cgmath::Point3::new(0.0, 0.0, 5.0 - distance))
+            .unwrap()
+            .z
+    }
+
+    #[test]
+    fn standard_depth_goes_from_0_at_znear_to_1_at_zfar() {
+        let camera = test_camera(DepthMode::Standard);
+        assert!(depth_at(&camera, 0.1).abs() < 1e-5);
+        assert!((depth_at(&camera, 100.0) - 1.0).abs() < 1e-5);
+        let middle = depth_at(&camera, 1.0);
+        assert!(middle > 0.0 && middle < 1.0);
+    }
+
+    #[test]
+    fn reverse_z_depth_goes_from_1_at_znear_towards_0() {
+        let camera = test_camera(DepthMode::ReverseZ);
+        assert!((depth_at(&camera, 0.1) - 1.0).abs() < 1e-5);
+        assert!((depth_at(&camera, 1000.0) - 0.1 / 1000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn points_behind_the_camera_dont_project() {
+        let camera = test_camera(DepthMode::Standard);
+        assert!(camera.project(cgmath::Point3::new(0.0, 0.0, 6.0)).is_none());
+    }
+
+    #[test]
+    fn unproject_undoes_project() {
+        use cgmath::MetricSpace;
+        let points = [
+            cgmath::Point3::new(0.0, 0.0, 0.0),
+            cgmath::Point3::new(1.0, -0.5, 3.0),
+            cgmath::Point3::new(-4.0, 2.0, -10.0),
+            cgmath::Point3::new(20.0, 10.0, -60.0),
+        ];
+        for depth_mode in [DepthMode::Standard, DepthMode::ReverseZ] {
+            let camera = test_camera(depth_mode);
+            for point in points {
+                let screen = camera.project(point).unwrap();
+                let back = camera.unproject(cgmath::Point2::new(screen.x, screen.y), screen.z);
+                let tolerance = 1e-3 * camera.eye.distance(point);
+                assert!(
+                    back.distance(point) < tolerance,
+                    "{depth_mode:?}: {point:?} came back as {back:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn screen_to_ray_goes_through_the_pixel() {
+        use cgmath::InnerSpace;
+        for depth_mode in [DepthMode::Standard, DepthMode::ReverseZ] {
+            let camera = test_camera(depth_mode);
+            let ray = camera.screen_to_ray(cgmath::Point2::new(100.0, 50.0));
+            assert_eq!(ray.origin, camera.eye);
+            assert!((ray.direction - -cgmath::Vector3::unit_z()).magnitude() < 1e-5);
+
+            let point = cgmath::Point3::new(3.0, -1.0, -2.0);
+            let screen = camera.project(point).unwrap();
+            let ray = camera.screen_to_ray(cgmath::Point2::new(screen.x, screen.y));
+            let expected = (point - camera.eye).normalize();
+            assert!(
+                (ray.direction - expected).magnitude() < 1e-4,
+                "{depth_mode:?}: {:?}",
+                ray.direction
+            );
+        }
     }
 }
@@ -0,0 +1,313 @@
+use super::camera::{Camera, CameraController};
+use cgmath::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Camera pose at a point in time, in seconds since the start of the path.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CameraKeyframe {
+    pub time: f32,
+    pub eye: [f32; 3],
+    pub target: [f32; 3],
+    pub up: [f32; 3],
+}
+
+impl CameraKeyframe {
+    pub fn from_camera(time: f32, camera: &Camera) -> Self {
+        Self {
+            time,
+            eye: camera.eye.into(),
+            target: camera.target.into(),
+            up: camera.up.into(),
+        }
+    }
+
+    pub fn apply(&self, camera: &mut Camera) {
+        camera.eye = self.eye.into();
+        camera.target = self.target.into();
+        camera.up = self.up.into();
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CameraPath {
+    pub keyframes: Vec<CameraKeyframe>,
+}
+
+impl CameraPath {
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |k| k.time)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        let camera_path: CameraPath = serde_json::from_str(&json)?;
+        if camera_path
+            .keyframes
+            .windows(2)
+            .any(|pair| pair[1].time < pair[0].time)
+        {
+            anyhow::bail!("camera path keyframes must be sorted by time");
+        }
+        Ok(camera_path)
+    }
+
+    /// Catmull-Rom interpolation of the keyframes, the time is clamped to the path duration.
+    pub fn sample(&self, time: f32) -> Option<CameraKeyframe> {
+        let keys = &self.keyframes;
+        let last = keys.len().checked_sub(1)?;
+        let time = time.clamp(keys[0].time, keys[last].time);
+        // Index of the keyframe that starts the segment containing `time`
+        let i1 = keys
+            .partition_point(|k| k.time <= time)
+            .saturating_sub(1)
+            .min(last.saturating_sub(1));
+        let i2 = (i1 + 1).min(last);
+        let i0 = i1.saturating_sub(1);
+        let i3 = (i2 + 1).min(last);
+
+        let span = keys[i2].time - keys[i1].time;
+        let t = if span > 0.0 {
+            (time - keys[i1].time) / span
+        } else {
+            0.0
+        };
+        let interpolate = |get: fn(&CameraKeyframe) -> [f32; 3]| {
+            catmull_rom(
+                get(&keys[i0]).into(),
+                get(&keys[i1]).into(),
+                get(&keys[i2]).into(),
+                get(&keys[i3]).into(),
+                t,
+            )
+        };
+        let up = interpolate(|k| k.up);
+        let up = if up.magnitude2() > 0.0 {
+            up.normalize()
+        } else {
+            keys[i1].up.into()
+        };
+
+        Some(CameraKeyframe {
+            time,
+            eye: interpolate(|k| k.eye).into(),
+            target: interpolate(|k| k.target).into(),
+            up: up.into(),
+        })
+    }
+}
+
+fn catmull_rom(
+    p0: cgmath::Vector3<f32>,
+    p1: cgmath::Vector3<f32>,
+    p2: cgmath::Vector3<f32>,
+    p3: cgmath::Vector3<f32>,
+    t: f32,
+) -> cgmath::Vector3<f32> {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    (p1 * 2.0
+        + (p2 - p0) * t
+        + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2
+        + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * t3)
+        * 0.5
+}
+
+/// Samples the controller camera at a fixed interval.
+pub struct CameraRecorder {
+    path: CameraPath,
+    interval: f32,
+    elapsed: f32,
+    next_sample: f32,
+}
+
+impl CameraRecorder {
+    pub fn new(interval: f32) -> Self {
+        Self {
+            path: CameraPath::default(),
+            interval,
+            elapsed: 0.0,
+            next_sample: 0.0,
+        }
+    }
+
+    /// `dt` is the time in seconds since the last call. The first call
+    /// starts the path at 0.
+    pub fn record(&mut self, dt: f32, controller: &CameraController) {
+        if !self.path.keyframes.is_empty() {
+            self.elapsed += dt;
+        }
+        if self.elapsed >= self.next_sample {
+            self.path.keyframes.push(CameraKeyframe::from_camera(
+                self.elapsed,
+                &controller.camera,
+            ));
+            self.next_sample = self.elapsed + self.interval;
+        }
+    }
+
+    pub fn finish(mut self, controller: &CameraController) -> CameraPath {
+        // Always end on the pose the recording stopped at
+        self.path.keyframes.push(CameraKeyframe::from_camera(
+            self.elapsed,
+            &controller.camera,
+        ));
+        self.path
+    }
+}
+
+/// Plays a path back at a fixed step per frame, so a fly-through shows the
+/// same frames on every run whatever the frame rate.
+pub struct CameraPlayer {
+    path: CameraPath,
+    time: f32,
+    step: f32,
+    pub looping: bool,
+}
+
+impl CameraPlayer {
+    /// `step` is the path time in seconds between two frames.
+    pub fn new(path: CameraPath, step: f32, looping: bool) -> Self {
+        Self {
+            path,
+            time: 0.0,
+            step,
+            looping,
+        }
+    }
+
+    /// Path time of the next frame.
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    /// Poses the camera for the current frame and moves the playback forward
+    /// by one step. Returns false once a non looping path is over.
+    pub fn advance(&mut self, camera: &mut Camera) -> bool {
+        let duration = self.path.duration();
+        if !self.looping && self.time > duration {
+            return false;
+        }
+        match self.path.sample(self.time) {
+            Some(keyframe) => keyframe.apply(camera),
+            None => return false,
+        }
+        self.time += self.step;
+        if self.looping && duration > 0.0 {
+            self.time %= duration;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        Camera {
+            eye: (0.0, 0.0, 0.0).into(),
+            target: (0.0, 0.0, -1.0).into(),
+            up: cgmath::Vector3::unit_y(),
+            aspect: 1.0,
+            fovy: 45.0,
+            znear: 0.1,
+            zfar: 100.0,
+            viewport_size: (100.0, 100.0).into(),
+            depth_mode: Default::default(),
+            camera_buffer: None,
+        }
+    }
+
+    fn keyframe(time: f32, x: f32) -> CameraKeyframe {
+        CameraKeyframe {
+            time,
+            eye: [x, 0.0, 0.0],
+            target: [x, 0.0, -1.0],
+            up: [0.0, 1.0, 0.0],
+        }
+    }
+
+    fn path() -> CameraPath {
+        CameraPath {
+            keyframes: vec![
+                keyframe(0.0, 0.0),
+                keyframe(1.0, 2.0),
+                keyframe(2.0, 3.0),
+                keyframe(4.0, 7.0),
+            ],
+        }
+    }
+
+    #[test]
+    fn sample_passes_through_keyframes() {
+        let path = path();
+        for key in &path.keyframes {
+            let sample = path.sample(key.time).unwrap();
+            assert!((sample.eye[0] - key.eye[0]).abs() < 1e-5, "{sample:?}");
+        }
+    }
+
+    #[test]
+    fn sample_clamps_to_the_path() {
+        let path = path();
+        assert_eq!(path.sample(-1.0).unwrap().eye, path.keyframes[0].eye);
+        assert_eq!(path.sample(10.0).unwrap().eye, path.keyframes[3].eye);
+        assert!(CameraPath::default().sample(0.0).is_none());
+    }
+
+    #[test]
+    fn recording_starts_at_zero() {
+        let mut controller = CameraController::new(0.1, camera());
+        let mut recorder = CameraRecorder::new(0.1);
+        recorder.record(0.5, &controller);
+        recorder.record(0.05, &controller);
+        controller.camera.eye.x = 1.0;
+        recorder.record(0.06, &controller);
+        let path = recorder.finish(&controller);
+        let times = path.keyframes.iter().map(|k| k.time).collect::<Vec<_>>();
+        assert_eq!(times, [0.0, 0.11, 0.11]);
+        assert_eq!(path.keyframes[1].eye, [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn playback_uses_a_fixed_step() {
+        let mut player = CameraPlayer::new(path(), 1.0, false);
+        let mut camera = camera();
+        let mut eyes = Vec::new();
+        while player.advance(&mut camera) {
+            eyes.push(camera.eye.x);
+        }
+        let expected = [0.0, 1.0, 2.0, 3.0, 4.0].map(|time| path().sample(time).unwrap().eye[0]);
+        assert_eq!(eyes, expected);
+        assert!(!player.advance(&mut camera));
+    }
+
+    #[test]
+    fn looping_playback_wraps() {
+        let mut player = CameraPlayer::new(path(), 1.5, true);
+        let mut camera = camera();
+        for _ in 0..3 {
+            assert!(player.advance(&mut camera));
+        }
+        assert_eq!(player.time(), 0.5);
+    }
+
+    #[test]
+    fn load_rejects_unsorted_keyframes() {
+        let file = std::env::temp_dir().join(format!("camera_path_{}.json", std::process::id()));
+        let mut path = path();
+        path.save(&file).unwrap();
+        assert_eq!(CameraPath::load(&file).unwrap().keyframes, path.keyframes);
+        path.keyframes.swap(0, 1);
+        path.save(&file).unwrap();
+        assert!(CameraPath::load(&file).is_err());
+        std::fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+use super::{
+    bounds::{Aabb, BoundingSphere},
+    InstanceBuffer, InstanceRaw, InstancesVec,
+};
+use cgmath::prelude::*;
+
+/// Points with `normal.dot(p) + distance >= 0` are on the inner side.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Plane {
+    pub normal: cgmath::Vector3<f32>,
+    pub distance: f32,
+}
+
+impl Plane {
+    fn from_vector(v: cgmath::Vector4<f32>) -> Self {
+        let normal = v.truncate();
+        let length = normal.magnitude();
+        if length < 1e-6 {
+            // Degenerate plane, e.g. the far plane of an infinite projection,
+            // everything is inside of it
+            return Self {
+                normal: cgmath::Vector3::zero(),
+                distance: f32::MAX,
+            };
+        }
+        Self {
+            normal: normal / length,
+            distance: v.w / length,
+        }
+    }
+
+    pub fn signed_distance(&self, point: cgmath::Point3<f32>) -> f32 {
+        self.normal.dot(point.to_vec()) + self.distance
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Frustum {
+    /// Left, right, bottom, top, near and far.
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    /// Gribb-Hartmann plane extraction for wgpu's clip space, where
+    /// `-w <= x, y <= w` and `0 <= z <= w`. Works with reverse-Z too, the near
+    /// and far planes just swap places.
+    pub fn from_view_projection(matrix: &cgmath::Matrix4<f32>) -> Self {
+        let row = |i: usize| matrix.row(i);
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        Self {
+            planes: [
+                Plane::from_vector(r3 + r0),
+                Plane::from_vector(r3 - r0),
+                Plane::from_vector(r3 + r1),
+                Plane::from_vector(r3 - r1),
+                Plane::from_vector(r2),
+                Plane::from_vector(r3 - r2),
+            ],
+        }
+    }
+
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.signed_distance(sphere.center) >= -sphere.radius)
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // Corner of the box the furthest along the plane normal
+            let corner = cgmath::Point3::new(
+                if plane.normal.x >= 0.0 {
+                    aabb.max.x
+                } else {
+                    aabb.min.x
+                },
+                if plane.normal.y >= 0.0 {
+                    aabb.max.y
+                } else {
+                    aabb.min.y
+                },
+                if plane.normal.z >= 0.0 {
+                    aabb.max.z
+                } else {
+                    aabb.min.z
+                },
+            );
+            plane.signed_distance(corner) >= 0.0
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct CullStats {
+    pub visible: usize,
+    pub culled: usize,
+}
+
+/// Keeps the instances that can be seen by a camera packed at the start of its
+/// own instance buffer.
+pub struct InstanceCuller {
+    pub buffer: InstanceBuffer,
+    visible: Vec<InstanceRaw>,
+    stats: CullStats,
+}
+
+impl InstanceCuller {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            buffer: InstanceBuffer::new(device, 0, "Culled Instance Buffer"),
+            visible: Vec::new(),
+            stats: CullStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CullStats {
+        self.stats
+    }
+
+    /// Tests the bounding sphere of every instance against the frustum and uploads
+    /// the visible ones. Returns how many instances have to be drawn.
+    pub fn cull(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        frustum: &Frustum,
+        mesh_sphere: &BoundingSphere,
+        instances: &InstancesVec,
+    ) -> u32 {
+        self.visible.clear();
+        self.visible.extend(
+            instances.raw().iter().filter(|raw| {
+                frustum.intersects_sphere(&mesh_sphere.transform(&raw.model_matrix()))
+            }),
+        );
+        self.buffer.reserve(device, self.visible.len());
+        self.buffer.write(queue, 0, &self.visible);
+
+        let stats = CullStats {
+            visible: self.visible.len(),
+            culled: instances.len() - self.visible.len(),
+        };
+        if stats != self.stats {
+            log::debug!(
+                "{} instances visible, {} culled",
+                stats.visible,
+                stats.culled
+            );
+            self.stats = stats;
+        }
+        self.visible.len() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wgpu_things::camera::{Camera, DepthMode};
+
+    // Looking down -z from (0, 0, 5), see `corners`
+    fn test_camera(depth_mode: DepthMode) -> Camera {
+        Camera {
+            eye: (0.0, 0.0, 5.0).into(),
+            target: (0.0, 0.0, 0.0).into(),
+            up: cgmath::Vector3::unit_y(),
+            aspect: 2.0,
+            fovy: 90.0,
+            znear: 0.1,
+            zfar: 100.0,
+            viewport_size: (200.0, 100.0).into(),
+            depth_mode,
+            camera_buffer: None,
+        }
+    }
+
+    fn point(x: f32, y: f32, z: f32) -> cgmath::Point3<f32> {
+        cgmath::Point3::new(x, y, z)
+    }
+
+    fn contains(frustum: &Frustum, p: cgmath::Point3<f32>) -> bool {
+        let inside = frustum.intersects_aabb(&Aabb::new(p, p));
+        assert_eq!(
+            inside,
+            frustum.intersects_sphere(&BoundingSphere::new(p, 0.0))
+        );
+        inside
+    }
+
+    // At the origin, 5 in front of the eye, the 90 degree frustum spans
+    // -10..10 horizontally and -5..5 vertically
+    #[test]
+    fn points_on_each_side_of_each_plane() {
+        let frustum = test_camera(DepthMode::Standard).frustum();
+        let pairs = [
+            (point(-9.9, 0.0, 0.0), point(-10.1, 0.0, 0.0)),
+            (point(9.9, 0.0, 0.0), point(10.1, 0.0, 0.0)),
+            (point(0.0, -4.9, 0.0), point(0.0, -5.1, 0.0)),
+            (point(0.0, 4.9, 0.0), point(0.0, 5.1, 0.0)),
+            (point(0.0, 0.0, 4.8), point(0.0, 0.0, 4.95)),
+            (point(0.0, 0.0, -94.0), point(0.0, 0.0, -96.0)),
+        ];
+        for (inside, outside) in pairs {
+            assert!(contains(&frustum, inside), "{inside:?}");
+            assert!(!contains(&frustum, outside), "{outside:?}");
+        }
+    }
+
+    #[test]
+    fn boxes_are_culled_only_when_fully_outside() {
+        let frustum = test_camera(DepthMode::Standard).frustum();
+        let inside = Aabb::new(point(-1.0, -1.0, -1.0), point(1.0, 1.0, 1.0));
+        let straddling = Aabb::new(point(9.0, -1.0, -1.0), point(11.0, 1.0, 1.0));
+        let outside = Aabb::new(point(13.0, -1.0, -1.0), point(14.0, 1.0, 1.0));
+        let behind = Aabb::new(point(-1.0, -1.0, 6.0), point(1.0, 1.0, 7.0));
+        assert!(frustum.intersects_aabb(&inside));
+        assert!(frustum.intersects_aabb(&straddling));
+        assert!(!frustum.intersects_aabb(&outside));
+        assert!(!frustum.intersects_aabb(&behind));
+    }
+
+    #[test]
+    fn spheres_touching_a_plane_are_kept() {
+        let frustum = test_camera(DepthMode::Standard).frustum();
+        assert!(frustum.intersects_sphere(&BoundingSphere::new(point(0.0, 5.5, 0.0), 1.0)));
+        assert!(!frustum.intersects_sphere(&BoundingSphere::new(point(0.0, 7.0, 0.0), 1.0)));
+    }
+
+    #[test]
+    fn reverse_z_has_no_far_plane() {
+        let frustum = test_camera(DepthMode::ReverseZ).frustum();
+        assert!(contains(&frustum, point(0.0, 0.0, -10000.0)));
+        assert!(!contains(&frustum, point(0.0, 0.0, 4.95)));
+        assert!(!contains(&frustum, point(10.1, 0.0, 0.0)));
+    }
+}
@@ -0,0 +1,344 @@
+use super::{
+    assets::AssetManager,
+    mesh_processing,
+    model::{Material, MaterialFactors, Mesh, Model, ModelVertex},
+    normals::{generate_normals, generate_tangents, tangent, NormalMode},
+    resources::load_binary,
+    vfs, Texture,
+};
+use anyhow::Context;
+use base64::Engine;
+use cgmath::prelude::*;
+use std::path::Path;
+
+/// True for `.gltf` and `.glb` files.
+pub fn is_gltf(file_name: &str) -> bool {
+    Path::new(file_name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gltf") || ext.eq_ignore_ascii_case("glb"))
+}
+
+/// Geometry of one primitive, already moved to model space by the node
+/// transforms.
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    /// Index of the glTF material, `None` for the default material.
+    pub material: Option<usize>,
+}
+
+// Path of a URI relative to the glTF file, as given to `load_binary`
+fn resolve_uri(file_name: &str, uri: &str) -> anyhow::Result<String> {
+    let path = urlencoding::decode(uri)?;
+    let path = Path::new(file_name)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(path.as_ref());
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Reads `data:` URIs, other URIs are paths relative to the glTF file.
+async fn load_uri(file_name: &str, uri: &str) -> anyhow::Result<Vec<u8>> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let (header, payload) = data.split_once(',').context("malformed data URI")?;
+        anyhow::ensure!(
+            header.ends_with(";base64"),
+            "only base64 data URIs are supported"
+        );
+        return Ok(base64::engine::general_purpose::STANDARD.decode(payload)?);
+    }
+    load_binary(&resolve_uri(file_name, uri)?)
+        .await
+        .with_context(|| format!("loading {uri}"))
+}
+
+async fn load_document(file_name: &str) -> anyhow::Result<(gltf::Document, Vec<Vec<u8>>)> {
+    let data = load_binary(file_name).await?;
+    let gltf::Gltf { document, mut blob } = gltf::Gltf::from_slice(&data)?;
+
+    let mut buffers = Vec::new();
+    for buffer in document.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => blob.take().context("the GLB binary chunk is missing")?,
+            gltf::buffer::Source::Uri(uri) => load_uri(file_name, uri).await?,
+        };
+        anyhow::ensure!(
+            data.len() >= buffer.length(),
+            "buffer {} is {} bytes long instead of {}",
+            buffer.index(),
+            data.len(),
+            buffer.length()
+        );
+        buffers.push(data);
+    }
+    Ok((document, buffers))
+}
+
+fn read_primitive(
+    name: &str,
+    primitive: &gltf::Primitive,
+    transform: &cgmath::Matrix4<f32>,
+    buffers: &[Vec<u8>],
+) -> anyhow::Result<MeshData> {
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+    let positions = reader
+        .read_positions()
+        .context("primitive without positions")?;
+    let mut vertices = positions
+        .map(|position| ModelVertex {
+            position: transform.transform_point(position.into()).into(),
+            tex_coords: [0.0; 2],
+            normal: [0.0; 3],
+            tangent: [0.0; 4],
+        })
+        .collect::<Vec<_>>();
+
+    let normals = reader.read_normals();
+    let has_normals = normals.is_some();
+    if let Some(normals) = normals {
+        let normal_matrix = transform
+            .invert()
+            .unwrap_or(cgmath::Matrix4::identity())
+            .transpose();
+        for (vertex, normal) in vertices.iter_mut().zip(normals) {
+            let normal = normal_matrix.transform_vector(normal.into());
+            if normal.magnitude2() > 0.0 {
+                vertex.normal = normal.normalize().into();
+            }
+        }
+    }
+
+    // glTF UVs already have their origin at the top left, like wgpu
+    let tex_coord_set = primitive
+        .material()
+        .pbr_metallic_roughness()
+        .base_color_texture()
+        .map_or(0, |info| info.tex_coord());
+    if let Some(tex_coords) = reader.read_tex_coords(tex_coord_set) {
+        for (vertex, tex_coords) in vertices.iter_mut().zip(tex_coords.into_f32()) {
+            vertex.tex_coords = tex_coords;
+        }
+    }
+
+    let mut indices = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+        None => (0..vertices.len() as u32).collect(),
+    };
+    anyhow::ensure!(
+        indices
+            .iter()
+            .all(|&index| (index as usize) < vertices.len()),
+        "index out of range"
+    );
+    // Mirroring transforms turn the triangles inside out
+    if transform.determinant() < 0.0 {
+        for triangle in indices.chunks_exact_mut(3) {
+            triangle.swap(1, 2);
+        }
+    }
+    if !has_normals {
+        generate_normals(&mut vertices, &mut indices, NormalMode::Smooth);
+    }
+
+    // Tangents only make sense with the normals they were made for
+    match reader.read_tangents().filter(|_| has_normals) {
+        Some(tangents) => {
+            // Mirroring flips the bitangent the sign builds
+            let sign = transform.determinant().signum();
+            for (vertex, [x, y, z, w]) in vertices.iter_mut().zip(tangents) {
+                // Made perpendicular again, scaling can tilt it off the normal
+                let direction = transform.transform_vector(cgmath::Vector3::new(x, y, z));
+                let [x, y, z, _] =
+                    tangent(vertex.normal.into(), direction, cgmath::Vector3::zero());
+                vertex.tangent = [x, y, z, w * sign];
+            }
+        }
+        None => generate_tangents(&mut vertices, &indices),
+    }
+
+    Ok(MeshData {
+        name: name.to_string(),
+        vertices,
+        indices,
+        material: primitive.material().index(),
+    })
+}
+
+/// Loads the geometry of the default scene, one `MeshData` per triangle
+/// primitive of each node. Other primitive modes are skipped.
+pub async fn load_gltf_meshes(file_name: &str) -> anyhow::Result<Vec<MeshData>> {
+    let (document, buffers) = load_document(file_name).await?;
+    read_meshes(&document, &buffers).with_context(|| format!("loading {file_name}"))
+}
+
+fn read_meshes(document: &gltf::Document, buffers: &[Vec<u8>]) -> anyhow::Result<Vec<MeshData>> {
+    let Some(scene) = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    else {
+        return Ok(Vec::new());
+    };
+
+    let mut meshes = Vec::new();
+    let mut stack = scene
+        .nodes()
+        .map(|node| (node, cgmath::Matrix4::identity()))
+        .collect::<Vec<_>>();
+    while let Some((node, parent_transform)) = stack.pop() {
+        let transform = parent_transform * cgmath::Matrix4::from(node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            let name = mesh.name().or(node.name()).unwrap_or("mesh");
+            for primitive in mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    log::warn!(
+                        "Skipping {:?} primitive of {name:?}, only triangles are supported",
+                        primitive.mode()
+                    );
+                    continue;
+                }
+                let data = read_primitive(name, &primitive, &transform, buffers)
+                    .with_context(|| format!("mesh {name:?}"))?;
+                meshes.push(data);
+            }
+        }
+        stack.extend(node.children().map(|child| (child, transform)));
+    }
+    Ok(meshes)
+}
+
+async fn load_material(
+    file_name: &str,
+    material: &gltf::Material<'_>,
+    buffers: &[Vec<u8>],
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    assets: &AssetManager,
+) -> anyhow::Result<Material> {
+    let name = material.name().unwrap_or("material");
+    let pbr = material.pbr_metallic_roughness();
+    let diffuse_texture = match pbr.base_color_texture() {
+        Some(info) => {
+            let image = info.texture().source();
+            // External images are shared with the other models using them
+            let key = match image.source() {
+                gltf::image::Source::Uri { uri, .. } if !uri.starts_with("data:") => {
+                    resolve_uri(file_name, uri)?
+                }
+                _ => format!("{file_name}#image{}", image.index()),
+            };
+            if let Some(texture) = assets.texture(&key) {
+                texture
+            } else {
+                let bytes = match image.source() {
+                    gltf::image::Source::View { view, .. } => {
+                        let buffer = &buffers[view.buffer().index()];
+                        buffer
+                            .get(view.offset()..view.offset() + view.length())
+                            .context("image outside of its buffer")?
+                            .to_vec()
+                    }
+                    gltf::image::Source::Uri { uri, .. } => load_uri(file_name, uri).await?,
+                };
+                let texture = Texture::from_bytes(device, queue, &bytes, &key)
+                    .with_context(|| format!("image {} of material {name:?}", image.index()))?;
+                assets.insert_texture(&key, texture)
+            }
+        }
+        None => assets.white_texture(device, queue),
+    };
+    let factors = MaterialFactors {
+        base_color: pbr.base_color_factor(),
+        emissive: material.emissive_factor(),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        ..Default::default()
+    };
+    Ok(Material::new(
+        device,
+        layout,
+        name,
+        diffuse_texture,
+        factors,
+    ))
+}
+
+/// Loads a `.gltf` file with its external or embedded buffers and images, or
+/// a `.glb` file. Node transforms are baked into the vertices.
+pub async fn load_gltf(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    assets: &AssetManager,
+) -> anyhow::Result<Model> {
+    let recording = vfs::Recording::start();
+    let (document, buffers) = load_document(file_name).await?;
+    // The glTF file and its buffers
+    let source_files = recording.finish();
+
+    let mut materials = Vec::new();
+    for (index, material) in document.materials().enumerate() {
+        let key = format!("{file_name}#material{index}");
+        if let Some(material) = assets.material(&key) {
+            materials.push(material);
+            continue;
+        }
+        let recording = vfs::Recording::start();
+        let material = load_material(
+            file_name, &material, &buffers, device, queue, layout, assets,
+        )
+        .await
+        .with_context(|| format!("loading {file_name}"))?;
+        let files = recording.finish().into_iter().chain(source_files.clone());
+        materials.push(assets.insert_material(&key, material, files.collect()));
+    }
+
+    // Primitives without a material use the glTF default one, added after the others
+    let default_material = materials.len();
+    let meshes = read_meshes(&document, &buffers)
+        .with_context(|| format!("loading {file_name}"))?
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| {
+            let key = format!("{file_name}#mesh{index}");
+            assets.mesh(&key).unwrap_or_else(|| {
+                let (vertices, indices) =
+                    mesh_processing::process(&data.name, &data.vertices, data.indices);
+                let mesh = Mesh::new(
+                    device,
+                    &data.name,
+                    vertices,
+                    indices,
+                    data.material.unwrap_or(default_material),
+                );
+                assets.insert_mesh(&key, mesh, source_files.clone())
+            })
+        })
+        .collect::<Vec<_>>();
+    if meshes.iter().any(|mesh| mesh.material == default_material) {
+        let key = "#gltf_default";
+        let material = assets.material(key).unwrap_or_else(|| {
+            let material = Material::new(
+                device,
+                layout,
+                "default",
+                assets.white_texture(device, queue),
+                MaterialFactors {
+                    metallic: 1.0,
+                    ..Default::default()
+                },
+            );
+            assets.insert_material(key, material, Vec::new())
+        });
+        materials.push(material);
+    }
+
+    Ok(Model {
+        meshes,
+        materials,
+        lods: Vec::new(),
+    })
+}
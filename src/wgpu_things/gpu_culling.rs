@@ -0,0 +1,295 @@
+use super::{assets::Handle, bounds::BoundingSphere, culling::Frustum, model::Mesh, InstancesVec};
+
+const WORKGROUP_SIZE: u32 = 64;
+const MAX_WORKGROUPS_PER_DIMENSION: u32 = 65535;
+const DRAW_ARGS_SIZE: wgpu::BufferAddress =
+    std::mem::size_of::<wgpu::util::DrawIndexedIndirectArgs>() as wgpu::BufferAddress;
+// Offset of `instance_count` in `DrawIndexedIndirectArgs`
+const INSTANCE_COUNT_OFFSET: wgpu::BufferAddress = 4;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullParams {
+    planes: [[f32; 4]; 6],
+    sphere: [f32; 4],
+    instance_count: u32,
+    // Uniforms are padded to 16 bytes
+    _padding: [u32; 3],
+}
+
+/// Compute pipeline testing every instance against a camera frustum on the GPU.
+/// The surviving instance indices and the draw arguments stay on the GPU and are
+/// consumed by `draw_indexed_indirect`, one draw per mesh.
+pub struct GpuCuller {
+    pipeline: wgpu::ComputePipeline,
+    cull_bind_group_layout: wgpu::BindGroupLayout,
+    /// Layout of the bind group the indirect render pipeline reads instances from.
+    pub instances_bind_group_layout: wgpu::BindGroupLayout,
+}
+
+fn storage_entry(
+    binding: u32,
+    visibility: wgpu::ShaderStages,
+    read_only: bool,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+impl GpuCuller {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Cull Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../shaders/cull.wgsl").into()),
+        });
+
+        let cull_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    storage_entry(0, wgpu::ShaderStages::COMPUTE, true),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    storage_entry(2, wgpu::ShaderStages::COMPUTE, false),
+                    storage_entry(3, wgpu::ShaderStages::COMPUTE, false),
+                ],
+                label: Some("cull_bind_group_layout"),
+            });
+
+        let instances_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    storage_entry(0, wgpu::ShaderStages::VERTEX, true),
+                    storage_entry(1, wgpu::ShaderStages::VERTEX, true),
+                ],
+                label: Some("culled_instances_bind_group_layout"),
+            });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Cull Pipeline Layout"),
+            bind_group_layouts: &[&cull_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Cull Pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "cs_main",
+        });
+
+        Self {
+            pipeline,
+            cull_bind_group_layout,
+            instances_bind_group_layout,
+        }
+    }
+}
+
+/// Buffers the culling of one camera writes into.
+pub struct GpuCullTarget {
+    params_buffer: wgpu::Buffer,
+    // Visible instance count written by the compute pass
+    count_buffer: wgpu::Buffer,
+    /// One `wgpu::util::DrawIndexedIndirectArgs` per mesh, see `draw_offset`.
+    pub indirect_buffer: wgpu::Buffer,
+    cull_bind_group: wgpu::BindGroup,
+    /// Bind group for the indirect render pipeline.
+    pub render_bind_group: wgpu::BindGroup,
+    // Capacity of the instance buffer the bind groups point to, the instance
+    // buffer is recreated when it grows
+    instance_capacity: usize,
+    // Number of draws `indirect_buffer` has room for
+    draw_capacity: usize,
+}
+
+impl GpuCullTarget {
+    pub fn new(
+        device: &wgpu::Device,
+        culler: &GpuCuller,
+        instances: &InstancesVec,
+        mesh_count: usize,
+    ) -> Self {
+        let instance_capacity = instances.buffer().capacity();
+        let draw_capacity = mesh_count.max(1);
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cull Params Buffer"),
+            size: std::mem::size_of::<CullParams>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        // Kept alive by the bind groups
+        let visible_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Visible Instances Buffer"),
+            size: (instance_capacity * std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let count_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Visible Count Buffer"),
+            size: std::mem::size_of::<u32>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let indirect_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Indirect Draw Buffer"),
+            size: draw_capacity as wgpu::BufferAddress * DRAW_ARGS_SIZE,
+            usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let cull_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &culler.cull_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: instances.buffer().buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: visible_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: count_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("cull_bind_group"),
+        });
+        let render_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &culler.instances_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: instances.buffer().buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: visible_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("culled_instances_bind_group"),
+        });
+
+        Self {
+            params_buffer,
+            count_buffer,
+            indirect_buffer,
+            cull_bind_group,
+            render_bind_group,
+            instance_capacity,
+            draw_capacity,
+        }
+    }
+
+    /// Offset in `indirect_buffer` of the arguments drawing `meshes[index]`.
+    pub fn draw_offset(index: usize) -> wgpu::BufferAddress {
+        index as wgpu::BufferAddress * DRAW_ARGS_SIZE
+    }
+
+    /// Records the culling of `instances` into `encoder`. The indirect buffer
+    /// then holds the arguments to draw the visible instances of each of
+    /// `meshes`, `sphere` has to bound all of them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn cull(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        culler: &GpuCuller,
+        frustum: &Frustum,
+        sphere: &BoundingSphere,
+        meshes: &[Handle<Mesh>],
+        instances: &InstancesVec,
+    ) {
+        if instances.buffer().capacity() != self.instance_capacity
+            || meshes.len() > self.draw_capacity
+        {
+            *self = Self::new(device, culler, instances, meshes.len());
+        }
+
+        let params = CullParams {
+            planes: frustum.planes.map(|plane| {
+                [
+                    plane.normal.x,
+                    plane.normal.y,
+                    plane.normal.z,
+                    plane.distance,
+                ]
+            }),
+            sphere: [
+                sphere.center.x,
+                sphere.center.y,
+                sphere.center.z,
+                sphere.radius,
+            ],
+            instance_count: instances.len() as u32,
+            _padding: [0; 3],
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[params]));
+        // The compute pass counts the instances up from zero
+        queue.write_buffer(&self.count_buffer, 0, bytemuck::bytes_of(&0_u32));
+        let draws = meshes
+            .iter()
+            .flat_map(|mesh| {
+                wgpu::util::DrawIndexedIndirectArgs {
+                    index_count: mesh.num_elements,
+                    instance_count: 0,
+                    first_index: 0,
+                    base_vertex: 0,
+                    first_instance: 0,
+                }
+                .as_bytes()
+                .to_vec()
+            })
+            .collect::<Vec<_>>();
+        queue.write_buffer(&self.indirect_buffer, 0, &draws);
+
+        let workgroups = (instances.len() as u32).div_ceil(WORKGROUP_SIZE);
+        if workgroups == 0 {
+            return;
+        }
+        let workgroups_x = workgroups.min(MAX_WORKGROUPS_PER_DIMENSION);
+        let workgroups_y = workgroups.div_ceil(workgroups_x);
+
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Cull Pass"),
+                timestamp_writes: None,
+            });
+            compute_pass.set_pipeline(&culler.pipeline);
+            compute_pass.set_bind_group(0, &self.cull_bind_group, &[]);
+            compute_pass.dispatch_workgroups(workgroups_x, workgroups_y, 1);
+        }
+        // Every mesh draws the same visible instances
+        for index in 0..meshes.len() {
+            encoder.copy_buffer_to_buffer(
+                &self.count_buffer,
+                0,
+                &self.indirect_buffer,
+                Self::draw_offset(index) + INSTANCE_COUNT_OFFSET,
+                std::mem::size_of::<u32>() as wgpu::BufferAddress,
+            );
+        }
+    }
+}
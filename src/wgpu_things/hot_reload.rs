@@ -0,0 +1,281 @@
+//! Reloading assets when their files change on disk.
+//!
+//! Files are polled for their modification time on a background thread. A
+//! change is only reported once the time stays the same for a whole interval,
+//! so a file still being written by an exporter isn't read half way. Watched
+//! directories are listed on every poll to find the files created in them.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+#[derive(Debug, Default, Copy, Clone)]
+struct FileState {
+    // Modification time seen on the last poll
+    seen: Option<SystemTime>,
+    // Modification time last reported, or the one the file had when watched
+    reported: Option<SystemTime>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Files under `dir`, subdirectories included
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => list_files(&path, files),
+            Ok(_) => files.push(path),
+            Err(_) => {}
+        }
+    }
+}
+
+#[derive(Default)]
+struct Watched {
+    files: HashMap<PathBuf, FileState>,
+    // Files seen in each watched directory
+    dirs: HashMap<PathBuf, HashSet<PathBuf>>,
+}
+
+impl Watched {
+    // Starts watching the files that appeared in the directories
+    fn find_new_files(&mut self) {
+        for (dir, known) in &mut self.dirs {
+            let mut listed = Vec::new();
+            list_files(dir, &mut listed);
+            for path in listed {
+                if known.insert(path.clone()) {
+                    // Never reported, so it counts as created once it stops changing
+                    self.files.entry(path).or_default();
+                }
+            }
+        }
+    }
+}
+
+/// A watched file that changed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileChange {
+    pub path: PathBuf,
+    /// The file didn't exist when it started being watched, e.g. a new file
+    /// in a watched directory.
+    pub created: bool,
+}
+
+/// Reports the watched files whose modification time changed.
+pub struct FileWatcher {
+    watched: Arc<Mutex<Watched>>,
+    changes: mpsc::Receiver<FileChange>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FileWatcher {
+    pub fn new(interval: Duration) -> Self {
+        let watched = Arc::new(Mutex::new(Watched::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, changes) = mpsc::channel();
+
+        let thread_watched = watched.clone();
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("file watcher".to_string())
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Relaxed) {
+                    thread::sleep(interval);
+                    let mut watched = thread_watched.lock().unwrap();
+                    watched.find_new_files();
+                    for (path, state) in watched.files.iter_mut() {
+                        let time = modified(path);
+                        if time != state.seen {
+                            // Still changing, wait for the next poll
+                            state.seen = time;
+                        } else if time.is_some() && time != state.reported {
+                            let change = FileChange {
+                                path: path.clone(),
+                                created: state.reported.is_none(),
+                            };
+                            state.reported = time;
+                            if sender.send(change).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn the file watcher thread");
+
+        Self {
+            watched,
+            changes,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Starts watching `path`, its current content doesn't count as a change.
+    pub fn watch(&self, path: PathBuf) {
+        let time = modified(&path);
+        self.watched
+            .lock()
+            .unwrap()
+            .files
+            .entry(path)
+            .or_insert(FileState {
+                seen: time,
+                reported: time,
+            });
+    }
+
+    pub fn unwatch(&self, path: &Path) {
+        self.watched.lock().unwrap().files.remove(path);
+    }
+
+    /// Reports the files created under `dir` from now on. The files already
+    /// there aren't watched for changes.
+    pub fn watch_dir(&self, dir: PathBuf) {
+        let mut files = Vec::new();
+        list_files(&dir, &mut files);
+        self.watched
+            .lock()
+            .unwrap()
+            .dirs
+            .entry(dir)
+            .or_insert_with(|| files.into_iter().collect());
+    }
+
+    /// Files changed since the last call, without duplicates.
+    pub fn changes(&self) -> Vec<FileChange> {
+        let mut changes = self.changes.try_iter().collect::<Vec<_>>();
+        changes.sort();
+        changes.dedup_by(|later, first| {
+            let same = later.path == first.path;
+            first.created |= same && later.created;
+            same
+        });
+        changes
+    }
+}
+
+impl Drop for FileWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Keeps track of the files each asset was loaded from, e.g. with
+/// `loader::Pending::files`, and tells which assets to reload when some of
+/// them change. A file created in a watched directory reloads every asset,
+/// since it can change what a load finds, e.g. `cube_lod1.obj`.
+pub struct HotReloader<K> {
+    watcher: FileWatcher,
+    assets: Vec<(K, Vec<PathBuf>)>,
+}
+
+impl<K: Copy + PartialEq> HotReloader<K> {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            watcher: FileWatcher::new(interval),
+            assets: Vec::new(),
+        }
+    }
+
+    /// Replaces the files `asset` depends on.
+    pub fn set_files(&mut self, asset: K, files: Vec<PathBuf>) {
+        self.assets.retain(|(key, _)| *key != asset);
+        for file in &files {
+            self.watcher.watch(file.clone());
+        }
+        self.assets.push((asset, files));
+
+        // Stops watching the files no asset depends on anymore, new files are
+        // kept until they are reported
+        let assets = &self.assets;
+        self.watcher
+            .watched
+            .lock()
+            .unwrap()
+            .files
+            .retain(|file, state| {
+                state.reported.is_none() || assets.iter().any(|(_, files)| files.contains(file))
+            });
+    }
+
+    /// See `FileWatcher::watch_dir`.
+    pub fn watch_dir(&self, dir: PathBuf) {
+        self.watcher.watch_dir(dir);
+    }
+
+    /// Changed files and the assets using them.
+    pub fn poll(&self) -> (Vec<PathBuf>, Vec<K>) {
+        let changes = self.watcher.changes();
+        let created = changes.iter().any(|change| change.created);
+        let changes = changes
+            .into_iter()
+            .map(|change| change.path)
+            .collect::<Vec<_>>();
+        let assets = self
+            .assets
+            .iter()
+            .filter(|(_, files)| created || files.iter().any(|file| changes.contains(file)))
+            .map(|(asset, _)| *asset)
+            .collect();
+        (changes, assets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait_for_changes(watcher: &FileWatcher) -> Vec<FileChange> {
+        for _ in 0..200 {
+            let changes = watcher.changes();
+            if !changes.is_empty() {
+                return changes;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Vec::new()
+    }
+
+    #[test]
+    fn files_created_in_watched_directories_are_reported() {
+        let dir = std::env::temp_dir().join(format!("hot_reload_test_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("models")).unwrap();
+        let existing = dir.join("cube.obj");
+        std::fs::write(&existing, "o cube").unwrap();
+
+        let watcher = FileWatcher::new(Duration::from_millis(10));
+        watcher.watch(existing.clone());
+        watcher.watch_dir(dir.clone());
+        let created = dir.join("models").join("cube_lod1.obj");
+        std::fs::write(&created, "o cube").unwrap();
+        let changes = wait_for_changes(&watcher);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            changes,
+            vec![FileChange {
+                path: created,
+                created: true
+            }]
+        );
+    }
+}
@@ -1,18 +1,40 @@
+use super::instance_generators::{Grid, InstanceGenerator};
 use cgmath::prelude::*;
-use wgpu::util::DeviceExt;
+use std::ops::Range;
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Instance {
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
+    /// Non uniform scale, applied before the rotation.
+    pub scale: cgmath::Vector3<f32>,
+    /// RGBA multiplied with the material colour.
+    pub tint: [f32; 4],
+    /// Free for the shaders to use, e.g. a value to visualize.
+    pub data: [f32; 4],
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct InstanceRaw {
     model: [[f32; 4]; 4],
+    tint: [f32; 4],
+    data: [f32; 4],
 }
 
 impl InstanceRaw {
+    pub fn new(model: cgmath::Matrix4<f32>, tint: [f32; 4], data: [f32; 4]) -> Self {
+        Self {
+            model: model.into(),
+            tint,
+            data,
+        }
+    }
+
+    pub fn model_matrix(&self) -> cgmath::Matrix4<f32> {
+        self.model.into()
+    }
+
     fn desc() -> wgpu::VertexBufferLayout<'static> {
         use std::mem;
         wgpu::VertexBufferLayout {
@@ -46,20 +68,61 @@ impl InstanceRaw {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float32x4,
                 },
+                // tint
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                // data
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 20]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
             ],
         }
     }
 }
 
 impl Instance {
-    fn to_raw(&self) -> InstanceRaw {
-        InstanceRaw {
-            model: (cgmath::Matrix4::from_translation(self.position)
-                * cgmath::Matrix4::from(self.rotation))
-            .into(),
+    pub const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
+    pub fn new(position: cgmath::Vector3<f32>, rotation: cgmath::Quaternion<f32>) -> Self {
+        Self {
+            position,
+            rotation,
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+            tint: Self::WHITE,
+            data: [0.0; 4],
         }
     }
 
+    pub fn with_scale(mut self, scale: cgmath::Vector3<f32>) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_tint(mut self, tint: [f32; 4]) -> Self {
+        self.tint = tint;
+        self
+    }
+
+    pub fn with_data(mut self, data: [f32; 4]) -> Self {
+        self.data = data;
+        self
+    }
+
+    pub fn to_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.position)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    pub fn to_raw(&self) -> InstanceRaw {
+        InstanceRaw::new(self.to_matrix(), self.tint, self.data)
+    }
+
     pub fn desc() -> wgpu::VertexBufferLayout<'static> {
         InstanceRaw::desc()
     }
@@ -69,45 +132,226 @@ impl Instance {
         displacement: cgmath::Vector3<f32>,
         device: &wgpu::Device,
     ) -> InstancesVec {
-        let instances = (0..instances_per_row)
-            .flat_map(|z| {
-                (0..instances_per_row).map(move |x| {
-                    let position = cgmath::Vector3 {
-                        x: (3 * x) as f32,
-                        y: 0.0,
-                        z: (3 * z) as f32,
-                    } - displacement;
-
-                    let rotation = if position.is_zero() {
-                        // this is needed so an object at (0, 0, 0) won't get scaled to zero
-                        // as Quaternions can affect scale if they're not created correctly
-                        cgmath::Quaternion::from_axis_angle(
-                            cgmath::Vector3::unit_z(),
-                            cgmath::Deg(0.0),
-                        )
-                    } else {
-                        cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(45.0))
-                    };
-
-                    Instance { position, rotation }
-                })
+        let grid = Grid {
+            dimensions: [instances_per_row, 1, instances_per_row],
+            spacing: cgmath::Vector3::new(3.0, 0.0, 3.0),
+            origin: -displacement,
+        };
+        let instances = grid
+            .instances()
+            .into_iter()
+            .map(|instance| {
+                let position = instance.position;
+                let rotation = if position.is_zero() {
+                    // this is needed so an object at (0, 0, 0) won't get scaled to zero
+                    // as Quaternions can affect scale if they're not created correctly
+                    cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
+                } else {
+                    cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(45.0))
+                };
+                Instance {
+                    rotation,
+                    ..instance
+                }
             })
             .collect::<Vec<_>>();
 
-        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
-        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Instance Buffer"),
-            contents: bytemuck::cast_slice(&instance_data),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
-        InstancesVec {
-            instances,
-            buffer: instance_buffer,
+        InstancesVec::new(instances, device)
+    }
+}
+
+/// GPU buffer of `InstanceRaw` that grows when it runs out of space.
+pub struct InstanceBuffer {
+    pub buffer: wgpu::Buffer,
+    capacity: usize,
+    label: &'static str,
+}
+
+impl InstanceBuffer {
+    const MIN_CAPACITY: usize = 64;
+
+    pub fn new(device: &wgpu::Device, capacity: usize, label: &'static str) -> Self {
+        let capacity = capacity.max(Self::MIN_CAPACITY);
+        Self {
+            buffer: Self::create_buffer(device, capacity, label),
+            capacity,
+            label,
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize, label: &str) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            // STORAGE so the GPU culling can read the instances
+            usage: wgpu::BufferUsages::VERTEX
+                | wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Makes room for at least `len` instances, doubling the capacity so repeated
+    /// pushes don't reallocate every frame. The old contents are lost when this
+    /// returns true.
+    pub fn reserve(&mut self, device: &wgpu::Device, len: usize) -> bool {
+        if len <= self.capacity {
+            return false;
+        }
+        self.capacity = len.next_power_of_two().max(Self::MIN_CAPACITY);
+        self.buffer = Self::create_buffer(device, self.capacity, self.label);
+        true
+    }
+
+    /// Writes `data` starting at the instance `offset`, the buffer must be big enough.
+    pub fn write(&self, queue: &wgpu::Queue, offset: usize, data: &[InstanceRaw]) {
+        if data.is_empty() {
+            return;
         }
+        let offset = (offset * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress;
+        queue.write_buffer(&self.buffer, offset, bytemuck::cast_slice(data));
     }
 }
 
+/// Identifies an instance of an `InstancesVec` for as long as it is in there,
+/// unlike its index which changes when another instance is removed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct InstanceId(u64);
+
+/// Instances kept on the CPU together with their GPU copy. Changes are tracked as
+/// dirty ranges and only those are uploaded by `upload`.
 pub struct InstancesVec {
-    pub instances: Vec<Instance>,
-    pub buffer: wgpu::Buffer,
+    instances: Vec<Instance>,
+    buffer: InstanceBuffer,
+    raw: Vec<InstanceRaw>,
+    ids: Vec<InstanceId>,
+    next_id: u64,
+    // Sorted and non overlapping
+    dirty: Vec<Range<usize>>,
+}
+
+impl InstancesVec {
+    pub fn new(instances: Vec<Instance>, device: &wgpu::Device) -> Self {
+        let raw = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+        let buffer = InstanceBuffer::new(device, raw.len(), "Instance Buffer");
+        let len = raw.len();
+        let mut instances_vec = Self {
+            instances,
+            buffer,
+            raw,
+            ids: (0..len as u64).map(InstanceId).collect(),
+            next_id: len as u64,
+            dirty: Vec::new(),
+        };
+        instances_vec.mark_dirty(0..len);
+        instances_vec
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    /// Change instances with `set`, `update` or `update_range` so they get
+    /// uploaded.
+    pub fn instances(&self) -> &[Instance] {
+        &self.instances
+    }
+
+    pub fn raw(&self) -> &[InstanceRaw] {
+        &self.raw
+    }
+
+    pub fn buffer(&self) -> &InstanceBuffer {
+        &self.buffer
+    }
+
+    /// Id of the instance at each index.
+    pub fn ids(&self) -> &[InstanceId] {
+        &self.ids
+    }
+
+    pub fn push(&mut self, instance: Instance) -> usize {
+        let index = self.instances.len();
+        self.ids.push(InstanceId(self.next_id));
+        self.next_id += 1;
+        self.raw.push(instance.to_raw());
+        self.instances.push(instance);
+        self.mark_dirty(index..index + 1);
+        index
+    }
+
+    /// Removes an instance by moving the last one into its place, so only one
+    /// instance has to be uploaded again.
+    pub fn swap_remove(&mut self, index: usize) -> Instance {
+        self.raw.swap_remove(index);
+        self.ids.swap_remove(index);
+        let removed = self.instances.swap_remove(index);
+        if index < self.instances.len() {
+            self.mark_dirty(index..index + 1);
+        }
+        self.dirty.retain_mut(|range| {
+            range.end = range.end.min(self.raw.len());
+            range.start < range.end
+        });
+        removed
+    }
+
+    pub fn set(&mut self, index: usize, instance: Instance) {
+        self.update(index, |current| *current = instance);
+    }
+
+    pub fn update(&mut self, index: usize, f: impl FnOnce(&mut Instance)) {
+        f(&mut self.instances[index]);
+        self.raw[index] = self.instances[index].to_raw();
+        self.mark_dirty(index..index + 1);
+    }
+
+    /// Applies `f` to every instance in `range` and marks them as changed.
+    pub fn update_range(&mut self, range: Range<usize>, mut f: impl FnMut(usize, &mut Instance)) {
+        for index in range.clone() {
+            f(index, &mut self.instances[index]);
+            self.raw[index] = self.instances[index].to_raw();
+        }
+        self.mark_dirty(range);
+    }
+
+    pub fn mark_dirty(&mut self, range: Range<usize>) {
+        if range.start >= range.end {
+            return;
+        }
+        // Merge with every range that overlaps or touches the new one
+        let start = self.dirty.partition_point(|r| r.end < range.start);
+        let end = self.dirty.partition_point(|r| r.start <= range.end);
+        let merged = if start < end {
+            self.dirty[start].start.min(range.start)..self.dirty[end - 1].end.max(range.end)
+        } else {
+            range
+        };
+        self.dirty.splice(start..end, std::iter::once(merged));
+    }
+
+    pub fn dirty_ranges(&self) -> &[Range<usize>] {
+        &self.dirty
+    }
+
+    /// Sends the changed instances to the GPU, growing the buffer if needed.
+    pub fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if self.buffer.reserve(device, self.raw.len()) {
+            self.buffer.write(queue, 0, &self.raw);
+        } else {
+            for range in &self.dirty {
+                self.buffer
+                    .write(queue, range.start, &self.raw[range.clone()]);
+            }
+        }
+        self.dirty.clear();
+    }
 }
@@ -0,0 +1,428 @@
+use super::{bounds::Aabb, model::ModelVertex, Instance, InstancesVec};
+use cgmath::prelude::*;
+use std::collections::HashSet;
+
+/// Something that places instances in the world.
+pub trait InstanceGenerator {
+    fn instances(&self) -> Vec<Instance>;
+
+    fn build(&self, device: &wgpu::Device) -> InstancesVec {
+        InstancesVec::new(self.instances(), device)
+    }
+}
+
+/// Instances on a regular 3D grid, `origin` is the position of the first one.
+/// X changes fastest, then Y, then Z.
+#[derive(Debug, Copy, Clone)]
+pub struct Grid {
+    pub dimensions: [usize; 3],
+    pub spacing: cgmath::Vector3<f32>,
+    pub origin: cgmath::Vector3<f32>,
+}
+
+impl Grid {
+    /// A grid centered on `center`.
+    pub fn centered(
+        dimensions: [usize; 3],
+        spacing: cgmath::Vector3<f32>,
+        center: cgmath::Vector3<f32>,
+    ) -> Self {
+        let size = cgmath::Vector3::new(
+            dimensions[0].saturating_sub(1) as f32 * spacing.x,
+            dimensions[1].saturating_sub(1) as f32 * spacing.y,
+            dimensions[2].saturating_sub(1) as f32 * spacing.z,
+        );
+        Self {
+            dimensions,
+            spacing,
+            origin: center - size * 0.5,
+        }
+    }
+}
+
+impl InstanceGenerator for Grid {
+    fn instances(&self) -> Vec<Instance> {
+        let [nx, ny, nz] = self.dimensions;
+        (0..nz)
+            .flat_map(|z| (0..ny).flat_map(move |y| (0..nx).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| {
+                let offset = cgmath::Vector3::new(
+                    x as f32 * self.spacing.x,
+                    y as f32 * self.spacing.y,
+                    z as f32 * self.spacing.z,
+                );
+                Instance::new(self.origin + offset, cgmath::Quaternion::one())
+            })
+            .collect()
+    }
+}
+
+/// Instances evenly spread on a horizontal circle, rotated so their local +X
+/// points away from the center.
+#[derive(Debug, Copy, Clone)]
+pub struct Ring {
+    pub count: usize,
+    pub radius: f32,
+    pub center: cgmath::Vector3<f32>,
+}
+
+impl InstanceGenerator for Ring {
+    fn instances(&self) -> Vec<Instance> {
+        let step = std::f32::consts::TAU / self.count.max(1) as f32;
+        (0..self.count)
+            .map(|i| {
+                let angle = cgmath::Rad(step * i as f32);
+                let offset = cgmath::Vector3::new(angle.cos(), 0.0, angle.sin()) * self.radius;
+                // -angle because a positive rotation around Y goes from +X towards -Z
+                let rotation = cgmath::Quaternion::from_angle_y(-angle);
+                Instance::new(self.center + offset, rotation)
+            })
+            .collect()
+    }
+}
+
+/// Archimedean spiral around the Y axis, optionally climbing like a helix.
+/// Instances are oriented like in `Ring`.
+#[derive(Debug, Copy, Clone)]
+pub struct Spiral {
+    pub count: usize,
+    pub start_radius: f32,
+    /// How much the radius grows on each full turn.
+    pub radius_per_turn: f32,
+    /// Angle between two consecutive instances.
+    pub angle_step: cgmath::Deg<f32>,
+    /// Height gained between two consecutive instances.
+    pub height_step: f32,
+    pub center: cgmath::Vector3<f32>,
+}
+
+impl InstanceGenerator for Spiral {
+    fn instances(&self) -> Vec<Instance> {
+        let step = cgmath::Rad::from(self.angle_step);
+        (0..self.count)
+            .map(|i| {
+                let angle = step * i as f32;
+                let turns = angle.0 / std::f32::consts::TAU;
+                let radius = self.start_radius + self.radius_per_turn * turns;
+                let offset = cgmath::Vector3::new(
+                    angle.cos() * radius,
+                    self.height_step * i as f32,
+                    angle.sin() * radius,
+                );
+                let rotation = cgmath::Quaternion::from_angle_y(-angle);
+                Instance::new(self.center + offset, rotation)
+            })
+            .collect()
+    }
+}
+
+/// Instances at random positions inside a box. The same seed always gives the
+/// same instances.
+#[derive(Debug, Copy, Clone)]
+pub struct Scatter {
+    pub count: usize,
+    pub bounds: Aabb,
+    pub seed: u64,
+    pub random_rotation: bool,
+}
+
+impl InstanceGenerator for Scatter {
+    fn instances(&self) -> Vec<Instance> {
+        let mut rng = SplitMix64(self.seed);
+        let extent = self.bounds.extent();
+        (0..self.count)
+            .map(|_| {
+                let position = self.bounds.min.to_vec()
+                    + cgmath::Vector3::new(
+                        rng.next_f32() * extent.x,
+                        rng.next_f32() * extent.y,
+                        rng.next_f32() * extent.z,
+                    );
+                let rotation = if self.random_rotation {
+                    rng.next_rotation()
+                } else {
+                    cgmath::Quaternion::one()
+                };
+                Instance::new(position, rotation)
+            })
+            .collect()
+    }
+}
+
+/// One instance on each distinct vertex position of a mesh, e.g. of
+/// `Mesh::vertices`.
+pub struct MeshVertices<'a> {
+    pub vertices: &'a [ModelVertex],
+    /// Moves the mesh vertices to world space.
+    pub transform: cgmath::Matrix4<f32>,
+    /// Rotates the instances so their +Y points along the vertex normal.
+    pub align_to_normals: bool,
+}
+
+impl InstanceGenerator for MeshVertices<'_> {
+    fn instances(&self) -> Vec<Instance> {
+        // Vertices are split on UV seams, only keep one instance per position
+        let mut seen = HashSet::new();
+        // Normals go through the inverse transpose so they stay perpendicular
+        // to the surface under non uniform scale. The cofactor matrix is the
+        // inverse transpose times the determinant, whose sign is kept so
+        // mirroring transforms don't flip the normals.
+        let [x, y, z] = [0, 1, 2].map(|i| self.transform[i].truncate());
+        let normal_matrix = cgmath::Matrix3::from_cols(y.cross(z), z.cross(x), x.cross(y))
+            * x.dot(y.cross(z)).signum();
+        self.vertices
+            .iter()
+            .filter(|vertex| seen.insert(vertex.position.map(f32::to_bits)))
+            .map(|vertex| {
+                let position = self
+                    .transform
+                    .transform_point(vertex.position.into())
+                    .to_vec();
+                let normal = normal_matrix * cgmath::Vector3::from(vertex.normal);
+                let rotation = if self.align_to_normals && normal.magnitude2() > 0.0 {
+                    cgmath::Quaternion::from_arc(
+                        cgmath::Vector3::unit_y(),
+                        normal.normalize(),
+                        None,
+                    )
+                } else {
+                    cgmath::Quaternion::one()
+                };
+                Instance::new(position, rotation)
+            })
+            .collect()
+    }
+}
+
+/// Small deterministic generator, good enough to place objects and stable
+/// across platforms and dependency updates.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniformly distributed rotation (Shoemake's method)
+    fn next_rotation(&mut self) -> cgmath::Quaternion<f32> {
+        let (u1, u2, u3) = (self.next_f32(), self.next_f32(), self.next_f32());
+        let (a, b) = ((1.0 - u1).sqrt(), u1.sqrt());
+        let (t2, t3) = (std::f32::consts::TAU * u2, std::f32::consts::TAU * u3);
+        cgmath::Quaternion::new(b * t3.cos(), a * t2.sin(), a * t2.cos(), b * t3.sin())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: cgmath::Vector3<f32>, b: cgmath::Vector3<f32>) {
+        assert!((a - b).magnitude() < 1e-5, "{a:?} != {b:?}");
+    }
+
+    fn vertex(position: [f32; 3], normal: [f32; 3]) -> ModelVertex {
+        ModelVertex {
+            position,
+            tex_coords: [0.0; 2],
+            normal,
+            tangent: [1.0, 0.0, 0.0, 1.0],
+        }
+    }
+
+    #[test]
+    fn grid_goes_x_then_y_then_z() {
+        let grid = Grid {
+            dimensions: [3, 2, 2],
+            spacing: cgmath::Vector3::new(1.0, 2.0, 3.0),
+            origin: cgmath::Vector3::new(10.0, 0.0, 0.0),
+        };
+        let instances = grid.instances();
+        assert_eq!(instances.len(), 12);
+        assert_close(instances[0].position, cgmath::Vector3::new(10.0, 0.0, 0.0));
+        assert_close(instances[1].position, cgmath::Vector3::new(11.0, 0.0, 0.0));
+        assert_close(instances[3].position, cgmath::Vector3::new(10.0, 2.0, 0.0));
+        assert_close(instances[6].position, cgmath::Vector3::new(10.0, 0.0, 3.0));
+        assert_close(instances[11].position, cgmath::Vector3::new(12.0, 2.0, 3.0));
+    }
+
+    #[test]
+    fn centered_grid_is_symmetric_around_its_center() {
+        let center = cgmath::Vector3::new(1.0, 2.0, 3.0);
+        let grid = Grid::centered([4, 1, 3], cgmath::Vector3::new(2.0, 5.0, 1.0), center);
+        let instances = grid.instances();
+        assert_eq!(instances.len(), 12);
+        let sum = instances
+            .iter()
+            .fold(cgmath::Vector3::zero(), |sum, instance| {
+                sum + instance.position
+            });
+        assert_close(sum / instances.len() as f32, center);
+        assert_close(instances[0].position, cgmath::Vector3::new(-2.0, 2.0, 2.0));
+
+        assert!(Grid::centered([0, 5, 5], grid.spacing, center)
+            .instances()
+            .is_empty());
+    }
+
+    #[test]
+    fn ring_instances_face_away_from_the_center() {
+        let center = cgmath::Vector3::new(0.0, 1.0, 0.0);
+        let ring = Ring {
+            count: 8,
+            radius: 3.0,
+            center,
+        };
+        let instances = ring.instances();
+        assert_eq!(instances.len(), 8);
+        for instance in &instances {
+            let offset = instance.position - center;
+            assert!((offset.magnitude() - 3.0).abs() < 1e-5);
+            assert_eq!(offset.y, 0.0);
+            assert_close(
+                instance.rotation.rotate_vector(cgmath::Vector3::unit_x()),
+                offset / 3.0,
+            );
+        }
+        assert_close(instances[2].position, cgmath::Vector3::new(0.0, 1.0, 3.0));
+        assert!(Ring { count: 0, ..ring }.instances().is_empty());
+    }
+
+    #[test]
+    fn spiral_grows_by_radius_per_turn() {
+        let spiral = Spiral {
+            count: 9,
+            start_radius: 1.0,
+            radius_per_turn: 2.0,
+            angle_step: cgmath::Deg(90.0),
+            height_step: 0.5,
+            center: cgmath::Vector3::zero(),
+        };
+        let instances = spiral.instances();
+        assert_eq!(instances.len(), 9);
+        for (i, instance) in instances.iter().enumerate() {
+            let radius = 1.0 + 2.0 * i as f32 / 4.0;
+            let horizontal = cgmath::Vector3::new(instance.position.x, 0.0, instance.position.z);
+            assert!((horizontal.magnitude() - radius).abs() < 1e-5);
+            assert!((instance.position.y - 0.5 * i as f32).abs() < 1e-5);
+            assert_close(
+                instance.rotation.rotate_vector(cgmath::Vector3::unit_x()),
+                horizontal / radius,
+            );
+        }
+        // After a full turn the spiral is back on +X, one radius_per_turn out
+        assert_close(instances[4].position, cgmath::Vector3::new(3.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn scatter_is_deterministic_and_inside_the_bounds() {
+        let scatter = Scatter {
+            count: 200,
+            bounds: Aabb::new(
+                cgmath::Point3::new(-1.0, 2.0, 10.0),
+                cgmath::Point3::new(3.0, 2.5, 20.0),
+            ),
+            seed: 42,
+            random_rotation: true,
+        };
+        let instances = scatter.instances();
+        assert_eq!(instances.len(), 200);
+        for instance in &instances {
+            let p = cgmath::Point3::from_vec(instance.position);
+            assert!(p.x >= -1.0 && p.x < 3.0, "{p:?}");
+            assert!(p.y >= 2.0 && p.y < 2.5, "{p:?}");
+            assert!(p.z >= 10.0 && p.z < 20.0, "{p:?}");
+            assert!((instance.rotation.magnitude() - 1.0).abs() < 1e-5);
+        }
+
+        let again = scatter.instances();
+        assert!(instances
+            .iter()
+            .zip(&again)
+            .all(|(a, b)| a.position == b.position && a.rotation == b.rotation));
+
+        let other = Scatter {
+            seed: 43,
+            ..scatter
+        }
+        .instances();
+        assert!(instances
+            .iter()
+            .zip(&other)
+            .all(|(a, b)| a.position != b.position));
+
+        let upright = Scatter {
+            random_rotation: false,
+            ..scatter
+        }
+        .instances();
+        assert!(upright
+            .iter()
+            .all(|instance| instance.rotation == cgmath::Quaternion::one()));
+    }
+
+    #[test]
+    fn mesh_vertices_skip_duplicate_positions() {
+        let vertices = [
+            vertex([0.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+            vertex([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+            // Same position on the other side of a UV seam
+            vertex([0.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+            vertex([0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+        ];
+        let generator = MeshVertices {
+            vertices: &vertices,
+            transform: cgmath::Matrix4::from_translation(cgmath::Vector3::new(0.0, 5.0, 0.0)),
+            align_to_normals: false,
+        };
+        let positions: Vec<_> = generator
+            .instances()
+            .iter()
+            .map(|instance| instance.position)
+            .collect();
+        assert_eq!(
+            positions,
+            [
+                cgmath::Vector3::new(0.0, 5.0, 0.0),
+                cgmath::Vector3::new(1.0, 5.0, 0.0),
+                cgmath::Vector3::new(0.0, 5.0, 1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn mesh_vertices_stay_perpendicular_under_non_uniform_scale() {
+        // A vertex on the plane x + y = 1. Scaling X by 2 turns it into
+        // x / 2 + y = 1, whose normal is (1, 2, 0) normalized.
+        let normal = cgmath::Vector3::new(1.0, 1.0, 0.0).normalize();
+        let vertices = [vertex([0.5, 0.5, 0.0], normal.into())];
+        let expected = cgmath::Vector3::new(1.0, 2.0, 0.0).normalize();
+        for transform in [
+            cgmath::Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0),
+            // Mirroring on Z doesn't change the plane, the normal mustn't flip
+            cgmath::Matrix4::from_nonuniform_scale(2.0, 1.0, -1.0),
+        ] {
+            let instances = MeshVertices {
+                vertices: &vertices,
+                transform,
+                align_to_normals: true,
+            }
+            .instances();
+            assert_close(instances[0].position, cgmath::Vector3::new(1.0, 0.5, 0.0));
+            assert_close(
+                instances[0]
+                    .rotation
+                    .rotate_vector(cgmath::Vector3::unit_y()),
+                expected,
+            );
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Reading and writing instance placements as CSV or JSON tables.
+//!
+//! Both formats use the same columns, only `x`, `y` and `z` are required:
+//!
+//! | columns                | meaning                                    |
+//! |------------------------|--------------------------------------------|
+//! | `x`, `y`, `z`          | position                                   |
+//! | `qx`, `qy`, `qz`, `qw` | rotation as a quaternion                   |
+//! | `rx`, `ry`, `rz`       | rotation as Euler angles in degrees        |
+//! | `sx`, `sy`, `sz`       | scale, defaults to 1                       |
+//! | `r`, `g`, `b`, `a`     | tint, defaults to white, `a` defaults to 1 |
+//!
+//! JSON files hold an array of objects with those keys.
+
+use super::Instance;
+use cgmath::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{Read, Write},
+    path::Path,
+};
+
+/// A row of the table that can't be turned into an instance. Rows are counted
+/// from 1 and don't include the CSV header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowError {
+    pub row: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "row {}: {}", self.row, self.message)
+    }
+}
+
+impl std::error::Error for RowError {}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct InstanceRecord {
+    x: f32,
+    y: f32,
+    z: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    qx: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    qy: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    qz: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    qw: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rx: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ry: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rz: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sx: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sy: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sz: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    g: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    b: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<f32>,
+}
+
+/// `Some` when all the values are present, `None` when none of them are.
+fn all_or_none<const N: usize>(
+    values: [Option<f32>; N],
+    names: &str,
+) -> Result<Option<[f32; N]>, String> {
+    match values.iter().filter(|v| v.is_some()).count() {
+        0 => Ok(None),
+        n if n == N => {
+            let values = values.map(Option::unwrap);
+            if values.iter().all(|v| v.is_finite()) {
+                Ok(Some(values))
+            } else {
+                Err(format!("{names} must be finite numbers"))
+            }
+        }
+        _ => Err(format!("{names} must be given together")),
+    }
+}
+
+impl InstanceRecord {
+    fn from_instance(instance: &Instance) -> Self {
+        let q = instance.rotation;
+        Self {
+            x: instance.position.x,
+            y: instance.position.y,
+            z: instance.position.z,
+            qx: Some(q.v.x),
+            qy: Some(q.v.y),
+            qz: Some(q.v.z),
+            qw: Some(q.s),
+            sx: Some(instance.scale.x),
+            sy: Some(instance.scale.y),
+            sz: Some(instance.scale.z),
+            r: Some(instance.tint[0]),
+            g: Some(instance.tint[1]),
+            b: Some(instance.tint[2]),
+            a: Some(instance.tint[3]),
+            ..Default::default()
+        }
+    }
+
+    fn to_instance(&self) -> Result<Instance, String> {
+        let position = cgmath::Vector3::new(self.x, self.y, self.z);
+        if !position.x.is_finite() || !position.y.is_finite() || !position.z.is_finite() {
+            return Err("x, y and z must be finite numbers".to_string());
+        }
+
+        let quaternion = all_or_none([self.qx, self.qy, self.qz, self.qw], "qx, qy, qz and qw")?;
+        let euler = all_or_none([self.rx, self.ry, self.rz], "rx, ry and rz")?;
+        let rotation = match (quaternion, euler) {
+            (Some(_), Some(_)) => {
+                return Err("rotation is given both as a quaternion and as Euler angles".to_string())
+            }
+            (Some([x, y, z, w]), None) => {
+                let q = cgmath::Quaternion::new(w, x, y, z);
+                if q.magnitude2() < 1e-12 {
+                    return Err("quaternion must not be zero".to_string());
+                }
+                q.normalize()
+            }
+            (None, Some([x, y, z])) => cgmath::Quaternion::from(cgmath::Euler::new(
+                cgmath::Deg(x),
+                cgmath::Deg(y),
+                cgmath::Deg(z),
+            )),
+            (None, None) => cgmath::Quaternion::one(),
+        };
+
+        let mut instance = Instance::new(position, rotation);
+        if let Some(scale) = all_or_none([self.sx, self.sy, self.sz], "sx, sy and sz")? {
+            instance.scale = scale.into();
+        }
+        if let Some([r, g, b]) = all_or_none([self.r, self.g, self.b], "r, g and b")? {
+            let a = self.a.unwrap_or(1.0);
+            if !a.is_finite() {
+                return Err("a must be a finite number".to_string());
+            }
+            instance.tint = [r, g, b, a];
+        } else if self.a.is_some() {
+            return Err("a needs r, g and b".to_string());
+        }
+        Ok(instance)
+    }
+}
+
+fn records_to_instances(
+    records: impl Iterator<Item = anyhow::Result<InstanceRecord>>,
+) -> anyhow::Result<Vec<Instance>> {
+    records
+        .enumerate()
+        .map(|(index, record)| {
+            let row = index + 1;
+            let record = record.map_err(|err| RowError {
+                row,
+                message: err.to_string(),
+            })?;
+            record
+                .to_instance()
+                .map_err(|message| RowError { row, message }.into())
+        })
+        .collect()
+}
+
+pub fn read_csv(reader: impl Read) -> anyhow::Result<Vec<Instance>> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    records_to_instances(
+        csv_reader
+            .deserialize::<InstanceRecord>()
+            .map(|record| record.map_err(anyhow::Error::from)),
+    )
+}
+
+pub fn write_csv(writer: impl Write, instances: &[Instance]) -> anyhow::Result<()> {
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    for instance in instances {
+        csv_writer.serialize(InstanceRecord::from_instance(instance))?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+pub fn read_json(reader: impl Read) -> anyhow::Result<Vec<Instance>> {
+    // Parsing the rows one by one keeps the row number for type errors
+    let rows: Vec<serde_json::Value> = serde_json::from_reader(reader)?;
+    records_to_instances(
+        rows.into_iter()
+            .map(|row| serde_json::from_value::<InstanceRecord>(row).map_err(anyhow::Error::from)),
+    )
+}
+
+pub fn write_json(writer: impl Write, instances: &[Instance]) -> anyhow::Result<()> {
+    let records = instances
+        .iter()
+        .map(InstanceRecord::from_instance)
+        .collect::<Vec<_>>();
+    serde_json::to_writer_pretty(writer, &records)?;
+    Ok(())
+}
+
+enum Format {
+    Csv,
+    Json,
+}
+
+fn format_of(path: &Path) -> anyhow::Result<Format> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("csv") => Ok(Format::Csv),
+        Some(ext) if ext.eq_ignore_ascii_case("json") => Ok(Format::Json),
+        _ => anyhow::bail!("unknown instance file format: {}", path.display()),
+    }
+}
+
+/// Reads a `.csv` or `.json` file, errors mention the file and the row.
+pub fn load_instances(path: impl AsRef<Path>) -> anyhow::Result<Vec<Instance>> {
+    let path = path.as_ref();
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let instances = match format_of(path)? {
+        Format::Csv => read_csv(file),
+        Format::Json => read_json(file),
+    };
+    instances.map_err(|err| err.context(format!("loading {}", path.display())))
+}
+
+pub fn save_instances(path: impl AsRef<Path>, instances: &[Instance]) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let format = format_of(path)?;
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    match format {
+        Format::Csv => write_csv(file, instances),
+        Format::Json => write_json(file, instances),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row_error(result: anyhow::Result<Vec<Instance>>) -> RowError {
+        result
+            .unwrap_err()
+            .downcast::<RowError>()
+            .expect("not a row error")
+    }
+
+    #[test]
+    fn reads_a_valid_csv_file() {
+        let csv = "x,y,z,rx,ry,rz,sx,sy,sz,r,g,b,a\n\
+                   1,2,3,0,90,0,1,2,3,1,0,0,0.5\n\
+                   -1,0,0,0,0,0,1,1,1,0,1,0,1\n";
+        let instances = read_csv(csv.as_bytes()).unwrap();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].position, cgmath::Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(instances[0].scale, cgmath::Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(instances[0].tint, [1.0, 0.0, 0.0, 0.5]);
+        let rotated = instances[0]
+            .rotation
+            .rotate_vector(cgmath::Vector3::unit_x());
+        assert!((rotated - -cgmath::Vector3::unit_z()).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn optional_columns_default() {
+        let instances = read_csv("x,y,z\n1,2,3\n".as_bytes()).unwrap();
+        assert_eq!(instances[0].rotation, cgmath::Quaternion::one());
+        assert_eq!(instances[0].scale, cgmath::Vector3::new(1.0, 1.0, 1.0));
+        assert_eq!(instances[0].tint, [1.0; 4]);
+
+        let json = r#"[{"x": 1, "y": 2, "z": 3, "r": 0.5, "g": 0.5, "b": 0.5}]"#;
+        let instances = read_json(json.as_bytes()).unwrap();
+        assert_eq!(instances[0].tint, [0.5, 0.5, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn bad_rows_report_their_number() {
+        let csv = "x,y,z,sx,sy,sz\n0,0,0,1,1,1\n0,0,0,1,,1\n";
+        let err = row_error(read_csv(csv.as_bytes()));
+        assert_eq!(err.row, 2);
+        assert!(err.message.contains("sx, sy and sz"), "{err}");
+
+        let csv = "x,y,z\n0,0,0\n0,0,0\n0,oops,0\n";
+        assert_eq!(row_error(read_csv(csv.as_bytes())).row, 3);
+
+        let json = r#"[{"x": 0, "y": 0, "z": 0}, {"x": 0, "y": 0}]"#;
+        assert_eq!(row_error(read_json(json.as_bytes())).row, 2);
+
+        let json = r#"[{"x": 0, "y": 0, "z": 0, "qx": 0, "qy": 0, "qz": 0, "qw": 0}]"#;
+        let err = row_error(read_json(json.as_bytes()));
+        assert_eq!(
+            (err.row, err.message.as_str()),
+            (1, "quaternion must not be zero")
+        );
+    }
+
+    #[test]
+    fn written_files_read_back() {
+        let mut instance = Instance::new(
+            cgmath::Vector3::new(1.0, -2.0, 0.5),
+            cgmath::Quaternion::from_angle_y(cgmath::Deg(30.0)),
+        );
+        instance.scale = cgmath::Vector3::new(2.0, 1.0, 0.5);
+        instance.tint = [0.25, 0.5, 0.75, 1.0];
+
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &[instance]).unwrap();
+        let mut json = Vec::new();
+        write_json(&mut json, &[instance]).unwrap();
+        for read in [read_csv(csv.as_slice()), read_json(json.as_slice())] {
+            let read = read.unwrap();
+            assert_eq!(read[0].position, instance.position);
+            assert_eq!(read[0].scale, instance.scale);
+            assert_eq!(read[0].tint, instance.tint);
+            assert!((read[0].rotation - instance.rotation).magnitude() < 1e-6);
+        }
+    }
+}
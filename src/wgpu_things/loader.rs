@@ -0,0 +1,296 @@
+//! Loading assets on a pool of worker threads.
+//!
+//! Jobs run the loaders of `resources` to completion on a worker, GPU resources
+//! included since `wgpu::Device` and `wgpu::Queue` can be used from any thread.
+//! The caller keeps drawing a placeholder until `Pending::take` returns the
+//! result.
+
+use super::{
+    assets::{AssetManager, Handle},
+    model::{Material, MaterialFactors, Model},
+    primitives, resources, vfs,
+};
+use std::{
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoadState {
+    Queued,
+    Loading,
+    Loaded,
+    Failed,
+}
+
+struct Slot<T> {
+    state: LoadState,
+    result: Option<anyhow::Result<T>>,
+    files: Vec<PathBuf>,
+}
+
+/// Result of a job, filled in by the worker running it.
+pub struct Pending<T> {
+    name: String,
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Pending<T> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> LoadState {
+        self.slot.lock().unwrap().state
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state(), LoadState::Loaded | LoadState::Failed)
+    }
+
+    /// Files the job read, cached assets included, once it's finished.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.slot.lock().unwrap().files.clone()
+    }
+
+    /// The result once the job is finished. Only the first call gets it.
+    pub fn take(&self) -> Option<anyhow::Result<T>> {
+        self.slot.lock().unwrap().result.take()
+    }
+}
+
+/// Number of jobs in each state since the loader was created.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub queued: usize,
+    pub loading: usize,
+    pub loaded: usize,
+    pub failed: usize,
+}
+
+impl LoadProgress {
+    pub fn total(&self) -> usize {
+        self.queued + self.loading + self.loaded + self.failed
+    }
+
+    pub fn finished(&self) -> usize {
+        self.loaded + self.failed
+    }
+
+    /// Fraction of the jobs that are finished, 1 when there are none.
+    pub fn fraction(&self) -> f32 {
+        match self.total() {
+            0 => 1.0,
+            total => self.finished() as f32 / total as f32,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.queued == 0 && self.loading == 0
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Worker threads loading models and textures into an `AssetManager`.
+/// Dropping the loader waits for the jobs already queued.
+pub struct AssetLoader {
+    sender: Option<mpsc::Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    progress: Arc<Mutex<LoadProgress>>,
+    device: Arc<wgpu::Device>,
+    queue: Arc<wgpu::Queue>,
+    layout: Arc<wgpu::BindGroupLayout>,
+    assets: Arc<AssetManager>,
+}
+
+impl AssetLoader {
+    /// `layout` is the material bind group layout of the loaded models.
+    pub fn new(
+        threads: usize,
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        layout: Arc<wgpu::BindGroupLayout>,
+        assets: Arc<AssetManager>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|index| {
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("asset loader {index}"))
+                    .spawn(move || loop {
+                        // The lock is released before running the job
+                        let job = receiver.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    })
+                    .expect("failed to spawn an asset loader thread")
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+            progress: Arc::new(Mutex::new(LoadProgress::default())),
+            device,
+            queue,
+            layout,
+            assets,
+        }
+    }
+
+    pub fn assets(&self) -> &Arc<AssetManager> {
+        &self.assets
+    }
+
+    pub fn progress(&self) -> LoadProgress {
+        *self.progress.lock().unwrap()
+    }
+
+    /// Runs `load` on a worker. A panic in `load` fails the job.
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        name: &str,
+        load: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+    ) -> Pending<T> {
+        let slot = Arc::new(Mutex::new(Slot {
+            state: LoadState::Queued,
+            result: None,
+            files: Vec::new(),
+        }));
+        self.progress.lock().unwrap().queued += 1;
+
+        let job_slot = slot.clone();
+        let progress = self.progress.clone();
+        let job_name = name.to_string();
+        let job = move || {
+            job_slot.lock().unwrap().state = LoadState::Loading;
+            {
+                let mut progress = progress.lock().unwrap();
+                progress.queued -= 1;
+                progress.loading += 1;
+            }
+
+            let recording = vfs::Recording::start();
+            let result = panic::catch_unwind(AssertUnwindSafe(load))
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("loading {job_name} panicked")));
+            let files = recording.finish();
+            let state = match &result {
+                Ok(_) => LoadState::Loaded,
+                Err(err) => {
+                    log::error!("Loading {job_name} failed: {err:#}");
+                    LoadState::Failed
+                }
+            };
+
+            *job_slot.lock().unwrap() = Slot {
+                state,
+                result: Some(result),
+                files,
+            };
+            let mut progress = progress.lock().unwrap();
+            progress.loading -= 1;
+            match state {
+                LoadState::Loaded => progress.loaded += 1,
+                _ => progress.failed += 1,
+            }
+        };
+        self.sender
+            .as_ref()
+            .expect("the loader is shut down")
+            .send(Box::new(job))
+            .expect("the asset loader threads are gone");
+
+        Pending {
+            name: name.to_string(),
+            slot,
+        }
+    }
+
+    /// Loads a model through `AssetManager::load_model`.
+    pub fn load_model(&self, file_name: &str) -> Pending<Handle<Model>> {
+        let (device, queue, layout, assets) = self.resources();
+        let owned_name = file_name.to_string();
+        self.spawn(file_name, move || {
+            pollster::block_on(assets.load_model(&owned_name, &device, &queue, &layout))
+        })
+    }
+
+    /// Loads a model with `resources::load_model_with_lods`, it isn't cached.
+    pub fn load_model_with_lods(&self, file_name: &str, ratios: &[f32]) -> Pending<Model> {
+        let (device, queue, layout, assets) = self.resources();
+        let owned_name = file_name.to_string();
+        let ratios = ratios.to_vec();
+        self.spawn(file_name, move || {
+            pollster::block_on(resources::load_model_with_lods(
+                &owned_name,
+                &device,
+                &queue,
+                &layout,
+                &ratios,
+                &assets,
+            ))
+        })
+    }
+
+    pub fn load_texture(&self, file_name: &str) -> Pending<Handle<super::Texture>> {
+        let (device, queue, _, assets) = self.resources();
+        let owned_name = file_name.to_string();
+        self.spawn(file_name, move || {
+            pollster::block_on(assets.load_texture(&owned_name, &device, &queue))
+        })
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn resources(
+        &self,
+    ) -> (
+        Arc<wgpu::Device>,
+        Arc<wgpu::Queue>,
+        Arc<wgpu::BindGroupLayout>,
+        Arc<AssetManager>,
+    ) {
+        (
+            self.device.clone(),
+            self.queue.clone(),
+            self.layout.clone(),
+            self.assets.clone(),
+        )
+    }
+}
+
+impl Drop for AssetLoader {
+    fn drop(&mut self) {
+        // Closing the channel stops the workers once the queue is empty
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Plain grey cube spanning -1..1, drawn while a model loads.
+pub fn placeholder_model(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    assets: &AssetManager,
+) -> Model {
+    let material = Material::new(
+        device,
+        layout,
+        "placeholder",
+        assets.white_texture(device, queue),
+        MaterialFactors {
+            base_color: [0.5, 0.5, 0.5, 1.0],
+            ..Default::default()
+        },
+    );
+    primitives::cube(2.0).to_model(device, "placeholder", material)
+}
@@ -0,0 +1,214 @@
+use super::{
+    bounds::BoundingSphere, camera::Camera, culling::Frustum, InstanceBuffer, InstanceId,
+    InstanceRaw, InstancesVec,
+};
+use cgmath::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+/// What the LOD thresholds are compared with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LodMetric {
+    /// Height of the instance bounding sphere as a fraction of the viewport
+    /// height, see `Camera::screen_size`. Thresholds go down.
+    ScreenSize,
+    /// Distance from `Camera::eye` to the instance bounding sphere center.
+    /// Thresholds go up.
+    Distance,
+}
+
+/// Picks a detail level from a list of thresholds. Level `i + 1` is used once
+/// an instance gets past `thresholds[i]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LodSelector {
+    pub metric: LodMetric,
+    pub thresholds: Vec<f32>,
+    /// Fraction of a threshold an instance has to go past it by before it
+    /// changes level, so instances sitting on a threshold don't pop every frame.
+    pub hysteresis: f32,
+}
+
+impl Default for LodSelector {
+    fn default() -> Self {
+        Self {
+            metric: LodMetric::ScreenSize,
+            thresholds: vec![0.25, 0.1, 0.04],
+            hysteresis: 0.1,
+        }
+    }
+}
+
+impl LodSelector {
+    pub fn measure(&self, camera: &Camera, sphere: &BoundingSphere) -> f32 {
+        match self.metric {
+            LodMetric::ScreenSize => camera.screen_size(sphere),
+            LodMetric::Distance => camera.eye.distance(sphere.center),
+        }
+    }
+
+    // Number of thresholds `value` is past, with the thresholds multiplied by `scale`
+    fn level(&self, value: f32, scale: f32) -> usize {
+        self.thresholds
+            .iter()
+            .take_while(|&&threshold| match self.metric {
+                LodMetric::ScreenSize => value < threshold * scale,
+                LodMetric::Distance => value > threshold * scale,
+            })
+            .count()
+    }
+
+    /// Level for a measured `value`, `current` is the level used on the last
+    /// frame if there is one.
+    pub fn select(&self, value: f32, current: Option<usize>) -> usize {
+        // Scales making a threshold harder and easier to cross towards less detail
+        let (coarser, finer) = match self.metric {
+            LodMetric::ScreenSize => (1.0 - self.hysteresis, 1.0 + self.hysteresis),
+            LodMetric::Distance => (1.0 + self.hysteresis, 1.0 - self.hysteresis),
+        };
+        let target = self.level(value, 1.0);
+        match current {
+            Some(current) if target > current => current.max(self.level(value, coarser)),
+            Some(current) if target < current => current.min(self.level(value, finer)),
+            _ => target,
+        }
+    }
+}
+
+/// Sorts the instances seen by one camera into an instance buffer per detail
+/// level. Remembers the level of every instance for the hysteresis, by id so
+/// removing instances doesn't mix them up.
+pub struct LodBatcher {
+    levels: HashMap<InstanceId, usize>,
+    batches: Vec<(InstanceBuffer, Vec<InstanceRaw>)>,
+}
+
+impl LodBatcher {
+    pub fn new() -> Self {
+        Self {
+            levels: HashMap::new(),
+            batches: Vec::new(),
+        }
+    }
+
+    /// Selects a level for every instance and uploads the batches. Instances
+    /// outside of `frustum` are skipped when there is one.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        selector: &LodSelector,
+        camera: &Camera,
+        frustum: Option<&Frustum>,
+        mesh_sphere: &BoundingSphere,
+        instances: &InstancesVec,
+        lod_count: usize,
+    ) {
+        self.batches.resize_with(lod_count, || {
+            (
+                InstanceBuffer::new(device, 0, "LOD Instance Buffer"),
+                Vec::new(),
+            )
+        });
+        self.batches.truncate(lod_count);
+        for (_, batch) in &mut self.batches {
+            batch.clear();
+        }
+
+        for (raw, id) in instances.raw().iter().zip(instances.ids()) {
+            let sphere = mesh_sphere.transform(&raw.model_matrix());
+            if frustum.is_some_and(|frustum| !frustum.intersects_sphere(&sphere)) {
+                // Off screen instances keep their level for when they come back
+                continue;
+            }
+            let current = self.levels.get(id).copied();
+            let selected = selector
+                .select(selector.measure(camera, &sphere), current)
+                .min(lod_count - 1);
+            self.levels.insert(*id, selected);
+            self.batches[selected].1.push(*raw);
+        }
+        if self.levels.len() > instances.len() {
+            // Forget the removed instances
+            let live = instances.ids().iter().collect::<HashSet<_>>();
+            self.levels.retain(|id, _| live.contains(id));
+        }
+
+        for (buffer, batch) in &mut self.batches {
+            buffer.reserve(device, batch.len());
+            buffer.write(queue, 0, batch);
+        }
+    }
+
+    /// Instance buffer and instance count of every level.
+    pub fn batches(&self) -> impl Iterator<Item = (&InstanceBuffer, u32)> {
+        self.batches
+            .iter()
+            .map(|(buffer, batch)| (buffer, batch.len() as u32))
+    }
+}
+
+impl Default for LodBatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distance_selector() -> LodSelector {
+        LodSelector {
+            metric: LodMetric::Distance,
+            thresholds: vec![10.0, 20.0, 40.0],
+            hysteresis: 0.1,
+        }
+    }
+
+    #[test]
+    fn levels_follow_the_screen_size_thresholds() {
+        let selector = LodSelector::default();
+        let levels =
+            [0.5, 0.26, 0.24, 0.11, 0.09, 0.05, 0.03, 0.0].map(|size| selector.select(size, None));
+        assert_eq!(levels, [0, 0, 1, 1, 2, 2, 3, 3]);
+    }
+
+    #[test]
+    fn levels_follow_the_distance_thresholds() {
+        let selector = distance_selector();
+        let levels = [0.0, 9.0, 11.0, 19.0, 21.0, 39.0, 41.0, 1000.0]
+            .map(|distance| selector.select(distance, None));
+        assert_eq!(levels, [0, 0, 1, 1, 2, 2, 3, 3]);
+    }
+
+    #[test]
+    fn levels_stay_inside_the_hysteresis_band() {
+        let selector = LodSelector::default();
+        // Between 0.225 and 0.275 around the 0.25 threshold
+        assert_eq!(selector.select(0.23, Some(0)), 0);
+        assert_eq!(selector.select(0.27, Some(1)), 1);
+        let selector = distance_selector();
+        // Between 9 and 11 around the 10 threshold
+        assert_eq!(selector.select(10.9, Some(0)), 0);
+        assert_eq!(selector.select(9.1, Some(1)), 1);
+    }
+
+    #[test]
+    fn levels_change_past_the_hysteresis_band() {
+        let selector = LodSelector::default();
+        assert_eq!(selector.select(0.22, Some(0)), 1);
+        assert_eq!(selector.select(0.28, Some(1)), 0);
+        let selector = distance_selector();
+        assert_eq!(selector.select(11.1, Some(0)), 1);
+        assert_eq!(selector.select(8.9, Some(1)), 0);
+    }
+
+    #[test]
+    fn levels_can_skip_several_thresholds() {
+        let selector = LodSelector::default();
+        assert_eq!(selector.select(0.01, Some(0)), 3);
+        assert_eq!(selector.select(0.5, Some(3)), 0);
+        // Past the 0.1 threshold but inside the band of the 0.04 one
+        assert_eq!(selector.select(0.039, Some(0)), 2);
+    }
+}
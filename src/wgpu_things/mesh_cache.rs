@@ -0,0 +1,565 @@
+//! Binary cache of processed OBJ meshes.
+//!
+//! Parsing OBJ text is slow for large models, so the meshes are written after
+//! going through `mesh_processing` to the cache directory under their asset
+//! name, e.g. `cache/cube.obj.meshcache` for `res/cube.obj`, and read back on
+//! the next load. The cache stores a checksum of every source file, the OBJ
+//! and its MTL files, and is ignored and written again once any of them
+//! changes. The `bake_mesh_cache` tool writes them ahead of time.
+//!
+//! All the numbers are little endian:
+//!
+//! ```text
+//! magic "WGMC", version: u32, payload length: u64, payload checksum: u64
+//! payload:
+//!   sources: u32 count, then name, present: u8, checksum: u64
+//!   MTL files: u32 count, then name
+//!   meshes: u32 count, then name, material: u32 (u32::MAX for none),
+//!           vertex count: u32, index count: u32, vertices, indices: u32
+//! ```
+//!
+//! Names are a u32 length followed by UTF-8 and vertices are the `ModelVertex`
+//! fields in order, all f32.
+
+use super::{mesh_processing, model::ModelVertex, normals::NormalMode, obj_loader, vfs};
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+};
+
+/// Bumped whenever the layout, or the processing the meshes went through,
+/// changes.
+pub const FORMAT_VERSION: u32 = 2;
+const MAGIC: &[u8; 4] = b"WGMC";
+const HEADER_LEN: usize = 24;
+const NO_MATERIAL: u32 = u32::MAX;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static CACHE_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Whether OBJ models are loaded from and saved to the cache, on by default.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Replaces the directory the caches are read from and written to.
+pub fn set_cache_dir(dir: impl Into<PathBuf>) {
+    *CACHE_DIR.write().unwrap() = Some(dir.into());
+}
+
+/// The directory set with `set_cache_dir`, otherwise `cache` next to the
+/// first search path of the global `vfs::Vfs` that exists, e.g. `cache` for
+/// `res`. It's kept out of the asset directories so the hot reloader doesn't
+/// see the caches being written. `None` if no search path exists.
+pub fn cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = CACHE_DIR.read().unwrap().clone() {
+        return Some(dir);
+    }
+    let vfs = vfs::current();
+    let root = vfs.search_paths().iter().find(|path| path.is_dir())?;
+    Some(root.with_file_name("cache"))
+}
+
+/// 64 bit FNV-1a hash.
+pub fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(std::io::Error),
+    NotACache,
+    Version {
+        found: u32,
+    },
+    /// The file ends before the data it announces.
+    Truncated,
+    /// The payload doesn't match its checksum.
+    Corrupt,
+    InvalidName,
+    IndexOutOfRange {
+        mesh: String,
+    },
+    /// A source file changed, appeared or disappeared since the cache was written.
+    Stale {
+        file: String,
+    },
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::NotACache => write!(f, "not a mesh cache"),
+            Self::Version { found } => {
+                write!(f, "version {found}, expected {FORMAT_VERSION}")
+            }
+            Self::Truncated => write!(f, "truncated"),
+            Self::Corrupt => write!(f, "checksum mismatch"),
+            Self::InvalidName => write!(f, "name isn't UTF-8"),
+            Self::IndexOutOfRange { mesh } => write!(f, "{mesh}: index out of range"),
+            Self::Stale { file } => write!(f, "{file} changed"),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl From<std::io::Error> for CacheError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Mesh as uploaded, with the index of its material in the MTL files.
+#[derive(Debug, Clone)]
+pub struct CachedMesh {
+    pub name: String,
+    pub material: Option<usize>,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+}
+
+/// Source file and its checksum, `None` if it didn't exist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub file_name: String,
+    pub checksum: Option<u64>,
+}
+
+impl Source {
+    fn read(file_name: &str) -> Self {
+        Self {
+            file_name: file_name.to_string(),
+            checksum: vfs::read(file_name).ok().map(|data| checksum(&data)),
+        }
+    }
+
+    fn is_fresh(&self) -> bool {
+        *self == Self::read(&self.file_name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MeshCache {
+    pub sources: Vec<Source>,
+    pub mtl_files: Vec<String>,
+    pub meshes: Vec<CachedMesh>,
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend(value.to_le_bytes());
+}
+
+fn put_name(out: &mut Vec<u8>, name: &str) {
+    put_u32(out, name.len() as u32);
+    out.extend(name.as_bytes());
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], CacheError> {
+        if len > self.data.len() {
+            return Err(CacheError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, CacheError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, CacheError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, CacheError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize, CacheError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn name(&mut self) -> Result<String, CacheError> {
+        let len = self.len()?;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| CacheError::InvalidName)
+    }
+
+    // `count` items of `size` bytes, checked against the data left before allocating
+    fn array(&mut self, count: usize, size: usize) -> Result<&'a [u8], CacheError> {
+        let len = count.checked_mul(size).ok_or(CacheError::Truncated)?;
+        self.bytes(len)
+    }
+}
+
+impl MeshCache {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        put_u32(&mut payload, self.sources.len() as u32);
+        for source in &self.sources {
+            put_name(&mut payload, &source.file_name);
+            payload.push(source.checksum.is_some() as u8);
+            payload.extend(source.checksum.unwrap_or(0).to_le_bytes());
+        }
+        put_u32(&mut payload, self.mtl_files.len() as u32);
+        for mtl_file in &self.mtl_files {
+            put_name(&mut payload, mtl_file);
+        }
+        put_u32(&mut payload, self.meshes.len() as u32);
+        for mesh in &self.meshes {
+            put_name(&mut payload, &mesh.name);
+            put_u32(
+                &mut payload,
+                mesh.material
+                    .map_or(NO_MATERIAL, |material| material as u32),
+            );
+            put_u32(&mut payload, mesh.vertices.len() as u32);
+            put_u32(&mut payload, mesh.indices.len() as u32);
+            for &value in bytemuck::cast_slice::<_, f32>(&mesh.vertices) {
+                payload.extend(value.to_le_bytes());
+            }
+            for &index in &mesh.indices {
+                put_u32(&mut payload, index);
+            }
+        }
+
+        let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+        out.extend(MAGIC);
+        put_u32(&mut out, FORMAT_VERSION);
+        out.extend((payload.len() as u64).to_le_bytes());
+        out.extend(checksum(&payload).to_le_bytes());
+        out.extend(payload);
+        out
+    }
+
+    /// Parses a cache, without checking whether its sources changed.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CacheError> {
+        let mut header = Reader { data };
+        if header.bytes(4).ok() != Some(MAGIC.as_slice()) {
+            return Err(CacheError::NotACache);
+        }
+        let version = header.u32()?;
+        if version != FORMAT_VERSION {
+            return Err(CacheError::Version { found: version });
+        }
+        let len = header.u64()?;
+        let expected = header.u64()?;
+        if header.data.len() as u64 != len {
+            return Err(CacheError::Truncated);
+        }
+        if checksum(header.data) != expected {
+            return Err(CacheError::Corrupt);
+        }
+
+        let mut reader = Reader { data: header.data };
+        let sources = (0..reader.len()?)
+            .map(|_| {
+                let file_name = reader.name()?;
+                let present = reader.u8()? != 0;
+                let checksum = reader.u64()?;
+                Ok(Source {
+                    file_name,
+                    checksum: present.then_some(checksum),
+                })
+            })
+            .collect::<Result<_, CacheError>>()?;
+        let mtl_files = (0..reader.len()?)
+            .map(|_| reader.name())
+            .collect::<Result<_, _>>()?;
+        let meshes = (0..reader.len()?)
+            .map(|_| {
+                let name = reader.name()?;
+                let material = match reader.u32()? {
+                    NO_MATERIAL => None,
+                    material => Some(material as usize),
+                };
+                let vertex_count = reader.len()?;
+                let index_count = reader.len()?;
+                let values = reader
+                    .array(vertex_count, std::mem::size_of::<ModelVertex>())?
+                    .chunks_exact(4)
+                    .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                    .collect::<Vec<_>>();
+                let vertices = bytemuck::pod_collect_to_vec(&values);
+                let indices = reader
+                    .array(index_count, 4)?
+                    .chunks_exact(4)
+                    .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                    .collect::<Vec<_>>();
+                if indices.iter().any(|&index| index as usize >= vertex_count) {
+                    return Err(CacheError::IndexOutOfRange { mesh: name });
+                }
+                Ok(CachedMesh {
+                    name,
+                    material,
+                    vertices,
+                    indices,
+                })
+            })
+            .collect::<Result<_, CacheError>>()?;
+
+        Ok(Self {
+            sources,
+            mtl_files,
+            meshes,
+        })
+    }
+
+    /// The first source file that changed since the cache was written.
+    pub fn check_sources(&self) -> Result<(), CacheError> {
+        match self.sources.iter().find(|source| !source.is_fresh()) {
+            Some(source) => Err(CacheError::Stale {
+                file: source.file_name.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Where the cache of `file_name` goes, `None` if the file isn't on disk.
+/// Named after the asset name so caches stay valid when the assets move,
+/// absolute names and ones going up with `..` get no cache.
+pub fn cache_path(file_name: &str) -> Option<PathBuf> {
+    vfs::resolve(file_name)?;
+    let path = Path::new(file_name);
+    let relative = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !relative {
+        return None;
+    }
+    let mut cache_path = cache_dir()?.join(path).into_os_string();
+    cache_path.push(".meshcache");
+    Some(cache_path.into())
+}
+
+/// Reads the cache of `file_name` if it's valid and up to date.
+pub fn read(file_name: &str) -> Result<MeshCache, CacheError> {
+    let path = cache_path(file_name)
+        .ok_or_else(|| CacheError::Io(std::io::Error::from(std::io::ErrorKind::NotFound)))?;
+    // Not read through the vfs so it isn't recorded as a file of the model,
+    // checking the sources records them instead
+    let cache = MeshCache::from_bytes(&std::fs::read(path)?)?;
+    cache.check_sources()?;
+    Ok(cache)
+}
+
+/// Writes the cache of `file_name` to the cache directory. Does nothing if
+/// the file isn't on disk.
+pub fn write(file_name: &str, cache: &MeshCache) -> std::io::Result<()> {
+    let Some(path) = cache_path(file_name) else {
+        return Ok(());
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Renamed into place so a reader never sees half a file. Each writer has
+    // its own temporary file, loader threads can write the same cache at once.
+    let thread = format!("{:?}", std::thread::current().id())
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+    let mut temp_name = path.clone().into_os_string();
+    temp_name.push(format!(".{}-{thread}.tmp", std::process::id()));
+    std::fs::write(&temp_name, cache.to_bytes())?;
+    std::fs::rename(&temp_name, &path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp_name);
+    })
+}
+
+/// Parses an OBJ file and processes its meshes.
+pub async fn build(file_name: &str) -> anyhow::Result<(MeshCache, Vec<tobj::Material>)> {
+    // Checksummed first so a file changing while it's parsed makes the cache stale
+    let obj_source = Source::read(file_name);
+    let (models, materials, mtl_files) = obj_loader::load_obj_with_libs(file_name).await?;
+    let meshes = models
+        .iter()
+        .map(|model| {
+            let (vertices, indices) = obj_loader::mesh_vertices(model, NormalMode::Smooth)?;
+            let (vertices, indices) = mesh_processing::process(&model.name, &vertices, indices);
+            Ok(CachedMesh {
+                name: model.name.clone(),
+                material: model.mesh.material_id,
+                vertices,
+                indices,
+            })
+        })
+        .collect::<Result<_, obj_loader::ObjError>>()?;
+    let sources = std::iter::once(obj_source)
+        .chain(mtl_files.iter().map(|mtl_file| Source::read(mtl_file)))
+        .collect();
+    let cache = MeshCache {
+        sources,
+        mtl_files,
+        meshes,
+    };
+    Ok((cache, materials))
+}
+
+/// Meshes and materials of an OBJ file, from its cache when it's up to date.
+/// Otherwise the OBJ file is parsed and the cache written for the next time.
+pub async fn load_obj(file_name: &str) -> anyhow::Result<(Vec<CachedMesh>, Vec<tobj::Material>)> {
+    if is_enabled() {
+        match read(file_name) {
+            Ok(cache) => {
+                log::debug!("Loading {file_name} from its cache");
+                let materials = obj_loader::load_mtls(file_name, &cache.mtl_files).await;
+                return Ok((cache.meshes, materials));
+            }
+            Err(CacheError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => log::info!("Ignoring the cache of {file_name}: {err}"),
+        }
+    }
+
+    let (cache, materials) = build(file_name).await?;
+    if is_enabled() {
+        if let Err(err) = write(file_name, &cache) {
+            log::warn!("Can't write the cache of {file_name}: {err}");
+        }
+    }
+    Ok((cache.meshes, materials))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wgpu_things::primitives;
+
+    fn sample() -> MeshCache {
+        let cube = primitives::cube(1.0);
+        let sphere = primitives::uv_sphere(1.0, 8, 4);
+        MeshCache {
+            sources: vec![
+                Source {
+                    file_name: "cube.obj".to_string(),
+                    checksum: Some(0x0123456789abcdef),
+                },
+                Source {
+                    file_name: "cube.mtl".to_string(),
+                    checksum: None,
+                },
+            ],
+            mtl_files: vec!["cube.mtl".to_string()],
+            meshes: vec![
+                CachedMesh {
+                    name: "cube".to_string(),
+                    material: Some(0),
+                    vertices: cube.vertices,
+                    indices: cube.indices,
+                },
+                CachedMesh {
+                    name: "sphere".to_string(),
+                    material: None,
+                    vertices: sphere.vertices,
+                    indices: sphere.indices,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn caches_read_back() {
+        let cache = sample();
+        let read = MeshCache::from_bytes(&cache.to_bytes()).unwrap();
+        assert_eq!(read.sources, cache.sources);
+        assert_eq!(read.mtl_files, cache.mtl_files);
+        assert_eq!(read.meshes.len(), cache.meshes.len());
+        for (read, mesh) in read.meshes.iter().zip(&cache.meshes) {
+            assert_eq!(read.name, mesh.name);
+            assert_eq!(read.material, mesh.material);
+            assert_eq!(
+                bytemuck::cast_slice::<_, u32>(&read.vertices),
+                bytemuck::cast_slice::<_, u32>(&mesh.vertices)
+            );
+            assert_eq!(read.indices, mesh.indices);
+        }
+    }
+
+    #[test]
+    fn vertices_are_little_endian() {
+        let mut cache = sample();
+        cache.sources.clear();
+        cache.mtl_files.clear();
+        cache.meshes.truncate(1);
+        cache.meshes[0].vertices[0].position = [1.5, 0.0, 0.0];
+        let bytes = cache.to_bytes();
+        // Empty source and MTL lists, mesh count, name, material, vertex and
+        // index counts
+        let offset = HEADER_LEN + 4 + 4 + 4 + (4 + "cube".len()) + 4 + 4 + 4;
+        assert_eq!(bytes[offset..offset + 4], 1.5f32.to_le_bytes());
+    }
+
+    #[test]
+    fn truncated_caches_are_rejected() {
+        let bytes = sample().to_bytes();
+        for len in 0..bytes.len() {
+            let err = MeshCache::from_bytes(&bytes[..len]).unwrap_err();
+            if len >= 4 {
+                assert!(matches!(err, CacheError::Truncated), "{len}: {err}");
+            } else {
+                assert!(matches!(err, CacheError::NotACache), "{len}: {err}");
+            }
+        }
+    }
+
+    #[test]
+    fn checksum_mismatches_are_rejected() {
+        let bytes = sample().to_bytes();
+        for offset in [16, HEADER_LEN, bytes.len() / 2, bytes.len() - 1] {
+            let mut corrupt = bytes.clone();
+            corrupt[offset] ^= 0x40;
+            let err = MeshCache::from_bytes(&corrupt).unwrap_err();
+            assert!(matches!(err, CacheError::Corrupt), "{offset}: {err}");
+        }
+    }
+
+    #[test]
+    fn caches_are_named_after_the_asset() {
+        let root = std::env::temp_dir()
+            .join(format!("mesh_cache_test_{}", std::process::id()))
+            .join("res");
+        std::fs::create_dir_all(root.join("models")).unwrap();
+        std::fs::write(root.join("models").join("cube.obj"), "o cube").unwrap();
+        let mut test_vfs = vfs::Vfs::new();
+        test_vfs.add_search_path(&root);
+        vfs::configure(test_vfs);
+
+        let expected = root
+            .with_file_name("cache")
+            .join("models")
+            .join("cube.obj.meshcache");
+        let absolute = root.join("models").join("cube.obj");
+        let paths = [
+            cache_path("models/cube.obj"),
+            cache_path(absolute.to_str().unwrap()),
+            cache_path("../res/models/cube.obj"),
+            cache_path("models/missing.obj"),
+        ];
+        std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+        assert_eq!(paths, [Some(expected), None, None, None]);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut bytes = sample().to_bytes();
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let err = MeshCache::from_bytes(&bytes).unwrap_err();
+        assert!(matches!(err, CacheError::Version { found } if found == FORMAT_VERSION + 1));
+    }
+}
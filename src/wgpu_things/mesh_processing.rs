@@ -0,0 +1,428 @@
+//! Preparing loaded geometry for the GPU.
+//!
+//! Loaders hand their vertices to `process`, which merges duplicates and
+//! reorders the triangles so that the post transform cache gets more hits,
+//! then the vertices so they are fetched in order.
+
+use super::{
+    bounds::{Aabb, BoundingSphere},
+    model::ModelVertex,
+};
+use std::collections::{HashMap, VecDeque};
+
+/// Size of the post transform cache the indices are ordered for.
+pub const CACHE_SIZE: usize = 32;
+
+/// Sizes and efficiency of a mesh, as uploaded.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MeshStats {
+    pub vertices: usize,
+    pub triangles: usize,
+    /// Average vertices transformed per triangle with a FIFO cache of
+    /// `CACHE_SIZE`, between 0.5 for a large regular grid and 3.
+    pub acmr: f32,
+    /// Vertices transformed per vertex, 1 is ideal.
+    pub atvr: f32,
+    pub vertex_bytes: u64,
+    pub index_bytes: u64,
+}
+
+impl MeshStats {
+    pub fn new(vertices: &[ModelVertex], indices: &[u32]) -> Self {
+        let misses = cache_misses(indices, CACHE_SIZE);
+        let ratio = |count: usize| match count {
+            0 => 0.0,
+            count => misses as f32 / count as f32,
+        };
+        let index_size = match index_format(vertices.len()) {
+            wgpu::IndexFormat::Uint16 => 2,
+            wgpu::IndexFormat::Uint32 => 4,
+        };
+        Self {
+            vertices: vertices.len(),
+            triangles: indices.len() / 3,
+            acmr: ratio(indices.len() / 3),
+            atvr: ratio(vertices.len()),
+            vertex_bytes: std::mem::size_of_val(vertices) as u64,
+            index_bytes: indices.len() as u64 * index_size,
+        }
+    }
+}
+
+/// Smallest index format that can address `vertex_count` vertices. 0xFFFF is
+/// left out since it restarts strips.
+pub fn index_format(vertex_count: usize) -> wgpu::IndexFormat {
+    if vertex_count < u16::MAX as usize {
+        wgpu::IndexFormat::Uint16
+    } else {
+        wgpu::IndexFormat::Uint32
+    }
+}
+
+/// Index buffer contents in `format`.
+pub fn index_bytes(indices: &[u32], format: wgpu::IndexFormat) -> Vec<u8> {
+    match format {
+        wgpu::IndexFormat::Uint16 => {
+            let indices = indices.iter().map(|&i| i as u16).collect::<Vec<_>>();
+            bytemuck::cast_slice(&indices).to_vec()
+        }
+        wgpu::IndexFormat::Uint32 => bytemuck::cast_slice(indices).to_vec(),
+    }
+}
+
+pub fn bounds(vertices: &[ModelVertex]) -> (Aabb, BoundingSphere) {
+    let positions = vertices.iter().map(|v| cgmath::Point3::from(v.position));
+    (
+        Aabb::from_points(positions.clone()),
+        BoundingSphere::from_points(positions),
+    )
+}
+
+/// Merges the vertices with exactly the same attributes and drops the unused
+/// ones. Returns the new vertices, `indices` are remapped in place.
+pub fn weld_vertices(vertices: &[ModelVertex], indices: &mut [u32]) -> Vec<ModelVertex> {
+    let mut welded = Vec::new();
+    let mut lookup = HashMap::new();
+    let mut remap = vec![None; vertices.len()];
+    for index in indices.iter_mut() {
+        let old = *index as usize;
+        *index = *remap[old].get_or_insert_with(|| {
+            let vertex = vertices[old];
+            // Adding 0 turns -0 into 0 so both are merged
+            let key: [u32; 12] =
+                bytemuck::cast(bytemuck::cast::<_, [f32; 12]>(vertex).map(|f| f + 0.0));
+            *lookup.entry(key).or_insert_with(|| {
+                welded.push(vertex);
+                welded.len() as u32 - 1
+            })
+        });
+    }
+    welded
+}
+
+// Score of a vertex from Tom Forsyth's "Linear-Speed Vertex Cache
+// Optimisation": recently used vertices and those with few triangles left
+// are preferred
+fn vertex_score(cache_position: Option<usize>, remaining: usize) -> f32 {
+    if remaining == 0 {
+        return -1.0;
+    }
+    let cache = match cache_position {
+        None => 0.0,
+        // The last triangle's vertices, using them again doesn't help as much
+        Some(position) if position < 3 => 0.75,
+        Some(position) => (1.0 - (position - 3) as f32 / (CACHE_SIZE - 3) as f32).powf(1.5),
+    };
+    cache + 2.0 * (remaining as f32).powf(-0.5)
+}
+
+/// Reorders the triangles so consecutive ones share vertices. The triangles
+/// themselves and their winding are unchanged.
+pub fn optimize_vertex_cache(indices: &mut [u32], vertex_count: usize) {
+    let triangle_count = indices.len() / 3;
+    if triangle_count == 0 {
+        return;
+    }
+
+    // Triangles of each vertex, the first `remaining[v]` of them not emitted yet
+    let mut offsets = vec![0; vertex_count + 1];
+    for &index in indices.iter() {
+        offsets[index as usize + 1] += 1;
+    }
+    for vertex in 0..vertex_count {
+        offsets[vertex + 1] += offsets[vertex];
+    }
+    let mut adjacency = vec![0; indices.len()];
+    let mut fill = offsets.clone();
+    for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+        for &vertex in corners {
+            adjacency[fill[vertex as usize]] = triangle;
+            fill[vertex as usize] += 1;
+        }
+    }
+    let mut remaining = (0..vertex_count)
+        .map(|vertex| offsets[vertex + 1] - offsets[vertex])
+        .collect::<Vec<_>>();
+
+    let mut scores = remaining
+        .iter()
+        .map(|&remaining| vertex_score(None, remaining))
+        .collect::<Vec<_>>();
+    let triangle_score = |corners: &[u32], scores: &[f32]| -> f32 {
+        corners.iter().map(|&v| scores[v as usize]).sum()
+    };
+    let mut emitted = vec![false; triangle_count];
+    let mut best = (0..triangle_count).max_by(|&a, &b| {
+        let a = triangle_score(&indices[a * 3..a * 3 + 3], &scores);
+        let b = triangle_score(&indices[b * 3..b * 3 + 3], &scores);
+        a.total_cmp(&b)
+    });
+    // Where to look for a triangle when none uses a vertex in the cache
+    let mut next_unemitted = 0;
+
+    let mut cache: Vec<u32> = Vec::with_capacity(CACHE_SIZE + 3);
+    let mut output = Vec::with_capacity(indices.len());
+    while let Some(triangle) = best {
+        emitted[triangle] = true;
+        let corners = [0, 1, 2].map(|corner| indices[triangle * 3 + corner]);
+        output.extend(corners);
+
+        for vertex in corners.map(|v| v as usize) {
+            let start = offsets[vertex];
+            let active = &mut adjacency[start..start + remaining[vertex]];
+            if let Some(position) = active.iter().position(|&t| t == triangle) {
+                let last = active.len() - 1;
+                active.swap(position, last);
+                remaining[vertex] -= 1;
+            }
+        }
+
+        let mut new_cache = corners.to_vec();
+        new_cache.extend(cache.iter().filter(|v| !corners.contains(v)));
+        let evicted = new_cache.split_off(new_cache.len().min(CACHE_SIZE));
+        cache = new_cache;
+        for (position, &vertex) in cache.iter().enumerate() {
+            scores[vertex as usize] = vertex_score(Some(position), remaining[vertex as usize]);
+        }
+        for &vertex in &evicted {
+            scores[vertex as usize] = vertex_score(None, remaining[vertex as usize]);
+        }
+
+        // Only the triangles of the vertices whose score changed can be next
+        best = None;
+        let mut best_score = f32::MIN;
+        for &vertex in cache.iter().chain(&evicted) {
+            let start = offsets[vertex as usize];
+            for &candidate in &adjacency[start..start + remaining[vertex as usize]] {
+                let score = triangle_score(&indices[candidate * 3..candidate * 3 + 3], &scores);
+                if score > best_score {
+                    best = Some(candidate);
+                    best_score = score;
+                }
+            }
+        }
+        if best.is_none() {
+            while next_unemitted < triangle_count && emitted[next_unemitted] {
+                next_unemitted += 1;
+            }
+            best = (next_unemitted < triangle_count).then_some(next_unemitted);
+        }
+    }
+
+    indices[..output.len()].copy_from_slice(&output);
+}
+
+/// Reorders the vertices by first use so they are read in order, and drops
+/// the unused ones. Returns the new vertices, `indices` are remapped in place.
+pub fn optimize_vertex_fetch(vertices: &[ModelVertex], indices: &mut [u32]) -> Vec<ModelVertex> {
+    let mut remap = vec![None; vertices.len()];
+    let mut ordered = Vec::with_capacity(vertices.len());
+    for index in indices.iter_mut() {
+        let old = *index as usize;
+        *index = *remap[old].get_or_insert_with(|| {
+            ordered.push(vertices[old]);
+            ordered.len() as u32 - 1
+        });
+    }
+    ordered
+}
+
+/// Vertices transformed drawing `indices` with a FIFO cache of `cache_size`.
+pub fn cache_misses(indices: &[u32], cache_size: usize) -> usize {
+    let mut cache = VecDeque::with_capacity(cache_size);
+    let mut misses = 0;
+    for &index in indices {
+        if !cache.contains(&index) {
+            misses += 1;
+            if cache.len() == cache_size {
+                cache.pop_front();
+            }
+            cache.push_back(index);
+        }
+    }
+    misses
+}
+
+/// Welds the vertices, then optimizes the index and vertex order.
+pub fn process(
+    name: &str,
+    vertices: &[ModelVertex],
+    mut indices: Vec<u32>,
+) -> (Vec<ModelVertex>, Vec<u32>) {
+    let before = MeshStats::new(vertices, &indices);
+    let welded = weld_vertices(vertices, &mut indices);
+    optimize_vertex_cache(&mut indices, welded.len());
+    let vertices = optimize_vertex_fetch(&welded, &mut indices);
+    let after = MeshStats::new(&vertices, &indices);
+    log::debug!(
+        "{name}: {} -> {} vertices, ACMR {:.3} -> {:.3}",
+        before.vertices,
+        after.vertices,
+        before.acmr,
+        after.acmr
+    );
+    (vertices, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wgpu_things::primitives;
+
+    fn vertex(position: [f32; 3]) -> ModelVertex {
+        ModelVertex {
+            position,
+            tex_coords: [0.0; 2],
+            normal: [0.0, 0.0, 1.0],
+            tangent: [1.0, 0.0, 0.0, 1.0],
+        }
+    }
+
+    // The triangles as corner positions, each rotated to start with its
+    // smallest corner so the winding is kept, in a stable order. -0 counts as 0.
+    fn triangle_set(vertices: &[ModelVertex], indices: &[u32]) -> Vec<[[u32; 3]; 3]> {
+        let position = |index: u32| {
+            vertices[index as usize]
+                .position
+                .map(|v| (v + 0.0).to_bits())
+        };
+        let mut triangles = indices
+            .chunks_exact(3)
+            .map(|t| {
+                let corners = [t[0], t[1], t[2]].map(position);
+                let first = (0..3).min_by_key(|&i| corners[i]).unwrap();
+                [0, 1, 2].map(|i| corners[(first + i) % 3])
+            })
+            .collect::<Vec<_>>();
+        triangles.sort();
+        triangles
+    }
+
+    // Deterministic shuffle of the triangles
+    fn shuffle_triangles(indices: &mut [u32]) {
+        let mut state = 12345_u64;
+        let count = indices.len() / 3;
+        for i in (1..count).rev() {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let j = (state >> 33) as usize % (i + 1);
+            for corner in 0..3 {
+                indices.swap(i * 3 + corner, j * 3 + corner);
+            }
+        }
+    }
+
+    #[test]
+    fn welding_merges_identical_vertices() {
+        // A quad with the diagonal's vertices duplicated, -0 for one of them,
+        // and an unused vertex at the end
+        let vertices = [
+            vertex([0.0, 0.0, 0.0]),
+            vertex([1.0, 0.0, 0.0]),
+            vertex([1.0, 1.0, 0.0]),
+            vertex([-0.0, 0.0, 0.0]),
+            vertex([1.0, 1.0, 0.0]),
+            vertex([0.0, 1.0, 0.0]),
+            vertex([5.0, 5.0, 5.0]),
+        ];
+        let mut indices = vec![0, 1, 2, 3, 4, 5];
+        let welded = weld_vertices(&vertices, &mut indices);
+        assert_eq!(welded.len(), 4);
+        assert_eq!(indices, [0, 1, 2, 0, 2, 3]);
+        assert_eq!(
+            triangle_set(&welded, &indices),
+            triangle_set(&vertices, &[0, 1, 2, 3, 4, 5])
+        );
+    }
+
+    #[test]
+    fn welding_keeps_vertices_differing_in_any_attribute() {
+        let mut seam = vertex([0.0, 0.0, 0.0]);
+        seam.tex_coords = [1.0, 0.0];
+        let vertices = [vertex([0.0, 0.0, 0.0]), seam];
+        let mut indices = vec![0, 1, 0];
+        assert_eq!(weld_vertices(&vertices, &mut indices).len(), 2);
+        assert_eq!(indices, [0, 1, 0]);
+    }
+
+    #[test]
+    fn cache_optimization_keeps_the_triangles_and_lowers_acmr() {
+        let meshes = [
+            primitives::plane(1.0, 1.0, 20, 20),
+            primitives::uv_sphere(1.0, 24, 12),
+        ];
+        for mesh in meshes {
+            let mut shuffled = mesh.indices.clone();
+            shuffle_triangles(&mut shuffled);
+            for before in [mesh.indices.clone(), shuffled] {
+                let mut after = before.clone();
+                optimize_vertex_cache(&mut after, mesh.vertices.len());
+                assert_eq!(
+                    triangle_set(&mesh.vertices, &after),
+                    triangle_set(&mesh.vertices, &before)
+                );
+                let acmr_before = MeshStats::new(&mesh.vertices, &before).acmr;
+                let acmr_after = MeshStats::new(&mesh.vertices, &after).acmr;
+                assert!(acmr_after <= acmr_before, "{acmr_after} > {acmr_before}");
+            }
+        }
+    }
+
+    #[test]
+    fn fetch_optimization_orders_vertices_by_first_use() {
+        let mesh = primitives::cube(1.0);
+        let mut indices = mesh.indices.clone();
+        indices.reverse();
+        let before = indices.clone();
+        let vertices = optimize_vertex_fetch(&mesh.vertices, &mut indices);
+        let mut next = 0;
+        for &index in &indices {
+            assert!(index <= next);
+            if index == next {
+                next += 1;
+            }
+        }
+        assert_eq!(next as usize, vertices.len());
+        assert_eq!(
+            triangle_set(&vertices, &indices),
+            triangle_set(&mesh.vertices, &before)
+        );
+    }
+
+    #[test]
+    fn index_format_leaves_out_the_restart_index() {
+        assert_eq!(index_format(0), wgpu::IndexFormat::Uint16);
+        assert_eq!(
+            index_format(u16::MAX as usize - 1),
+            wgpu::IndexFormat::Uint16
+        );
+        assert_eq!(index_format(u16::MAX as usize), wgpu::IndexFormat::Uint32);
+    }
+
+    #[test]
+    fn index_bytes_read_back() {
+        let indices = [0, 1, 2, 65534, 7, 3];
+        let u16s = index_bytes(&indices, wgpu::IndexFormat::Uint16);
+        assert_eq!(u16s.len(), indices.len() * 2);
+        let read = bytemuck::pod_collect_to_vec::<u8, u16>(&u16s)
+            .into_iter()
+            .map(u32::from)
+            .collect::<Vec<_>>();
+        assert_eq!(read, indices);
+
+        let indices = [0, 70000, u32::MAX - 1];
+        let u32s = index_bytes(&indices, wgpu::IndexFormat::Uint32);
+        assert_eq!(bytemuck::pod_collect_to_vec::<u8, u32>(&u32s), indices);
+    }
+
+    #[test]
+    fn cache_misses_follow_a_fifo_cache() {
+        assert_eq!(cache_misses(&[], 3), 0);
+        assert_eq!(cache_misses(&[0, 1, 2, 2, 1, 0], 3), 3);
+        // 3 pushes 0 out, which then misses again and pushes 1 out
+        assert_eq!(cache_misses(&[0, 1, 2, 3, 0, 1], 3), 6);
+        // Hits don't refresh a vertex's place
+        assert_eq!(cache_misses(&[0, 1, 2, 0, 3, 0], 3), 5);
+    }
+}
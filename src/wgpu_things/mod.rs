@@ -1,8 +1,30 @@
+pub mod assets;
+pub mod bounds;
+pub mod bvh;
 pub mod camera;
+pub mod camera_path;
+pub mod culling;
+pub mod gltf_loader;
+pub mod gpu_culling;
+pub mod hot_reload;
 pub mod instance_draw;
+pub mod instance_generators;
+pub mod instance_io;
+pub mod loader;
+pub mod lod;
+pub mod mesh_cache;
+pub mod mesh_processing;
 pub mod model;
+pub mod normals;
+pub mod obj_loader;
+pub mod picking;
+pub mod primitives;
 pub mod renderer;
 pub mod resources;
+pub mod scene_graph;
+pub mod simplify;
 pub mod texture;
+pub mod vfs;
+pub mod viewport;
 pub use instance_draw::*;
 pub use texture::*;
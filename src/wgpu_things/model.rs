@@ -1,4 +1,10 @@
+use super::{
+    assets::Handle,
+    bounds::{Aabb, BoundingSphere},
+    mesh_processing::{self, MeshStats},
+};
 use std::ops::Range;
+use wgpu::util::DeviceExt;
 
 // model.rs
 pub trait Vertex {
@@ -11,6 +17,9 @@ pub struct ModelVertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
     pub normal: [f32; 3],
+    /// `w` is the sign making `cross(normal, tangent) * w` point towards
+    /// increasing `v`.
+    pub tangent: [f32; 4],
 }
 
 impl Vertex for ModelVertex {
@@ -35,27 +44,284 @@ impl Vertex for ModelVertex {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float32x3,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
             ],
         }
     }
 }
 
+// Puts the handles of `new` that differ into `current`, all of them when the
+// lengths differ. Returns how many were replaced.
+fn replace_changed<T>(current: &mut Vec<Handle<T>>, new: Vec<Handle<T>>) -> usize {
+    if current.len() != new.len() {
+        *current = new;
+        return current.len();
+    }
+    let mut replaced = 0;
+    for (current, new) in current.iter_mut().zip(new) {
+        if !Handle::ptr_eq(current, &new) {
+            *current = new;
+            replaced += 1;
+        }
+    }
+    replaced
+}
+
+/// Meshes and materials of a model, shared with the other models loaded from
+/// the same files through the `AssetManager`.
+#[derive(Clone)]
 pub struct Model {
-    pub meshes: Vec<Mesh>,
-    pub materials: Vec<Material>,
+    pub meshes: Vec<Handle<Mesh>>,
+    pub materials: Vec<Handle<Material>>,
+    /// Lower detail versions of `meshes`, from the most to the least detailed.
+    /// Each level replaces all the meshes of the model.
+    pub lods: Vec<Vec<Handle<Mesh>>>,
+}
+
+impl Model {
+    /// Number of detail levels, `meshes` being level 0.
+    pub fn lod_count(&self) -> usize {
+        1 + self.lods.len()
+    }
+
+    pub fn lod_meshes(&self, level: usize) -> &[Handle<Mesh>] {
+        match level {
+            0 => &self.meshes,
+            _ => &self.lods[level - 1],
+        }
+    }
+
+    /// Takes the meshes and materials of `other` this model doesn't share
+    /// already, e.g. after a reload where only some of the files changed, so
+    /// the others stay as they are. Returns how many were replaced.
+    pub fn update_from(&mut self, other: Model) -> usize {
+        let mut replaced = replace_changed(&mut self.meshes, other.meshes)
+            + replace_changed(&mut self.materials, other.materials);
+        self.lods.resize_with(other.lods.len(), Vec::new);
+        for (level, other_level) in self.lods.iter_mut().zip(other.lods) {
+            replaced += replace_changed(level, other_level);
+        }
+        replaced
+    }
+
+    /// Sphere around every mesh of the model.
+    pub fn bounding_sphere(&self) -> BoundingSphere {
+        match self.meshes.as_slice() {
+            [mesh] => mesh.bounding_sphere,
+            meshes => {
+                BoundingSphere::from_points(meshes.iter().flat_map(|mesh| mesh.aabb.corners()))
+            }
+        }
+    }
+}
+
+/// Constant material parameters, multiplied with the diffuse texture by the
+/// shader. The defaults are those of a plain white, non metallic and fully
+/// rough surface.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MaterialFactors {
+    /// Linear RGBA, the MTL `Kd` colour with `d` as alpha.
+    pub base_color: [f32; 4],
+    /// MTL `Ka`.
+    pub ambient: [f32; 3],
+    /// MTL `Ks`.
+    pub specular: [f32; 3],
+    /// MTL `Ns`, the specular exponent.
+    pub shininess: f32,
+    /// MTL `Ke`, added to the shaded colour.
+    pub emissive: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+}
+
+impl Default for MaterialFactors {
+    fn default() -> Self {
+        Self {
+            base_color: [1.0; 4],
+            ambient: [1.0; 3],
+            specular: [0.0; 3],
+            shininess: 0.0,
+            emissive: [0.0; 3],
+            metallic: 0.0,
+            roughness: 1.0,
+        }
+    }
+}
+
+impl MaterialFactors {
+    /// Colours of an MTL material. `Ke` isn't a standard MTL statement so tobj
+    /// leaves it with the unknown parameters.
+    pub fn from_mtl(material: &tobj::Material) -> Self {
+        let emissive = material
+            .unknown_param
+            .get("Ke")
+            .and_then(|ke| {
+                let values = ke
+                    .split_whitespace()
+                    .map(str::parse)
+                    .collect::<Result<Vec<f32>, _>>()
+                    .ok()?;
+                values.try_into().ok()
+            })
+            .unwrap_or([0.0; 3]);
+        let [r, g, b] = material.diffuse;
+        Self {
+            base_color: [r, g, b, material.dissolve],
+            ambient: material.ambient,
+            specular: material.specular,
+            shininess: material.shininess,
+            emissive,
+            ..Default::default()
+        }
+    }
+}
+
+// Layout of `MaterialUniform` in test.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialUniform {
+    base_color: [f32; 4],
+    ambient: [f32; 4],
+    // Shininess in w
+    specular: [f32; 4],
+    emissive: [f32; 4],
+    // Metallic and roughness in x and y
+    pbr: [f32; 4],
+}
+
+impl From<&MaterialFactors> for MaterialUniform {
+    fn from(factors: &MaterialFactors) -> Self {
+        let [ar, ag, ab] = factors.ambient;
+        let [sr, sg, sb] = factors.specular;
+        let [er, eg, eb] = factors.emissive;
+        Self {
+            base_color: factors.base_color,
+            ambient: [ar, ag, ab, 0.0],
+            specular: [sr, sg, sb, factors.shininess],
+            emissive: [er, eg, eb, 0.0],
+            pbr: [factors.metallic, factors.roughness, 0.0, 0.0],
+        }
+    }
 }
+
 pub struct Material {
     pub name: String,
-    pub diffuse_texture: super::Texture,
+    pub diffuse_texture: Handle<super::Texture>,
+    pub factors: MaterialFactors,
+    pub factors_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
 }
 
+impl Material {
+    /// `diffuse_texture` can be `AssetManager::white_texture` when the material
+    /// has no texture.
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        name: &str,
+        diffuse_texture: Handle<super::Texture>,
+        factors: MaterialFactors,
+    ) -> Self {
+        let factors_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Material Buffer", name)),
+            contents: bytemuck::bytes_of(&MaterialUniform::from(&factors)),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: factors_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some(name),
+        });
+        Self {
+            name: name.to_string(),
+            diffuse_texture,
+            factors,
+            factors_buffer,
+            bind_group,
+        }
+    }
+
+    pub fn set_factors(&mut self, queue: &wgpu::Queue, factors: MaterialFactors) {
+        self.factors = factors;
+        queue.write_buffer(
+            &self.factors_buffer,
+            0,
+            bytemuck::bytes_of(&MaterialUniform::from(&factors)),
+        );
+    }
+}
+
 pub struct Mesh {
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
+    pub index_format: wgpu::IndexFormat,
     pub num_elements: u32,
     pub material: usize,
+    // CPU side copy of the geometry, used for picking
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    // Bounds in model space
+    pub aabb: Aabb,
+    pub bounding_sphere: BoundingSphere,
+    pub stats: MeshStats,
+}
+
+impl Mesh {
+    pub fn new(
+        device: &wgpu::Device,
+        name: &str,
+        vertices: Vec<ModelVertex>,
+        indices: Vec<u32>,
+        material: usize,
+    ) -> Self {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Vertex Buffer", name)),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        // 16 bit indices when there are few enough vertices
+        let index_format = mesh_processing::index_format(vertices.len());
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Index Buffer", name)),
+            contents: &mesh_processing::index_bytes(&indices, index_format),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        let (aabb, bounding_sphere) = mesh_processing::bounds(&vertices);
+        let stats = MeshStats::new(&vertices, &indices);
+
+        Self {
+            name: name.to_string(),
+            vertex_buffer,
+            index_buffer,
+            index_format,
+            num_elements: indices.len() as u32,
+            material,
+            vertices,
+            indices,
+            aabb,
+            bounding_sphere,
+            stats,
+        }
+    }
 }
 
 pub trait DrawModel<'a> {
@@ -72,6 +338,15 @@ pub trait DrawModel<'a> {
         instances: Range<u32>,
         camera_bind_group: &'a wgpu::BindGroup,
     );
+    /// Draws with the `DrawIndexedIndirectArgs` at `indirect_offset` in `indirect_buffer`.
+    fn draw_mesh_indirect(
+        &mut self,
+        mesh: &'a Mesh,
+        material: &'a Material,
+        indirect_buffer: &'a wgpu::Buffer,
+        indirect_offset: wgpu::BufferAddress,
+        camera_bind_group: &'a wgpu::BindGroup,
+    );
 }
 
 impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
@@ -95,9 +370,44 @@ where
         camera_bind_group: &'b wgpu::BindGroup,
     ) {
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, camera_bind_group, &[]);
         self.draw_indexed(0..mesh.num_elements, 0, instances);
     }
+
+    fn draw_mesh_indirect(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        indirect_buffer: &'b wgpu::Buffer,
+        indirect_offset: wgpu::BufferAddress,
+        camera_bind_group: &'b wgpu::BindGroup,
+    ) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
+        self.set_bind_group(0, &material.bind_group, &[]);
+        self.set_bind_group(1, camera_bind_group, &[]);
+        self.draw_indexed_indirect(indirect_buffer, indirect_offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_changed_handles_are_replaced() {
+        let shared = Handle::new(1);
+        let old = Handle::new(2);
+        let mut current = vec![shared.clone(), old.clone()];
+        let new = Handle::new(3);
+        assert_eq!(replace_changed(&mut current, vec![shared.clone(), new]), 1);
+        assert!(Handle::ptr_eq(&current[0], &shared));
+        assert_eq!(*current[1], 3);
+
+        // A different layout replaces everything
+        assert_eq!(replace_changed(&mut current, vec![old]), 1);
+        assert_eq!(current.len(), 1);
+    }
 }
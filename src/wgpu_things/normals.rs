@@ -0,0 +1,260 @@
+//! Normals and tangents for meshes that come without them.
+
+use super::model::ModelVertex;
+use cgmath::prelude::*;
+use std::collections::HashMap;
+
+// Used for vertices only touched by degenerate triangles
+const FALLBACK_NORMAL: [f32; 3] = [0.0, 1.0, 0.0];
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum NormalMode {
+    /// Every triangle gets its own three vertices with the face normal, for a
+    /// faceted look.
+    Flat,
+    /// Vertices at the same position share the average of the normals of the
+    /// triangles around them, weighted by the angle of each triangle's corner.
+    /// Unlike area weighting this doesn't depend on how the faces are split.
+    #[default]
+    Smooth,
+}
+
+/// Tangent along `dpdu` made perpendicular to `normal`, with the handedness
+/// given by `dpdv`. See `ModelVertex::tangent`.
+pub fn tangent(
+    normal: cgmath::Vector3<f32>,
+    dpdu: cgmath::Vector3<f32>,
+    dpdv: cgmath::Vector3<f32>,
+) -> [f32; 4] {
+    let t = dpdu - normal * normal.dot(dpdu);
+    let t = if t.magnitude2() > 0.0 {
+        t.normalize()
+    } else {
+        // Any direction perpendicular to the normal
+        let axis = if normal.x.abs() < 0.9 {
+            cgmath::Vector3::unit_x()
+        } else {
+            cgmath::Vector3::unit_y()
+        };
+        normal.cross(axis).normalize()
+    };
+    let w = if normal.cross(t).dot(dpdv) < 0.0 {
+        -1.0
+    } else {
+        1.0
+    };
+    [t.x, t.y, t.z, w]
+}
+
+fn face_normal(vertices: &[ModelVertex], triangle: &[u32]) -> cgmath::Vector3<f32> {
+    let [a, b, c] =
+        [0, 1, 2].map(|i| cgmath::Vector3::from(vertices[triangle[i] as usize].position));
+    (b - a).cross(c - a)
+}
+
+/// Replaces the normals of the mesh. Counter clockwise triangles face the
+/// viewer. `Flat` rebuilds the vertex and index lists.
+pub fn generate_normals(vertices: &mut Vec<ModelVertex>, indices: &mut Vec<u32>, mode: NormalMode) {
+    match mode {
+        NormalMode::Flat => {
+            let mut flat_vertices = Vec::with_capacity(indices.len());
+            for triangle in indices.chunks_exact(3) {
+                let normal = face_normal(vertices, triangle);
+                let normal = if normal.magnitude2() > 0.0 {
+                    normal.normalize().into()
+                } else {
+                    FALLBACK_NORMAL
+                };
+                for &index in triangle {
+                    flat_vertices.push(ModelVertex {
+                        normal,
+                        ..vertices[index as usize]
+                    });
+                }
+            }
+            *vertices = flat_vertices;
+            *indices = (0..vertices.len() as u32).collect();
+        }
+        NormalMode::Smooth => {
+            // Vertices split on UV seams still get the same normal. Adding 0
+            // turns -0 into 0 so both land on the same key.
+            let mut positions = HashMap::new();
+            let groups = vertices
+                .iter()
+                .map(|vertex| {
+                    let key = vertex.position.map(|v| (v + 0.0).to_bits());
+                    let next = positions.len();
+                    *positions.entry(key).or_insert(next)
+                })
+                .collect::<Vec<_>>();
+
+            let mut sums = vec![cgmath::Vector3::zero(); positions.len()];
+            for triangle in indices.chunks_exact(3) {
+                let normal = face_normal(vertices, triangle);
+                if normal.magnitude2() == 0.0 {
+                    continue;
+                }
+                let normal = normal.normalize();
+                for corner in 0..3 {
+                    let [a, b, c] = [0, 1, 2].map(|i| {
+                        cgmath::Vector3::from(
+                            vertices[triangle[(corner + i) % 3] as usize].position,
+                        )
+                    });
+                    let angle = (b - a).angle(c - a).0;
+                    if angle.is_finite() {
+                        sums[groups[triangle[corner] as usize]] += normal * angle;
+                    }
+                }
+            }
+
+            for (vertex, group) in vertices.iter_mut().zip(groups) {
+                let sum = sums[group];
+                vertex.normal = if sum.magnitude2() > 0.0 {
+                    sum.normalize().into()
+                } else {
+                    FALLBACK_NORMAL
+                };
+            }
+        }
+    }
+}
+
+/// Replaces the tangents of the mesh with ones following its UVs. Each vertex
+/// sums the position derivatives of the triangles using it, so vertices split
+/// on UV seams keep their own. Call it once the normals are final.
+pub fn generate_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    let mut sums = vec![(cgmath::Vector3::zero(), cgmath::Vector3::zero()); vertices.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|i| &vertices[triangle[i] as usize]);
+        let e1 = cgmath::Vector3::from(b.position) - cgmath::Vector3::from(a.position);
+        let e2 = cgmath::Vector3::from(c.position) - cgmath::Vector3::from(a.position);
+        let [du1, dv1] = [0, 1].map(|i| b.tex_coords[i] - a.tex_coords[i]);
+        let [du2, dv2] = [0, 1].map(|i| c.tex_coords[i] - a.tex_coords[i]);
+        let det = du1 * dv2 - du2 * dv1;
+        // No UVs, or a triangle squashed to a line in UV space
+        if det == 0.0 || !det.is_finite() {
+            continue;
+        }
+        let dpdu = (e1 * dv2 - e2 * dv1) / det;
+        let dpdv = (e2 * du1 - e1 * du2) / det;
+        for &index in triangle {
+            let sum = &mut sums[index as usize];
+            sum.0 += dpdu;
+            sum.1 += dpdv;
+        }
+    }
+
+    for (vertex, (dpdu, dpdv)) in vertices.iter_mut().zip(sums) {
+        vertex.tangent = tangent(vertex.normal.into(), dpdu, dpdv);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wgpu_things::primitives;
+
+    fn vertex(position: [f32; 3], tex_coords: [f32; 2]) -> ModelVertex {
+        ModelVertex {
+            position,
+            tex_coords,
+            normal: [0.0; 3],
+            tangent: [0.0; 4],
+        }
+    }
+
+    #[test]
+    fn smooth_normals_ignore_uv_seams() {
+        // A triangle facing +y and one facing -x sharing an edge, with the edge's
+        // vertices split
+        let mut vertices = vec![
+            vertex([-1.0, 0.0, 0.0], [0.0, 0.0]),
+            vertex([0.0, 0.0, 0.0], [1.0, 0.0]),
+            vertex([0.0, 0.0, -1.0], [1.0, 1.0]),
+            vertex([0.0, 0.0, 0.0], [0.0, 0.0]),
+            vertex([0.0, 1.0, 0.0], [0.0, 1.0]),
+            vertex([0.0, 0.0, -1.0], [0.0, 1.0]),
+        ];
+        let mut indices = vec![0, 1, 2, 3, 4, 5];
+        generate_normals(&mut vertices, &mut indices, NormalMode::Smooth);
+        assert_eq!(vertices.len(), 6);
+        assert_eq!(vertices[1].normal, vertices[3].normal);
+        assert_eq!(vertices[2].normal, vertices[5].normal);
+        let normal = cgmath::Vector3::from(vertices[1].normal);
+        assert!((normal.magnitude() - 1.0).abs() < 1e-6);
+        assert!((normal - cgmath::Vector3::new(-1.0, 1.0, 0.0).normalize()).magnitude() < 1e-6);
+    }
+
+    #[test]
+    fn smooth_normals_are_weighted_by_angle() {
+        // A fan around the origin, one triangle facing +z and two facing -x.
+        // The two span the same angle as the first but have twice its area.
+        let mut vertices = vec![
+            vertex([0.0, 0.0, 0.0], [0.0; 2]),
+            vertex([1.0, 0.0, 0.0], [0.0; 2]),
+            vertex([0.0, 1.0, 0.0], [0.0; 2]),
+            vertex([0.0, 0.0, -1.0], [0.0; 2]),
+            vertex([0.0, 1.0, -1.0], [0.0; 2]),
+        ];
+        let mut indices = vec![0, 1, 2, 0, 2, 4, 0, 4, 3];
+        generate_normals(&mut vertices, &mut indices, NormalMode::Smooth);
+        let normal = cgmath::Vector3::from(vertices[0].normal);
+        let expected = cgmath::Vector3::new(-1.0, 0.0, 1.0).normalize();
+        assert!((normal - expected).magnitude() < 1e-6, "{normal:?}");
+    }
+
+    #[test]
+    fn degenerate_triangles_get_the_fallback_normal() {
+        for mode in [NormalMode::Flat, NormalMode::Smooth] {
+            let mut vertices = vec![vertex([1.0, 2.0, 3.0], [0.0; 2]); 3];
+            let mut indices = vec![0, 1, 2];
+            generate_normals(&mut vertices, &mut indices, mode);
+            assert!(vertices.iter().all(|v| v.normal == FALLBACK_NORMAL));
+        }
+    }
+
+    #[test]
+    fn generated_tangents_match_the_primitives() {
+        for mut mesh in [primitives::plane(2.0, 3.0, 4, 2), primitives::cube(1.0)] {
+            let expected = mesh.vertices.iter().map(|v| v.tangent).collect::<Vec<_>>();
+            generate_tangents(&mut mesh.vertices, &mesh.indices);
+            for (vertex, expected) in mesh.vertices.iter().zip(expected) {
+                for (got, expected) in vertex.tangent.iter().zip(expected) {
+                    assert!((got - expected).abs() < 1e-5, "{vertex:?} != {expected:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tangents_are_perpendicular_unit_vectors() {
+        let mut mesh = primitives::uv_sphere(1.0, 16, 8);
+        generate_tangents(&mut mesh.vertices, &mesh.indices);
+        for vertex in &mesh.vertices {
+            let normal = cgmath::Vector3::from(vertex.normal);
+            let [x, y, z, w] = vertex.tangent;
+            let tangent = cgmath::Vector3::new(x, y, z);
+            assert!((tangent.magnitude() - 1.0).abs() < 1e-5);
+            assert!(tangent.dot(normal).abs() < 1e-5);
+            assert!(w == 1.0 || w == -1.0);
+        }
+    }
+
+    #[test]
+    fn meshes_without_uvs_still_get_tangents() {
+        let mut vertices =
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]].map(|position| ModelVertex {
+                position,
+                tex_coords: [0.0; 2],
+                normal: [1.0, 0.0, 0.0],
+                tangent: [0.0; 4],
+            });
+        generate_tangents(&mut vertices, &[0, 1, 2]);
+        for vertex in vertices {
+            let [x, y, z, _] = vertex.tangent;
+            assert!((cgmath::Vector3::new(x, y, z).magnitude() - 1.0).abs() < 1e-5);
+            assert_eq!(x, 0.0);
+        }
+    }
+}
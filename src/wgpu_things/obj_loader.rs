@@ -0,0 +1,347 @@
+use super::{
+    model::ModelVertex,
+    normals::{generate_normals, generate_tangents, NormalMode},
+    resources::load_string,
+};
+use std::{
+    cell::RefCell,
+    fmt,
+    io::{BufReader, Cursor},
+};
+
+/// Malformed OBJ data. Meshes are named after their `o` or `g` statement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjError {
+    /// tobj couldn't parse the file.
+    Parse(tobj::LoadError),
+    PositionCount {
+        mesh: String,
+        len: usize,
+    },
+    /// Texture coordinates or normals present for only some of the vertices.
+    AttributeCount {
+        mesh: String,
+        attribute: &'static str,
+        len: usize,
+        vertex_count: usize,
+    },
+    IndexCount {
+        mesh: String,
+        len: usize,
+    },
+    IndexOutOfRange {
+        mesh: String,
+        index: u32,
+        vertex_count: usize,
+    },
+    NonFinitePosition {
+        mesh: String,
+        vertex: usize,
+    },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(err) => write!(f, "{err}"),
+            Self::PositionCount { mesh, len } => {
+                write!(f, "{mesh}: {len} position values, not a multiple of 3")
+            }
+            Self::AttributeCount {
+                mesh,
+                attribute,
+                len,
+                vertex_count,
+            } => write!(
+                f,
+                "{mesh}: {len} {attribute} values for {vertex_count} vertices"
+            ),
+            Self::IndexCount { mesh, len } => {
+                write!(f, "{mesh}: {len} indices, not a multiple of 3")
+            }
+            Self::IndexOutOfRange {
+                mesh,
+                index,
+                vertex_count,
+            } => write!(f, "{mesh}: index {index} with {vertex_count} vertices"),
+            Self::NonFinitePosition { mesh, vertex } => {
+                write!(f, "{mesh}: vertex {vertex} has a non finite position")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+/// Parses an OBJ file and its MTL file. A missing or broken MTL file is
+/// logged and leaves the meshes without materials.
+pub async fn load_obj(file_name: &str) -> anyhow::Result<(Vec<tobj::Model>, Vec<tobj::Material>)> {
+    let (models, materials, _) = load_obj_with_libs(file_name).await?;
+    Ok((models, materials))
+}
+
+/// Like `load_obj`, also returns the MTL files named by the OBJ file, missing
+/// ones included.
+pub async fn load_obj_with_libs(
+    file_name: &str,
+) -> anyhow::Result<(Vec<tobj::Model>, Vec<tobj::Material>, Vec<String>)> {
+    let obj_text = load_string(file_name).await?;
+    let obj_cursor = Cursor::new(obj_text);
+    let mut obj_reader = BufReader::new(obj_cursor);
+
+    let libs = RefCell::new(Vec::new());
+    let (models, materials) = tobj::load_obj_buf_async(
+        &mut obj_reader,
+        &tobj::LoadOptions {
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        },
+        |p| {
+            libs.borrow_mut().push(p.clone());
+            async move {
+                match load_string(&p).await {
+                    Ok(mat_text) => tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text))),
+                    Err(err) => {
+                        log::warn!("Can't read {p}: {err}");
+                        Err(tobj::LoadError::OpenFileFailed)
+                    }
+                }
+            }
+        },
+    )
+    .await
+    .map_err(ObjError::Parse)?;
+
+    let materials = materials.unwrap_or_else(|err| {
+        log::warn!("No materials for {file_name}: {err}");
+        Vec::new()
+    });
+    Ok((models, materials, libs.into_inner()))
+}
+
+/// Parses MTL files in order, as the OBJ file naming them would. A missing or
+/// broken one is logged and gives no materials at all.
+pub async fn load_mtls(file_name: &str, libs: &[String]) -> Vec<tobj::Material> {
+    let mut materials = Vec::new();
+    for lib in libs {
+        let loaded = match load_string(lib).await {
+            Ok(mat_text) => tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
+                .map_err(anyhow::Error::from),
+            Err(err) => Err(err),
+        };
+        match loaded {
+            Ok((mut lib_materials, _)) => materials.append(&mut lib_materials),
+            Err(err) => {
+                log::warn!("No materials for {file_name}: can't read {lib}: {err}");
+                return Vec::new();
+            }
+        }
+    }
+    materials
+}
+
+/// Checks an OBJ mesh and turns it into vertices. Texture coordinates default
+/// to 0 and missing normals are generated with `normal_mode`.
+pub fn mesh_vertices(
+    model: &tobj::Model,
+    normal_mode: NormalMode,
+) -> Result<(Vec<ModelVertex>, Vec<u32>), ObjError> {
+    let mesh = &model.mesh;
+    let name = || model.name.clone();
+    if mesh.positions.len() % 3 != 0 {
+        return Err(ObjError::PositionCount {
+            mesh: name(),
+            len: mesh.positions.len(),
+        });
+    }
+    let vertex_count = mesh.positions.len() / 3;
+    for (attribute, len, size) in [
+        ("texture coordinate", mesh.texcoords.len(), 2),
+        ("normal", mesh.normals.len(), 3),
+    ] {
+        if len != 0 && len != vertex_count * size {
+            return Err(ObjError::AttributeCount {
+                mesh: name(),
+                attribute,
+                len,
+                vertex_count,
+            });
+        }
+    }
+    if mesh.indices.len() % 3 != 0 {
+        return Err(ObjError::IndexCount {
+            mesh: name(),
+            len: mesh.indices.len(),
+        });
+    }
+    if let Some(&index) = mesh
+        .indices
+        .iter()
+        .find(|&&index| index as usize >= vertex_count)
+    {
+        return Err(ObjError::IndexOutOfRange {
+            mesh: name(),
+            index,
+            vertex_count,
+        });
+    }
+    if let Some(vertex) = mesh.positions.iter().position(|v| !v.is_finite()) {
+        return Err(ObjError::NonFinitePosition {
+            mesh: name(),
+            vertex: vertex / 3,
+        });
+    }
+
+    let mut vertices = (0..vertex_count)
+        .map(|i| ModelVertex {
+            position: [
+                mesh.positions[i * 3],
+                mesh.positions[i * 3 + 1],
+                mesh.positions[i * 3 + 2],
+            ],
+            tex_coords: match mesh.texcoords.get(i * 2..i * 2 + 2) {
+                Some(uv) => [uv[0], 1.0 - uv[1]],
+                None => [0.0, 0.0],
+            },
+            normal: match mesh.normals.get(i * 3..i * 3 + 3) {
+                Some(n) => [n[0], n[1], n[2]],
+                None => [0.0, 0.0, 0.0],
+            },
+            tangent: [0.0; 4],
+        })
+        .collect::<Vec<_>>();
+    let mut indices = mesh.indices.clone();
+    if mesh.normals.is_empty() {
+        generate_normals(&mut vertices, &mut indices, normal_mode);
+    }
+    // OBJ files have no tangents
+    generate_tangents(&mut vertices, &indices);
+    Ok((vertices, indices))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two triangles sharing the edge from (0, 0, 0) to (1, 0, 0), one facing
+    // +z and one facing +y
+    fn model(edit: impl FnOnce(&mut tobj::Mesh)) -> tobj::Model {
+        let mut mesh = tobj::Mesh {
+            positions: vec![
+                0.0, 0.0, 0.0, //
+                1.0, 0.0, 0.0, //
+                0.0, 1.0, 0.0, //
+                0.0, 0.0, 1.0,
+            ],
+            indices: vec![0, 1, 2, 0, 3, 1],
+            ..Default::default()
+        };
+        edit(&mut mesh);
+        tobj::Model::new(mesh, "roof".to_string())
+    }
+
+    fn error(edit: impl FnOnce(&mut tobj::Mesh)) -> ObjError {
+        mesh_vertices(&model(edit), NormalMode::Smooth).unwrap_err()
+    }
+
+    #[test]
+    fn given_attributes_are_kept_and_v_is_flipped() {
+        let model = model(|mesh| {
+            mesh.texcoords = vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.5, 0.25];
+            mesh.normals = [0.0, 0.0, 1.0].repeat(4);
+        });
+        let (vertices, indices) = mesh_vertices(&model, NormalMode::Flat).unwrap();
+        assert_eq!(indices, [0, 1, 2, 0, 3, 1]);
+        assert_eq!(vertices[2].tex_coords, [0.0, 0.0]);
+        assert_eq!(vertices[3].tex_coords, [0.5, 0.75]);
+        // Given normals aren't replaced, even the wrong one of the +y triangle
+        assert!(vertices.iter().all(|v| v.normal == [0.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn missing_uvs_default_to_0() {
+        let (vertices, _) = mesh_vertices(&model(|_| {}), NormalMode::Smooth).unwrap();
+        assert!(vertices.iter().all(|v| v.tex_coords == [0.0, 0.0]));
+    }
+
+    #[test]
+    fn flat_normals_split_the_vertices() {
+        let (vertices, indices) = mesh_vertices(&model(|_| {}), NormalMode::Flat).unwrap();
+        assert_eq!(vertices.len(), 6);
+        assert_eq!(indices, [0, 1, 2, 3, 4, 5]);
+        let normals = vertices.iter().map(|v| v.normal).collect::<Vec<_>>();
+        assert_eq!(normals[..3], [[0.0, 0.0, 1.0]; 3]);
+        assert_eq!(normals[3..], [[0.0, 1.0, 0.0]; 3]);
+    }
+
+    #[test]
+    fn smooth_normals_are_shared_on_the_edge() {
+        let (vertices, indices) = mesh_vertices(&model(|_| {}), NormalMode::Smooth).unwrap();
+        assert_eq!(vertices.len(), 4);
+        assert_eq!(indices, [0, 1, 2, 0, 3, 1]);
+        let diagonal = std::f32::consts::FRAC_1_SQRT_2;
+        let expected = [
+            [0.0, diagonal, diagonal],
+            [0.0, diagonal, diagonal],
+            [0.0, 0.0, 1.0],
+            [0.0, 1.0, 0.0],
+        ];
+        for (vertex, expected) in vertices.iter().zip(expected) {
+            for (got, expected) in vertex.normal.iter().zip(expected) {
+                assert!((got - expected).abs() < 1e-6, "{vertex:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_meshes_are_reported() {
+        let mesh = "roof".to_string();
+        assert_eq!(
+            error(|m| m.positions.push(1.0)),
+            ObjError::PositionCount {
+                mesh: mesh.clone(),
+                len: 13
+            }
+        );
+        assert_eq!(
+            error(|m| m.texcoords = vec![0.0; 6]),
+            ObjError::AttributeCount {
+                mesh: mesh.clone(),
+                attribute: "texture coordinate",
+                len: 6,
+                vertex_count: 4
+            }
+        );
+        assert_eq!(
+            error(|m| m.normals = vec![0.0; 13]),
+            ObjError::AttributeCount {
+                mesh: mesh.clone(),
+                attribute: "normal",
+                len: 13,
+                vertex_count: 4
+            }
+        );
+        assert_eq!(
+            error(|m| {
+                m.indices.pop();
+            }),
+            ObjError::IndexCount {
+                mesh: mesh.clone(),
+                len: 5
+            }
+        );
+        assert_eq!(
+            error(|m| m.indices[4] = 4),
+            ObjError::IndexOutOfRange {
+                mesh: mesh.clone(),
+                index: 4,
+                vertex_count: 4
+            }
+        );
+        assert_eq!(
+            error(|m| m.positions[7] = f32::NAN),
+            ObjError::NonFinitePosition { mesh, vertex: 2 }
+        );
+    }
+}
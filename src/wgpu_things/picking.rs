@@ -0,0 +1,291 @@
+use super::{bounds::Aabb, model::Mesh, Instance};
+use cgmath::prelude::*;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Ray {
+    pub origin: cgmath::Point3<f32>,
+    pub direction: cgmath::Vector3<f32>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct RayHit {
+    /// Index of the instance that was hit, 0 when testing a single mesh.
+    pub instance: usize,
+    /// Index of the triangle inside the mesh, i.e. the first index is `triangle * 3`.
+    pub triangle: usize,
+    /// Distance along the ray, in multiples of `Ray::direction`.
+    pub distance: f32,
+    pub point: cgmath::Point3<f32>,
+}
+
+impl Ray {
+    pub fn new(origin: cgmath::Point3<f32>, direction: cgmath::Vector3<f32>) -> Self {
+        Self { origin, direction }
+    }
+
+    pub fn at(&self, distance: f32) -> cgmath::Point3<f32> {
+        self.origin + self.direction * distance
+    }
+
+    /// The same ray expressed in another space. The direction is not normalized
+    /// again so distances stay comparable with the original ray.
+    pub fn transform(&self, matrix: &cgmath::Matrix4<f32>) -> Ray {
+        Ray {
+            origin: matrix.transform_point(self.origin),
+            direction: matrix.transform_vector(self.direction),
+        }
+    }
+
+    /// Slab test, returns the distance where the ray enters the box
+    /// (0.0 when it starts inside of it).
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let mut t_min = 0.0_f32;
+        let mut t_max = f32::INFINITY;
+        for axis in 0..3 {
+            let inv_dir = 1.0 / self.direction[axis];
+            let mut t0 = (aabb.min[axis] - self.origin[axis]) * inv_dir;
+            let mut t1 = (aabb.max[axis] - self.origin[axis]) * inv_dir;
+            if inv_dir < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            // NaN happens when the ray is parallel to the slab and starts on its border,
+            // max/min ignore it so the slab is treated as a hit.
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_max < t_min {
+                return None;
+            }
+        }
+        Some(t_min)
+    }
+
+    /// Möller–Trumbore, both faces of the triangle count as hits.
+    pub fn intersect_triangle(
+        &self,
+        a: cgmath::Point3<f32>,
+        b: cgmath::Point3<f32>,
+        c: cgmath::Point3<f32>,
+    ) -> Option<f32> {
+        const EPSILON: f32 = 1e-7;
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let p = self.direction.cross(edge2);
+        let det = edge1.dot(p);
+        if det.abs() < EPSILON {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let s = self.origin - a;
+        let u = s.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = s.cross(edge1);
+        let v = self.direction.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = edge2.dot(q) * inv_det;
+        (t > EPSILON).then_some(t)
+    }
+
+    /// Closest triangle of the mesh hit by the ray, the ray must be in the mesh space.
+    pub fn intersect_mesh(&self, mesh: &Mesh) -> Option<RayHit> {
+        self.intersect_aabb(&mesh.aabb)?;
+        self.intersect_triangles(mesh)
+    }
+
+    fn intersect_triangles(&self, mesh: &Mesh) -> Option<RayHit> {
+        let position = |index: u32| cgmath::Point3::from(mesh.vertices[index as usize].position);
+        mesh.indices
+            .chunks_exact(3)
+            .enumerate()
+            .filter_map(|(triangle, tri)| {
+                let distance =
+                    self.intersect_triangle(position(tri[0]), position(tri[1]), position(tri[2]))?;
+                Some(RayHit {
+                    instance: 0,
+                    triangle,
+                    distance,
+                    point: self.at(distance),
+                })
+            })
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    /// Closest triangle of `mesh` drawn with the transform of `instance`, the ray
+    /// is in world space. `index` is reported back in the hit.
+    pub fn intersect_instance(
+        &self,
+        mesh: &Mesh,
+        index: usize,
+        instance: &Instance,
+    ) -> Option<RayHit> {
+        let model = instance.to_matrix();
+        let local_ray = self.transform(&model.invert()?);
+        local_ray.intersect_aabb(&mesh.aabb)?;
+        let hit = local_ray.intersect_triangles(mesh)?;
+        Some(RayHit {
+            instance: index,
+            point: self.at(hit.distance),
+            ..hit
+        })
+    }
+
+    /// Finds which instance of `mesh` is the closest one hit by a world space ray.
+    pub fn pick_instance(&self, mesh: &Mesh, instances: &[Instance]) -> Option<RayHit> {
+        instances
+            .iter()
+            .enumerate()
+            .filter_map(|(index, instance)| self.intersect_instance(mesh, index, instance))
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> Aabb {
+        Aabb::new(
+            cgmath::Point3::new(0.0, 0.0, 0.0),
+            cgmath::Point3::new(1.0, 1.0, 1.0),
+        )
+    }
+
+    fn ray(origin: [f32; 3], direction: [f32; 3]) -> Ray {
+        Ray::new(origin.into(), direction.into())
+    }
+
+    #[test]
+    fn ray_enters_the_box_on_the_near_face() {
+        let hit = ray([-2.0, 0.5, 0.5], [1.0, 0.0, 0.0]).intersect_aabb(&unit_box());
+        assert_eq!(hit, Some(2.0));
+        // Diagonal, coming from the far corner side
+        let hit = ray([2.0, 2.0, 2.0], [-1.0, -1.0, -1.0]).intersect_aabb(&unit_box());
+        assert_eq!(hit, Some(1.0));
+    }
+
+    #[test]
+    fn ray_starting_inside_the_box_hits_at_0() {
+        let hit = ray([0.5, 0.5, 0.5], [0.0, -1.0, 0.0]).intersect_aabb(&unit_box());
+        assert_eq!(hit, Some(0.0));
+    }
+
+    #[test]
+    fn ray_misses_the_box() {
+        let aabb = unit_box();
+        assert_eq!(
+            ray([-2.0, 1.5, 0.5], [1.0, 0.0, 0.0]).intersect_aabb(&aabb),
+            None
+        );
+        // Pointing away from it
+        assert_eq!(
+            ray([-2.0, 0.5, 0.5], [-1.0, 0.0, 0.0]).intersect_aabb(&aabb),
+            None
+        );
+        // Passes next to a corner
+        assert_eq!(
+            ray([-1.0, 0.0, 0.5], [1.0, 2.0, 0.0]).intersect_aabb(&aabb),
+            None
+        );
+    }
+
+    #[test]
+    fn ray_grazing_the_box_hits() {
+        // Along an edge of the box
+        let hit = ray([-1.0, 1.0, 1.0], [1.0, 0.0, 0.0]).intersect_aabb(&unit_box());
+        assert_eq!(hit, Some(1.0));
+        // Through a corner
+        let hit = ray([-1.0, -1.0, 0.5], [1.0, 1.0, 0.0]).intersect_aabb(&unit_box());
+        assert_eq!(hit, Some(1.0));
+    }
+
+    #[test]
+    fn ray_parallel_to_the_slabs() {
+        let aabb = unit_box();
+        // Inside the Y and Z slabs
+        assert_eq!(
+            ray([-1.0, 0.5, 0.5], [1.0, 0.0, 0.0]).intersect_aabb(&aabb),
+            Some(1.0)
+        );
+        // Outside the Y slab, never gets closer to it
+        assert_eq!(
+            ray([-1.0, 2.0, 0.5], [1.0, 0.0, 0.0]).intersect_aabb(&aabb),
+            None
+        );
+        assert_eq!(
+            ray([-1.0, 0.5, -0.1], [1.0, 0.0, 0.0]).intersect_aabb(&aabb),
+            None
+        );
+        // Negative zero components behave like positive ones
+        assert_eq!(
+            ray([0.5, 0.5, 3.0], [-0.0, -0.0, -1.0]).intersect_aabb(&aabb),
+            Some(2.0)
+        );
+    }
+
+    fn triangle() -> [cgmath::Point3<f32>; 3] {
+        [
+            cgmath::Point3::new(0.0, 0.0, 0.0),
+            cgmath::Point3::new(1.0, 0.0, 0.0),
+            cgmath::Point3::new(0.0, 1.0, 0.0),
+        ]
+    }
+
+    fn intersect(ray: Ray) -> Option<f32> {
+        let [a, b, c] = triangle();
+        ray.intersect_triangle(a, b, c)
+    }
+
+    #[test]
+    fn ray_hits_both_faces_of_the_triangle() {
+        assert_eq!(
+            intersect(ray([0.25, 0.25, 2.0], [0.0, 0.0, -1.0])),
+            Some(2.0)
+        );
+        assert_eq!(
+            intersect(ray([0.25, 0.25, -2.0], [0.0, 0.0, 2.0])),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn ray_misses_the_triangle() {
+        // Outside of the hypotenuse
+        assert_eq!(intersect(ray([0.6, 0.6, 2.0], [0.0, 0.0, -1.0])), None);
+        // Outside of the legs
+        assert_eq!(intersect(ray([-0.1, 0.5, 2.0], [0.0, 0.0, -1.0])), None);
+        assert_eq!(intersect(ray([0.5, -0.1, 2.0], [0.0, 0.0, -1.0])), None);
+        // Triangle behind the origin
+        assert_eq!(intersect(ray([0.25, 0.25, 2.0], [0.0, 0.0, 1.0])), None);
+    }
+
+    #[test]
+    fn ray_on_the_triangle_border_hits() {
+        assert_eq!(intersect(ray([0.5, 0.0, 1.0], [0.0, 0.0, -1.0])), Some(1.0));
+        assert_eq!(intersect(ray([0.0, 0.5, 1.0], [0.0, 0.0, -1.0])), Some(1.0));
+        assert_eq!(intersect(ray([0.5, 0.5, 1.0], [0.0, 0.0, -1.0])), Some(1.0));
+        assert_eq!(intersect(ray([1.0, 0.0, 1.0], [0.0, 0.0, -1.0])), Some(1.0));
+    }
+
+    #[test]
+    fn ray_parallel_to_the_triangle_misses() {
+        // In its plane, going through it
+        assert_eq!(intersect(ray([-1.0, 0.25, 0.0], [1.0, 0.0, 0.0])), None);
+        // Above it
+        assert_eq!(intersect(ray([-1.0, 0.25, 0.5], [1.0, 0.0, 0.0])), None);
+    }
+
+    #[test]
+    fn transformed_ray_keeps_distances() {
+        let ray = ray([0.0, 0.0, 5.0], [0.0, 0.0, -1.0]);
+        let matrix = cgmath::Matrix4::from_scale(2.0);
+        let local = ray.transform(&matrix.invert().unwrap());
+        let [a, b, c] = triangle().map(|p| cgmath::Point3::new(p.x - 0.25, p.y - 0.25, 1.0));
+        let distance = local.intersect_triangle(a, b, c).unwrap();
+        // The triangle is at z = 2 in world space
+        assert!((distance - 3.0).abs() < 1e-5);
+        assert_eq!(ray.at(distance), cgmath::Point3::new(0.0, 0.0, 2.0));
+    }
+}
@@ -0,0 +1,394 @@
+//! Procedural meshes for test scenes and debug geometry.
+//!
+//! All the primitives are centered on the origin with Y up. Triangles are
+//! counter clockwise seen from outside, UVs have their origin at the top left
+//! of the texture like the OBJ loader's, and seams are split so the UVs never
+//! wrap around.
+
+use super::{
+    assets::Handle,
+    model::{Material, Mesh, Model, ModelVertex},
+    normals::tangent,
+};
+use cgmath::prelude::*;
+use std::{collections::HashMap, f32::consts::PI};
+
+type Vec3 = cgmath::Vector3<f32>;
+
+/// Vertices and triangles of a primitive, not uploaded yet.
+#[derive(Debug, Clone, Default)]
+pub struct PrimitiveMesh {
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+}
+
+// Horizontal unit vector at angle `phi` and its derivative. The angle goes
+// clockwise seen from above so that `u` goes right seen from outside.
+fn radial(phi: f32) -> (Vec3, Vec3) {
+    let (sin, cos) = phi.sin_cos();
+    (Vec3::new(cos, 0.0, -sin), Vec3::new(-sin, 0.0, -cos))
+}
+
+impl PrimitiveMesh {
+    fn push(&mut self, position: Vec3, normal: Vec3, uv: [f32; 2], dpdu: Vec3, dpdv: Vec3) -> u32 {
+        let normal = normal.normalize();
+        self.vertices.push(ModelVertex {
+            position: position.into(),
+            tex_coords: uv,
+            normal: normal.into(),
+            tangent: tangent(normal, dpdu, dpdv),
+        });
+        self.vertices.len() as u32 - 1
+    }
+
+    // Adds a triangle facing the same way as its vertex normals, skips
+    // degenerate ones such as those touching the pole of a sphere
+    fn triangle(&mut self, a: u32, b: u32, c: u32) {
+        let [pa, pb, pc] = [a, b, c].map(|i| Vec3::from(self.vertices[i as usize].position));
+        let [na, nb, nc] = [a, b, c].map(|i| Vec3::from(self.vertices[i as usize].normal));
+        let (e1, e2) = (pb - pa, pc - pa);
+        let cross = e1.cross(e2);
+        if cross.magnitude2() <= 1e-12 * e1.magnitude2() * e2.magnitude2() {
+            return;
+        }
+        if cross.dot(na + nb + nc) >= 0.0 {
+            self.indices.extend([a, b, c]);
+        } else {
+            self.indices.extend([a, c, b]);
+        }
+    }
+
+    // Triangulates `columns + 1` by `rows + 1` vertices added row by row from `first`
+    fn grid(&mut self, first: u32, columns: u32, rows: u32) {
+        for row in 0..rows {
+            for column in 0..columns {
+                let a = first + row * (columns + 1) + column;
+                let b = a + 1;
+                let c = a + columns + 1;
+                let d = c + 1;
+                self.triangle(a, c, d);
+                self.triangle(a, d, b);
+            }
+        }
+    }
+
+    // Surface of revolution around Y. Each profile point is a radius, a height,
+    // their derivatives along the profile and its `v` coordinate. The profile
+    // must turn clockwise seen with the radius to the right, like a sphere's
+    // from the top pole to the bottom one.
+    fn revolve(&mut self, segments: u32, profile: &[(f32, f32, f32, f32, f32)]) {
+        let first = self.vertices.len() as u32;
+        for &(radius, height, d_radius, d_height, v) in profile {
+            for segment in 0..=segments {
+                let u = segment as f32 / segments as f32;
+                let (out, d_out) = radial(u * 2.0 * PI);
+                let position = out * radius + Vec3::unit_y() * height;
+                let dpdv = out * d_radius + Vec3::unit_y() * d_height;
+                // Perpendicular to the profile, on its outer side
+                let normal = out * -d_height + Vec3::unit_y() * d_radius;
+                self.push(position, normal, [u, v], d_out, dpdv);
+            }
+        }
+        self.grid(first, segments, profile.len() as u32 - 1);
+    }
+
+    // Flat disc facing `up`, `up` being +Y or -Y
+    fn disc(&mut self, radius: f32, height: f32, up: f32, segments: u32) {
+        let normal = Vec3::unit_y() * up;
+        // Seen from the side it faces, with +X to the right
+        let dpdv = Vec3::unit_z() * up;
+        let center = self.push(
+            Vec3::unit_y() * height,
+            normal,
+            [0.5, 0.5],
+            Vec3::unit_x(),
+            dpdv,
+        );
+        let first = self.vertices.len() as u32;
+        for segment in 0..=segments {
+            let (out, _) = radial(segment as f32 / segments as f32 * 2.0 * PI);
+            let uv = [0.5 + 0.5 * out.x, 0.5 + 0.5 * out.z * up];
+            self.push(
+                out * radius + Vec3::unit_y() * height,
+                normal,
+                uv,
+                Vec3::unit_x(),
+                dpdv,
+            );
+        }
+        for segment in 0..segments {
+            self.triangle(center, first + segment, first + segment + 1);
+        }
+    }
+
+    /// Uploads the mesh.
+    pub fn to_mesh(&self, device: &wgpu::Device, name: &str, material: usize) -> Mesh {
+        Mesh::new(
+            device,
+            name,
+            self.vertices.clone(),
+            self.indices.clone(),
+            material,
+        )
+    }
+
+    /// Model made of this mesh drawn with `material`.
+    pub fn to_model(&self, device: &wgpu::Device, name: &str, material: Material) -> Model {
+        Model {
+            meshes: vec![Handle::new(self.to_mesh(device, name, 0))],
+            materials: vec![Handle::new(material)],
+            lods: Vec::new(),
+        }
+    }
+}
+
+/// Cube with edges of length `size`, each face mapped to the whole texture.
+pub fn cube(size: f32) -> PrimitiveMesh {
+    let half = size * 0.5;
+    let mut mesh = PrimitiveMesh::default();
+    // Normal and the directions of u and v on each face
+    let faces = [
+        (Vec3::unit_x(), -Vec3::unit_z(), -Vec3::unit_y()),
+        (-Vec3::unit_x(), Vec3::unit_z(), -Vec3::unit_y()),
+        (Vec3::unit_y(), Vec3::unit_x(), Vec3::unit_z()),
+        (-Vec3::unit_y(), Vec3::unit_x(), -Vec3::unit_z()),
+        (Vec3::unit_z(), Vec3::unit_x(), -Vec3::unit_y()),
+        (-Vec3::unit_z(), -Vec3::unit_x(), -Vec3::unit_y()),
+    ];
+    for (normal, du, dv) in faces {
+        let first = mesh.vertices.len() as u32;
+        for v in [0.0, 1.0] {
+            for u in [0.0, 1.0] {
+                let position = (normal + du * (u * 2.0 - 1.0) + dv * (v * 2.0 - 1.0)) * half;
+                mesh.push(position, normal, [u, v], du, dv);
+            }
+        }
+        mesh.grid(first, 1, 1);
+    }
+    mesh
+}
+
+/// Sphere split along meridians and parallels, `u` goes around and `v` from
+/// the top to the bottom.
+pub fn uv_sphere(radius: f32, segments: u32, rings: u32) -> PrimitiveMesh {
+    let segments = segments.max(3);
+    let rings = rings.max(2);
+    let profile = (0..=rings)
+        .map(|ring| {
+            let v = ring as f32 / rings as f32;
+            let (sin, cos) = (v * PI).sin_cos();
+            (radius * sin, radius * cos, cos, -sin, v)
+        })
+        .collect::<Vec<_>>();
+    let mut mesh = PrimitiveMesh::default();
+    mesh.revolve(segments, &profile);
+    mesh
+}
+
+/// Sphere made of subdivided icosahedron faces, with evenly sized triangles.
+/// Uses the same UV mapping as `uv_sphere`.
+pub fn icosphere(radius: f32, subdivisions: u32) -> PrimitiveMesh {
+    let t = (1.0 + 5f32.sqrt()) / 2.0;
+    let mut points = [
+        [-1.0, t, 0.0],
+        [1.0, t, 0.0],
+        [-1.0, -t, 0.0],
+        [1.0, -t, 0.0],
+        [0.0, -1.0, t],
+        [0.0, 1.0, t],
+        [0.0, -1.0, -t],
+        [0.0, 1.0, -t],
+        [t, 0.0, -1.0],
+        [t, 0.0, 1.0],
+        [-t, 0.0, -1.0],
+        [-t, 0.0, 1.0],
+    ]
+    .map(|p| Vec3::from(p).normalize())
+    .to_vec();
+    let mut triangles: Vec<[usize; 3]> = vec![
+        [0, 11, 5],
+        [0, 5, 1],
+        [0, 1, 7],
+        [0, 7, 10],
+        [0, 10, 11],
+        [1, 5, 9],
+        [5, 11, 4],
+        [11, 10, 2],
+        [10, 7, 6],
+        [7, 1, 8],
+        [3, 9, 4],
+        [3, 4, 2],
+        [3, 2, 6],
+        [3, 6, 8],
+        [3, 8, 9],
+        [4, 9, 5],
+        [2, 4, 11],
+        [6, 2, 10],
+        [8, 6, 7],
+        [9, 8, 1],
+    ];
+
+    for _ in 0..subdivisions {
+        let mut midpoints = HashMap::new();
+        let mut midpoint = |a: usize, b: usize, points: &mut Vec<Vec3>| {
+            *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                points.push(((points[a] + points[b]) * 0.5).normalize());
+                points.len() - 1
+            })
+        };
+        triangles = triangles
+            .into_iter()
+            .flat_map(|[a, b, c]| {
+                let ab = midpoint(a, b, &mut points);
+                let bc = midpoint(b, c, &mut points);
+                let ca = midpoint(c, a, &mut points);
+                [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+            })
+            .collect();
+    }
+
+    // Vertices are split where the UVs need to differ, on the seam and at the poles
+    let mut mesh = PrimitiveMesh::default();
+    let mut vertices = HashMap::new();
+    for triangle in triangles {
+        let directions = triangle.map(|i| points[i]);
+        let mut uvs = directions.map(|d| {
+            let u = (-d.z).atan2(d.x) / (2.0 * PI);
+            [u.rem_euclid(1.0) + 0.0, d.y.clamp(-1.0, 1.0).acos() / PI]
+        });
+        // The u of a pole is meaningless, it gets the middle of the opposite edge
+        let poles = directions.map(|d| d.y.abs() > 0.9999);
+        // Triangles crossing the seam get u above 1 on the side past it
+        let max_u = (0..3)
+            .filter(|&corner| !poles[corner])
+            .map(|corner| uvs[corner][0])
+            .fold(0.0, f32::max);
+        for uv in &mut uvs {
+            if max_u - uv[0] > 0.5 {
+                uv[0] += 1.0;
+            }
+        }
+        for corner in (0..3).filter(|&corner| poles[corner]) {
+            let (a, b) = ((corner + 1) % 3, (corner + 2) % 3);
+            uvs[corner][0] = (uvs[a][0] + uvs[b][0]) * 0.5;
+        }
+
+        let indices = [0, 1, 2].map(|corner| {
+            let (d, uv) = (directions[corner], uvs[corner]);
+            let key = (triangle[corner], uv[0].to_bits(), uv[1].to_bits());
+            *vertices.entry(key).or_insert_with(|| {
+                let (out, d_out) = radial(uv[0] * 2.0 * PI);
+                let (sin, cos) = (uv[1] * PI).sin_cos();
+                mesh.push(d * radius, d, uv, d_out, out * cos + Vec3::unit_y() * -sin)
+            })
+        });
+        mesh.triangle(indices[0], indices[1], indices[2]);
+    }
+    mesh
+}
+
+/// Flat plane on XZ facing +Y, split in `subdivisions_x` by `subdivisions_z`
+/// quads. `v` goes towards +Z.
+pub fn plane(size_x: f32, size_z: f32, subdivisions_x: u32, subdivisions_z: u32) -> PrimitiveMesh {
+    let (columns, rows) = (subdivisions_x.max(1), subdivisions_z.max(1));
+    let mut mesh = PrimitiveMesh::default();
+    for row in 0..=rows {
+        for column in 0..=columns {
+            let (u, v) = (column as f32 / columns as f32, row as f32 / rows as f32);
+            let position = Vec3::new((u - 0.5) * size_x, 0.0, (v - 0.5) * size_z);
+            mesh.push(
+                position,
+                Vec3::unit_y(),
+                [u, v],
+                Vec3::unit_x(),
+                Vec3::unit_z(),
+            );
+        }
+    }
+    mesh.grid(0, columns, rows);
+    mesh
+}
+
+/// Cylinder along Y with both ends closed. The side uses the whole texture,
+/// `v` going down, and each cap a disc inscribed in it.
+pub fn cylinder(radius: f32, height: f32, segments: u32) -> PrimitiveMesh {
+    let segments = segments.max(3);
+    let half = height * 0.5;
+    let mut mesh = PrimitiveMesh::default();
+    mesh.revolve(
+        segments,
+        &[
+            (radius, half, 0.0, -height, 0.0),
+            (radius, -half, 0.0, -height, 1.0),
+        ],
+    );
+    mesh.disc(radius, half, 1.0, segments);
+    mesh.disc(radius, -half, -1.0, segments);
+    mesh
+}
+
+/// Cone along Y with its tip at the top and a closed base.
+pub fn cone(radius: f32, height: f32, segments: u32) -> PrimitiveMesh {
+    let segments = segments.max(3);
+    let half = height * 0.5;
+    let mut mesh = PrimitiveMesh::default();
+    // The tip is split so each column has the normal of its side
+    mesh.revolve(
+        segments,
+        &[
+            (0.0, half, radius, -height, 0.0),
+            (radius, -half, radius, -height, 1.0),
+        ],
+    );
+    mesh.disc(radius, -half, -1.0, segments);
+    mesh
+}
+
+/// Torus around Y. `u` goes around Y and `v` around the tube, starting on
+/// its outer side and going down.
+pub fn torus(
+    major_radius: f32,
+    minor_radius: f32,
+    major_segments: u32,
+    minor_segments: u32,
+) -> PrimitiveMesh {
+    let minor_segments = minor_segments.max(3);
+    let profile = (0..=minor_segments)
+        .map(|segment| {
+            let v = segment as f32 / minor_segments as f32;
+            let (sin, cos) = (v * 2.0 * PI).sin_cos();
+            (
+                major_radius + minor_radius * cos,
+                -minor_radius * sin,
+                -sin,
+                -cos,
+                v,
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut mesh = PrimitiveMesh::default();
+    mesh.revolve(major_segments.max(3), &profile);
+    mesh
+}
+
+/// Cylinder of `height` along Y capped by two half spheres, `height` doesn't
+/// include them. `v` is proportional to the length along the profile so the
+/// texture isn't stretched on the cylinder.
+pub fn capsule(radius: f32, height: f32, segments: u32, rings: u32) -> PrimitiveMesh {
+    let rings = rings.max(1);
+    let half = height * 0.5;
+    let length = PI * radius + height;
+    let mut profile = Vec::new();
+    // Top half sphere from the pole down, then the bottom one from the equator
+    for (offset, start) in [(half, 0.0), (-half, 0.5 * PI)] {
+        for ring in 0..=rings {
+            let theta = start + ring as f32 / rings as f32 * 0.5 * PI;
+            let (sin, cos) = theta.sin_cos();
+            let arc = theta * radius + if offset < 0.0 { height } else { 0.0 };
+            profile.push((radius * sin, radius * cos + offset, cos, -sin, arc / length));
+        }
+    }
+    let mut mesh = PrimitiveMesh::default();
+    mesh.revolve(segments.max(3), &profile);
+    mesh
+}
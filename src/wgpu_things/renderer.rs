@@ -1,12 +1,28 @@
 use super::{
-    camera::{Camera, CameraController},
-    model::Vertex,
+    assets::{AssetManager, Handle},
+    bvh::InstanceBvh,
+    camera::{Camera, DepthMode},
+    camera_path::{CameraPath, CameraPlayer, CameraRecorder},
+    gpu_culling::{GpuCullTarget, GpuCuller},
+    hot_reload::HotReloader,
+    loader::{placeholder_model, AssetLoader, Pending},
+    lod::LodSelector,
+    model::{Material, MaterialFactors, Model, Vertex},
+    scene_graph::{ModelId, NodeId, NodeTransform, SceneGraph, SceneInstances},
+    vfs,
+    viewport::{Viewport, ViewportLayout},
     Instance, InstancesVec, Texture,
 };
-use std::{iter, sync::Arc};
+use std::{
+    iter,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use winit::{
     event::*,
     event_loop::EventLoop,
+    keyboard::Key,
+    platform::modifier_supplement::KeyEventExtModifierSupplement,
     window::{Window, WindowBuilder},
 };
 
@@ -16,25 +32,87 @@ const INSTANCE_DISPLACEMENT: cgmath::Vector3<f32> = cgmath::Vector3::new(
     0.0,
     NUM_INSTANCES_PER_ROW as f32 * 0.5,
 );
+const CAMERA_PATH_FILE: &str = "camera_path.json";
+const CAMERA_RECORD_INTERVAL: f32 = 0.1;
+// Path time between two frames of a camera path playback
+const CAMERA_PLAYBACK_STEP: f32 = 1.0 / 60.0;
+const MODEL_FILE: &str = "cube.obj";
+const LOADER_THREADS: usize = 2;
+const HOT_RELOAD_INTERVAL: Duration = Duration::from_millis(500);
+// Fraction of the triangles kept by each level of detail
+const LOD_RATIOS: [f32; 3] = [0.5, 0.25, 0.1];
+
+/// How instances outside of the camera view are skipped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum CullingMode {
+    /// Every instance is drawn.
+    None,
+    /// Instances are tested against each camera frustum on the CPU every frame.
+    #[default]
+    Cpu,
+    /// Instances are tested in a compute pass and drawn with one indirect draw
+    /// per mesh, the visible count never comes back to the CPU. Levels of
+    /// detail aren't used, the most detailed meshes are always drawn.
+    Gpu,
+}
+
+/// Options picked when the window is created.
+#[derive(Debug, Clone, Default)]
+pub struct RenderSettings {
+    pub depth_mode: DepthMode,
+    pub viewport_layout: ViewportLayout,
+    pub culling: CullingMode,
+    /// Adds an animated arm built from a scene graph.
+    pub robot_arm: bool,
+    /// Reloads the models when their files change.
+    pub hot_reload: bool,
+}
+
+/// Assets reloaded when their files change.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ReloadTarget {
+    ObjModel,
+    ArmModel,
+}
 
 struct State {
     surface: wgpu::Surface<'static>,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
+    device: Arc<wgpu::Device>,
+    queue: Arc<wgpu::Queue>,
     config: wgpu::SurfaceConfiguration,
     size: winit::dpi::PhysicalSize<u32>,
     render_pipeline: wgpu::RenderPipeline,
+    // Only created with `CullingMode::Gpu`
+    gpu_culler: Option<GpuCuller>,
+    indirect_pipeline: Option<wgpu::RenderPipeline>,
     window: Arc<Window>,
-    diffuse_bind_group: wgpu::BindGroup,
-    camera_controller: CameraController,
-    camera_bind_group: wgpu::BindGroup,
+    diffuse_material: Material,
+    viewports: Vec<Viewport>,
+    // Viewport that receives the keyboard input, the last one under the cursor
+    active_viewport: usize,
     instances_vec: InstancesVec,
-    depth_texture: super::Texture,
+    // Speeds up picking, kept in sync with `instances_vec`
+    instance_bvh: InstanceBvh,
+    // A placeholder until `pending_obj_model` is loaded
     obj_model: super::model::Model,
+    loader: AssetLoader,
+    pending_obj_model: Option<Pending<super::model::Model>>,
+    arm_model: Option<ModelId>,
+    pending_arm_model: Option<Pending<Handle<super::model::Model>>>,
+    hot_reloader: Option<HotReloader<ReloadTarget>>,
+    lod_selector: LodSelector,
+    cursor_position: Option<winit::dpi::PhysicalPosition<f64>>,
+    camera_recorder: Option<CameraRecorder>,
+    camera_player: Option<CameraPlayer>,
+    last_update: Instant,
+    settings: RenderSettings,
+    scene: SceneGraph,
+    scene_instances: SceneInstances,
+    robot_arm: Option<RobotArm>,
 }
 
 impl State {
-    async fn new(window: Window) -> Self {
+    async fn new(window: Window, settings: RenderSettings) -> Self {
         let window = Arc::new(window);
         let size = window.inner_size();
 
@@ -71,8 +149,11 @@ impl State {
             )
             .await
             .unwrap();
+        // Shared with the asset loader threads
+        let (device, queue) = (Arc::new(device), Arc::new(queue));
 
-        let (texture_bind_group_layout, diffuse_bind_group) = create_texture(&device, &queue);
+        let (texture_bind_group_layout, diffuse_material) = create_texture(&device, &queue);
+        let texture_bind_group_layout = Arc::new(texture_bind_group_layout);
 
         let surface_caps = surface.get_capabilities(&adapter);
         // Shader code in this tutorial assumes an Srgb surface texture. Using a different
@@ -101,52 +182,31 @@ impl State {
             source: wgpu::ShaderSource::Wgsl(include_str!("../shaders/test.wgsl").into()),
         });
 
-        let (camera_controller, camera_bind_group, camera_bind_group_layout) = {
-            let camera = Camera {
-                // position the camera 1 unit up and 2 units back
-                // +z is out of the screen
-                eye: (0.0, 1.0, 2.0).into(),
-                // have it look at the origin
-                target: (0.0, 0.0, 0.0).into(),
-                // which way is "up"
-                up: cgmath::Vector3::unit_y(),
-                aspect: config.width as f32 / config.height as f32,
-                fovy: 45.0,
-                znear: 0.1,
-                zfar: 100.0,
-                camera_buffer: None,
-            };
-            let mut camera_controller = CameraController::new(0.1, camera);
-
-            let camera_bind_group_layout =
-                device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    entries: &[wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::VERTEX,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    }],
-                    label: Some("camera_bind_group_layout"),
-                });
-
-            let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                layout: &camera_bind_group_layout,
-                entries: &[wgpu::BindGroupEntry {
+        let camera_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    resource: camera_controller.camera.create_binding_resource(&device),
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 }],
-                label: Some("camera_bind_group"),
+                label: Some("camera_bind_group_layout"),
             });
-            (
-                camera_controller,
-                camera_bind_group,
-                camera_bind_group_layout,
-            )
-        };
+
+        let viewports = settings
+            .viewport_layout
+            .rects()
+            .into_iter()
+            .enumerate()
+            .map(|(index, rect)| {
+                let camera = default_camera(index, &settings);
+                Viewport::new(&device, &config, &camera_bind_group_layout, rect, camera)
+            })
+            .collect();
 
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -155,57 +215,42 @@ impl State {
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[super::model::ModelVertex::desc(), super::Instance::desc()],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState {
-                        color: wgpu::BlendComponent::REPLACE,
-                        alpha: wgpu::BlendComponent::REPLACE,
-                    }),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: None,
-                // Setting this to anything other than Fill requires Features::POLYGON_MODE_LINE
-                // or Features::POLYGON_MODE_POINT
-                polygon_mode: wgpu::PolygonMode::Fill,
-                // Requires Features::DEPTH_CLIP_CONTROL
-                unclipped_depth: false,
-                // Requires Features::CONSERVATIVE_RASTERIZATION
-                conservative: false,
-            },
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: super::Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less, // 1.
-                stencil: wgpu::StencilState::default(),     // 2.
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            // If the pipeline will be used with a multiview render pass, this
-            // indicates how many array layers the attachments will have.
-            multiview: None,
-        });
+        let render_pipeline = create_render_pipeline(
+            &device,
+            &render_pipeline_layout,
+            &shader,
+            "vs_main",
+            &[super::model::ModelVertex::desc(), super::Instance::desc()],
+            config.format,
+            settings.depth_mode,
+        );
 
-        let depth_texture = super::Texture::create_depth_texture(&device, &config, "depth_texture");
+        // The GPU culled pipeline reads the instances from storage buffers
+        let (gpu_culler, indirect_pipeline) = if settings.culling == CullingMode::Gpu {
+            let gpu_culler = GpuCuller::new(&device);
+            let indirect_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Indirect Render Pipeline Layout"),
+                    bind_group_layouts: &[
+                        &texture_bind_group_layout,
+                        &camera_bind_group_layout,
+                        &gpu_culler.instances_bind_group_layout,
+                    ],
+                    push_constant_ranges: &[],
+                });
+            let indirect_pipeline = create_render_pipeline(
+                &device,
+                &indirect_pipeline_layout,
+                &shader,
+                "vs_main_indirect",
+                &[super::model::ModelVertex::desc()],
+                config.format,
+                settings.depth_mode,
+            );
+            (Some(gpu_culler), Some(indirect_pipeline))
+        } else {
+            (None, None)
+        };
 
         let instances_vec = Instance::create_lots(
             NUM_INSTANCES_PER_ROW as usize,
@@ -213,10 +258,40 @@ impl State {
             &device,
         );
 
-        let obj_model =
-            super::resources::load_model("cube.obj", &device, &queue, &texture_bind_group_layout)
-                .await
-                .unwrap();
+        // Models load in the background, a placeholder cube is drawn meanwhile
+        let loader = AssetLoader::new(
+            LOADER_THREADS,
+            device.clone(),
+            queue.clone(),
+            texture_bind_group_layout.clone(),
+            Arc::new(AssetManager::new()),
+        );
+        let placeholder =
+            || placeholder_model(&device, &queue, &texture_bind_group_layout, loader.assets());
+        let obj_model = placeholder();
+        let pending_obj_model = Some(loader.load_model_with_lods(MODEL_FILE, &LOD_RATIOS));
+
+        let instance_bvh = InstanceBvh::build(obj_model.meshes[0].aabb, instances_vec.instances());
+
+        let mut scene = SceneGraph::new();
+        let (robot_arm, arm_model, pending_arm_model) = if settings.robot_arm {
+            let arm_model = scene.add_model(Handle::new(placeholder()));
+            (
+                Some(RobotArm::build(&mut scene, arm_model)),
+                Some(arm_model),
+                Some(loader.load_model(MODEL_FILE)),
+            )
+        } else {
+            (None, None, None)
+        };
+        let hot_reloader = settings.hot_reload.then(|| {
+            let hot_reloader = HotReloader::new(HOT_RELOAD_INTERVAL);
+            // Files added later can change what a load finds, e.g. LOD files
+            for dir in vfs::current().search_paths() {
+                hot_reloader.watch_dir(dir.clone());
+            }
+            hot_reloader
+        });
 
         Self {
             surface,
@@ -225,13 +300,29 @@ impl State {
             size,
             config,
             render_pipeline,
+            gpu_culler,
+            indirect_pipeline,
             window,
-            diffuse_bind_group,
-            camera_controller,
-            camera_bind_group,
+            diffuse_material,
+            viewports,
+            active_viewport: 0,
             instances_vec,
-            depth_texture,
+            instance_bvh,
             obj_model,
+            loader,
+            pending_obj_model,
+            arm_model,
+            pending_arm_model,
+            hot_reloader,
+            lod_selector: LodSelector::default(),
+            cursor_position: None,
+            camera_recorder: None,
+            camera_player: None,
+            last_update: Instant::now(),
+            settings,
+            scene,
+            scene_instances: SceneInstances::default(),
+            robot_arm,
         }
     }
 
@@ -246,17 +337,230 @@ impl State {
             self.config.height = new_size.height;
             self.surface.configure(&self.device, &self.config);
         }
-        self.depth_texture =
-            super::Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
+        for viewport in &mut self.viewports {
+            viewport.resize(&self.device, &self.config);
+        }
+    }
+
+    /// Topmost viewport under the pixel `(x, y)` of the surface.
+    fn viewport_at(&self, x: f32, y: f32) -> Option<usize> {
+        self.viewports.iter().rposition(|viewport| {
+            viewport
+                .pixel_rect(&self.config)
+                .is_some_and(|pixels| pixels.contains(x, y))
+        })
     }
 
     fn input(&mut self, event: &WindowEvent) -> bool {
-        self.camera_controller.process_events(event)
+        match event {
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor_position = Some(*position);
+                if let Some(index) = self.viewport_at(position.x as f32, position.y as f32) {
+                    self.active_viewport = index;
+                }
+                true
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                ..
+            } => {
+                self.pick();
+                true
+            }
+            WindowEvent::KeyboardInput {
+                event: key_event, ..
+            } if key_event.state == ElementState::Pressed => {
+                match key_event.key_without_modifiers() {
+                    Key::Character(code) if code.as_str() == "r" => {
+                        self.toggle_camera_recording();
+                        true
+                    }
+                    Key::Character(code) if code.as_str() == "p" => {
+                        self.toggle_camera_playback();
+                        true
+                    }
+                    _ => self.viewports[self.active_viewport]
+                        .camera_controller
+                        .process_events(event),
+                }
+            }
+            _ => self.viewports[self.active_viewport]
+                .camera_controller
+                .process_events(event),
+        }
+    }
+
+    fn toggle_camera_recording(&mut self) {
+        match self.camera_recorder.take() {
+            Some(recorder) => {
+                let path = recorder.finish(&self.viewports[self.active_viewport].camera_controller);
+                match path.save(CAMERA_PATH_FILE) {
+                    Ok(()) => log::info!(
+                        "Saved {} camera keyframes to {}",
+                        path.keyframes.len(),
+                        CAMERA_PATH_FILE
+                    ),
+                    Err(err) => log::error!("Could not save camera path: {err}"),
+                }
+            }
+            None => {
+                log::info!("Recording camera path");
+                self.camera_recorder = Some(CameraRecorder::new(CAMERA_RECORD_INTERVAL));
+            }
+        }
+    }
+
+    fn toggle_camera_playback(&mut self) {
+        if self.camera_player.take().is_some() {
+            return;
+        }
+        match CameraPath::load(CAMERA_PATH_FILE) {
+            Ok(path) => {
+                self.camera_player = Some(CameraPlayer::new(path, CAMERA_PLAYBACK_STEP, false))
+            }
+            Err(err) => log::error!("Could not load camera path: {err}"),
+        }
+    }
+
+    fn pick(&self) {
+        let Some(cursor) = self.cursor_position else {
+            return;
+        };
+        let (x, y) = (cursor.x as f32, cursor.y as f32);
+        let Some(viewport) = self.viewport_at(x, y).map(|index| &self.viewports[index]) else {
+            return;
+        };
+        let Some(pixels) = viewport.pixel_rect(&self.config) else {
+            return;
+        };
+        let ray = viewport
+            .camera_controller
+            .camera
+            .screen_to_ray((x - pixels.x as f32, y - pixels.y as f32).into());
+        let mesh = &self.obj_model.meshes[0];
+        match self
+            .instance_bvh
+            .pick(&ray, mesh, self.instances_vec.instances())
+        {
+            Some(hit) => log::info!("Picked instance {} at {:?}", hit.instance, hit.point),
+            None => log::info!("Nothing picked"),
+        }
+    }
+
+    // Swaps the meshes and materials of the placeholders, or of the models being
+    // reloaded, for the ones that changed in the models that finished loading.
+    // A model that fails to load is kept.
+    fn poll_loads(&mut self) {
+        let mut finished = false;
+        let finished_obj_model = take_finished(&mut self.pending_obj_model);
+        if let Some(pending) = finished_obj_model {
+            if let Some(hot_reloader) = &mut self.hot_reloader {
+                hot_reloader.set_files(ReloadTarget::ObjModel, pending.files());
+            }
+            finished = true;
+            match pending.take().expect("finished job without a result") {
+                // Failures are logged by the loader
+                Err(_) => {}
+                Ok(model) if model.meshes.is_empty() => log::warn!("{MODEL_FILE} has no meshes"),
+                Ok(model) => {
+                    let picked_mesh_changed =
+                        !Handle::ptr_eq(&self.obj_model.meshes[0], &model.meshes[0]);
+                    let replaced = self.obj_model.update_from(model);
+                    log::debug!("{MODEL_FILE}: {replaced} meshes and materials replaced");
+                    if picked_mesh_changed {
+                        self.instance_bvh = InstanceBvh::build(
+                            self.obj_model.meshes[0].aabb,
+                            self.instances_vec.instances(),
+                        );
+                    }
+                }
+            }
+        }
+        let finished_arm_model = take_finished(&mut self.pending_arm_model);
+        if let (Some(id), Some(pending)) = (self.arm_model, finished_arm_model) {
+            if let Some(hot_reloader) = &mut self.hot_reloader {
+                hot_reloader.set_files(ReloadTarget::ArmModel, pending.files());
+            }
+            if let Some(Ok(model)) = pending.take() {
+                self.scene.model_mut(id).update_from(Model::clone(&model));
+            }
+            finished = true;
+        }
+        if finished {
+            // The replaced models may have held the last handles to some assets
+            self.loader.assets().purge();
+        }
+        let progress = self.loader.progress();
+        if finished && progress.is_complete() {
+            log::info!(
+                "Loaded {} assets, {} failed: {:?}",
+                progress.loaded,
+                progress.failed,
+                self.loader.assets().stats()
+            );
+        }
+    }
+
+    // Starts loading again the models whose files changed
+    fn poll_reloads(&mut self) {
+        let Some(hot_reloader) = &self.hot_reloader else {
+            return;
+        };
+        let (changes, targets) = hot_reloader.poll();
+        if changes.is_empty() {
+            return;
+        }
+        log::info!("Reloading {changes:?}");
+        // The cached copies are out of date
+        self.loader.assets().forget_files(&changes);
+        for target in targets {
+            match target {
+                ReloadTarget::ObjModel => {
+                    self.pending_obj_model =
+                        Some(self.loader.load_model_with_lods(MODEL_FILE, &LOD_RATIOS));
+                }
+                ReloadTarget::ArmModel => {
+                    self.pending_arm_model = Some(self.loader.load_model(MODEL_FILE));
+                }
+            }
+        }
     }
 
     fn update(&mut self) {
-        self.camera_controller.update_camera();
-        self.camera_controller.update_camera_buffer(&self.queue);
+        let now = Instant::now();
+        let dt = (now - self.last_update).as_secs_f32();
+        self.last_update = now;
+
+        self.poll_loads();
+        self.poll_reloads();
+
+        for (index, viewport) in self.viewports.iter_mut().enumerate() {
+            let controller = &mut viewport.camera_controller;
+            match self.camera_player.as_mut() {
+                Some(player) if index == self.active_viewport => {
+                    if !player.advance(&mut controller.camera) {
+                        self.camera_player = None;
+                    }
+                }
+                _ => controller.update_camera(),
+            }
+            if index == self.active_viewport {
+                if let Some(recorder) = self.camera_recorder.as_mut() {
+                    recorder.record(dt, controller);
+                }
+            }
+            controller.update_camera_buffer(&self.queue);
+        }
+        self.instance_bvh.sync(&self.instances_vec);
+        self.instances_vec.upload(&self.device, &self.queue);
+
+        if let Some(robot_arm) = &mut self.robot_arm {
+            robot_arm.animate(&mut self.scene, dt);
+        }
+        self.scene.update();
+        self.scene_instances
+            .upload(&self.device, &self.queue, &self.scene);
     }
 
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
@@ -271,26 +575,90 @@ impl State {
                 label: Some("Render Encoder"),
             });
 
-        {
+        let model_sphere = self.obj_model.bounding_sphere();
+        // The GPU culling only draws the most detailed level
+        let use_lods = self.obj_model.lod_count() > 1 && self.settings.culling != CullingMode::Gpu;
+        let instance_counts = self
+            .viewports
+            .iter_mut()
+            .map(|viewport| {
+                let camera = &viewport.camera_controller.camera;
+                let frustum = camera.frustum();
+                match (self.settings.culling, &self.gpu_culler) {
+                    _ if use_lods => {
+                        viewport.lod_batcher.update(
+                            &self.device,
+                            &self.queue,
+                            &self.lod_selector,
+                            camera,
+                            (self.settings.culling == CullingMode::Cpu).then_some(&frustum),
+                            &model_sphere,
+                            &self.instances_vec,
+                            self.obj_model.lod_count(),
+                        );
+                        // Each level has its own count
+                        0
+                    }
+                    (CullingMode::Cpu, _) => viewport.culler.cull(
+                        &self.device,
+                        &self.queue,
+                        &frustum,
+                        &model_sphere,
+                        &self.instances_vec,
+                    ),
+                    (CullingMode::Gpu, Some(gpu_culler)) => {
+                        let target = viewport.gpu_cull_target.get_or_insert_with(|| {
+                            GpuCullTarget::new(
+                                &self.device,
+                                gpu_culler,
+                                &self.instances_vec,
+                                self.obj_model.meshes.len(),
+                            )
+                        });
+                        target.cull(
+                            &self.device,
+                            &self.queue,
+                            &mut encoder,
+                            gpu_culler,
+                            &frustum,
+                            &model_sphere,
+                            &self.obj_model.meshes,
+                            &self.instances_vec,
+                        );
+                        // The count only exists on the GPU
+                        0
+                    }
+                    _ => self.instances_vec.len() as u32,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for (index, viewport) in self.viewports.iter().enumerate() {
+            // The first viewport clears the whole surface, the others draw over it
+            let color_load = if index == 0 {
+                wgpu::LoadOp::Clear(wgpu::Color {
+                    r: 0.1,
+                    g: 0.2,
+                    b: 0.3,
+                    a: 1.0,
+                })
+            } else {
+                wgpu::LoadOp::Load
+            };
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
                     view: &view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
+                        load: color_load,
                         store: wgpu::StoreOp::Store,
                     },
                 })],
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture.view,
+                    view: &viewport.depth_texture.view,
                     depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
+                        load: wgpu::LoadOp::Clear(viewport.depth_mode().clear_value()),
                         store: wgpu::StoreOp::Store,
                     }),
                     stencil_ops: None,
@@ -299,22 +667,80 @@ impl State {
                 timestamp_writes: None,
             });
 
-            render_pass.set_pipeline(&self.render_pipeline);
-
-            render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
-            render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
-            render_pass.set_vertex_buffer(1, self.instances_vec.buffer.slice(..));
+            // Empty viewports still begin their pass so the first one clears
+            if !viewport.apply(&mut render_pass, &self.config) {
+                continue;
+            }
+            render_pass.set_bind_group(0, &self.diffuse_material.bind_group, &[]);
+            render_pass.set_bind_group(1, &viewport.camera_bind_group, &[]);
             use super::model::DrawModel;
 
-            let instances_range = 0..self.instances_vec.instances.len() as u32;
-            let mesh = &self.obj_model.meshes[0];
-            let material = &self.obj_model.materials[mesh.material];
-            render_pass.draw_mesh_instanced(
-                &self.obj_model.meshes[0],
-                material,
-                instances_range,
-                &self.camera_bind_group,
-            );
+            if let (Some(pipeline), Some(target)) =
+                (&self.indirect_pipeline, &viewport.gpu_cull_target)
+            {
+                render_pass.set_pipeline(pipeline);
+                render_pass.set_bind_group(2, &target.render_bind_group, &[]);
+                for (mesh_index, mesh) in self.obj_model.meshes.iter().enumerate() {
+                    render_pass.draw_mesh_indirect(
+                        mesh,
+                        &self.obj_model.materials[mesh.material],
+                        &target.indirect_buffer,
+                        GpuCullTarget::draw_offset(mesh_index),
+                        &viewport.camera_bind_group,
+                    );
+                }
+            } else if use_lods {
+                render_pass.set_pipeline(&self.render_pipeline);
+                for (level, (buffer, count)) in viewport.lod_batcher.batches().enumerate() {
+                    if count == 0 {
+                        continue;
+                    }
+                    render_pass.set_vertex_buffer(1, buffer.buffer.slice(..));
+                    for mesh in self.obj_model.lod_meshes(level) {
+                        render_pass.draw_mesh_instanced(
+                            mesh,
+                            &self.obj_model.materials[mesh.material],
+                            0..count,
+                            &viewport.camera_bind_group,
+                        );
+                    }
+                }
+            } else {
+                render_pass.set_pipeline(&self.render_pipeline);
+                let instance_buffer = match self.settings.culling {
+                    CullingMode::Cpu => &viewport.culler.buffer,
+                    _ => self.instances_vec.buffer(),
+                };
+                render_pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
+
+                for mesh in &self.obj_model.meshes {
+                    render_pass.draw_mesh_instanced(
+                        mesh,
+                        &self.obj_model.materials[mesh.material],
+                        0..instance_counts[index],
+                        &viewport.camera_bind_group,
+                    );
+                }
+            }
+
+            // Scene graph nodes, one instanced draw per mesh of each model
+            render_pass.set_pipeline(&self.render_pipeline);
+            for (model, (buffer, count)) in
+                self.scene.models.iter().zip(self.scene_instances.buffers())
+            {
+                if count == 0 {
+                    continue;
+                }
+                render_pass.set_vertex_buffer(1, buffer.buffer.slice(..));
+                for mesh in &model.meshes {
+                    render_pass.draw_mesh_instanced(
+                        mesh,
+                        &model.materials[mesh.material],
+                        0..count,
+                        &viewport.camera_bind_group,
+                    );
+                }
+            }
         }
 
         self.queue.submit(iter::once(encoder.finish()));
@@ -324,10 +750,161 @@ impl State {
     }
 }
 
-fn create_texture(
+/// Camera used by the viewport `index` of the layout in `settings`.
+// Takes the job out of `pending` once it's finished
+fn take_finished<T>(pending: &mut Option<Pending<T>>) -> Option<Pending<T>> {
+    match pending {
+        Some(job) if job.is_finished() => pending.take(),
+        _ => None,
+    }
+}
+
+fn default_camera(index: usize, settings: &RenderSettings) -> Camera {
+    let (eye, up) = match (settings.viewport_layout, index) {
+        // Looking at the scene from the opposite side
+        (ViewportLayout::SideBySide, 1) => ((0.0, 1.0, -2.0), cgmath::Vector3::unit_y()),
+        // Straight down, "up" can't be parallel to the view direction
+        (ViewportLayout::Inset, 1) => ((0.0, 25.0, 0.0), -cgmath::Vector3::unit_z()),
+        // position the camera 1 unit up and 2 units back
+        // +z is out of the screen
+        _ => ((0.0, 1.0, 2.0), cgmath::Vector3::unit_y()),
+    };
+    Camera {
+        eye: eye.into(),
+        // have it look at the origin
+        target: (0.0, 0.0, 0.0).into(),
+        up,
+        // aspect and viewport_size are set by the viewport
+        aspect: 1.0,
+        fovy: 45.0,
+        znear: 0.1,
+        zfar: 100.0,
+        viewport_size: (1.0, 1.0).into(),
+        depth_mode: settings.depth_mode,
+        camera_buffer: None,
+    }
+}
+
+/// Three segments joined by a rotating shoulder and a bending elbow. Each
+/// segment is a scaled cube on its own node, so scaling a segment doesn't
+/// stretch the ones attached to it.
+struct RobotArm {
+    shoulder: NodeId,
+    elbow: NodeId,
+    time: f32,
+}
+
+impl RobotArm {
+    fn build(scene: &mut SceneGraph, model: ModelId) -> Self {
+        // The cube spans -1..1, it's moved so a segment starts at its joint
+        let segment = |scene: &mut SceneGraph, name: &str, parent, length: f32, width, tint| {
+            let local = NodeTransform::from_position((0.0, length * 0.5, 0.0).into())
+                .with_scale((width, length * 0.5, width).into());
+            let node = scene.add_node(name, local, Some(parent));
+            scene.attach_model(node, Some(model));
+            scene.set_tint(node, tint);
+        };
+
+        let base = scene.add_node(
+            "base",
+            NodeTransform::from_position((-6.0, 0.0, -6.0).into()),
+            None,
+        );
+        segment(scene, "base_segment", base, 0.5, 1.0, [0.4, 0.4, 0.4, 1.0]);
+        let shoulder = scene.add_node(
+            "shoulder",
+            NodeTransform::from_position((0.0, 0.5, 0.0).into()),
+            Some(base),
+        );
+        segment(scene, "upper_arm", shoulder, 3.0, 0.3, [1.0, 0.6, 0.2, 1.0]);
+        let elbow = scene.add_node(
+            "elbow",
+            NodeTransform::from_position((0.0, 3.0, 0.0).into()),
+            Some(shoulder),
+        );
+        segment(scene, "forearm", elbow, 2.0, 0.2, [0.2, 0.6, 1.0, 1.0]);
+
+        Self {
+            shoulder,
+            elbow,
+            time: 0.0,
+        }
+    }
+
+    fn animate(&mut self, scene: &mut SceneGraph, dt: f32) {
+        use cgmath::Rotation3;
+        self.time += dt;
+        scene.update_local(self.shoulder, |local| {
+            local.rotation = cgmath::Quaternion::from_angle_y(cgmath::Deg(30.0 * self.time));
+        });
+        // The elbow swings back and forth, the forearm follows the upper arm
+        scene.update_local(self.elbow, |local| {
+            local.rotation = cgmath::Quaternion::from_angle_z(cgmath::Deg(60.0 * self.time.sin()));
+        });
+    }
+}
+
+fn create_render_pipeline(
     device: &wgpu::Device,
-    queue: &wgpu::Queue,
-) -> (wgpu::BindGroupLayout, wgpu::BindGroup) {
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    vertex_entry_point: &str,
+    buffers: &[wgpu::VertexBufferLayout],
+    format: wgpu::TextureFormat,
+    depth_mode: DepthMode,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(vertex_entry_point),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: vertex_entry_point,
+            buffers,
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState {
+                    color: wgpu::BlendComponent::REPLACE,
+                    alpha: wgpu::BlendComponent::REPLACE,
+                }),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            // Setting this to anything other than Fill requires Features::POLYGON_MODE_LINE
+            // or Features::POLYGON_MODE_POINT
+            polygon_mode: wgpu::PolygonMode::Fill,
+            // Requires Features::DEPTH_CLIP_CONTROL
+            unclipped_depth: false,
+            // Requires Features::CONSERVATIVE_RASTERIZATION
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: super::Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: depth_mode.compare_function(), // 1.
+            stencil: wgpu::StencilState::default(),       // 2.
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        // If the pipeline will be used with a multiview render pass, this
+        // indicates how many array layers the attachments will have.
+        multiview: None,
+    })
+}
+
+fn create_texture(device: &wgpu::Device, queue: &wgpu::Queue) -> (wgpu::BindGroupLayout, Material) {
     let img = include_bytes!("../assets/happy-tree.png");
 
     let diffuse_texture =
@@ -353,33 +930,41 @@ fn create_texture(
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                // MaterialFactors
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
             label: Some("texture_bind_group_layout"),
         });
-    let diffuse_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &texture_bind_group_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(&diffuse_texture.view), // CHANGED!
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler), // CHANGED!
-            },
-        ],
-        label: Some("diffuse_bind_group"),
-    });
-    (texture_bind_group_layout, diffuse_bind_group)
+    let diffuse_material = Material::new(
+        device,
+        &texture_bind_group_layout,
+        "diffuse_bind_group",
+        Handle::new(diffuse_texture),
+        MaterialFactors::default(),
+    );
+    (texture_bind_group_layout, diffuse_material)
 }
 
 pub async fn run() -> anyhow::Result<()> {
+    run_with(RenderSettings::default()).await
+}
+
+pub async fn run_with(settings: RenderSettings) -> anyhow::Result<()> {
     env_logger::init();
     let event_loop = EventLoop::new().unwrap();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
 
     // State::new uses async code, so we're going to wait for it to finish
-    let mut state = State::new(window).await;
+    let mut state = State::new(window, settings).await;
     event_loop.run(move |event, loop_window| {
         match event {
             Event::WindowEvent {
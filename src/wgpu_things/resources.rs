@@ -1,23 +1,18 @@
-use std::io::{BufReader, Cursor};
-use wgpu::util::DeviceExt;
-
-use super::{model, Texture};
+use super::{
+    assets::{AssetManager, Handle},
+    gltf_loader, mesh_cache, model, simplify, vfs, Texture,
+};
+use std::path::PathBuf;
 
+/// Reads a file through the global `vfs::Vfs`.
 pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
-    let path = std::path::Path::new(env!("OUT_DIR"))
-        .join("res")
-        .join(file_name);
-    let txt = std::fs::read_to_string(path)?;
+    let txt = String::from_utf8(load_binary(file_name).await?)?;
 
     Ok(txt)
 }
 
 pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
-    let path = std::path::Path::new(env!("OUT_DIR"))
-        .join("res")
-        .join(file_name);
-    let data = std::fs::read(path)?;
-    Ok(data)
+    vfs::read(file_name)
 }
 
 pub async fn load_texture(
@@ -29,98 +24,154 @@ pub async fn load_texture(
     Texture::from_bytes(device, queue, &data, file_name)
 }
 
+// Uploads the meshes of an OBJ file that aren't in `assets` yet
+fn create_meshes(
+    device: &wgpu::Device,
+    assets: &AssetManager,
+    file_name: &str,
+    meshes: Vec<mesh_cache::CachedMesh>,
+    material_count: usize,
+) -> Vec<Handle<model::Mesh>> {
+    let files = vfs::resolve(file_name).into_iter().collect::<Vec<_>>();
+    meshes
+        .into_iter()
+        .enumerate()
+        .map(|(index, mesh)| {
+            // Meshes without a usable material get the default one added after the others
+            let material = mesh
+                .material
+                .filter(|&id| id < material_count)
+                .unwrap_or(material_count);
+            // The material index comes from the MTL files, which the mesh isn't cached with
+            let key = format!("{file_name}#mesh{index}@{material}");
+            assets.mesh(&key).unwrap_or_else(|| {
+                let mesh =
+                    model::Mesh::new(device, file_name, mesh.vertices, mesh.indices, material);
+                assets.insert_mesh(&key, mesh, files.clone())
+            })
+        })
+        .collect()
+}
+
+/// Loads an OBJ or glTF model. Meshes, materials and textures come from
+/// `assets` so models loaded from the same files share them.
 pub async fn load_model(
     file_name: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     layout: &wgpu::BindGroupLayout,
+    assets: &AssetManager,
 ) -> anyhow::Result<model::Model> {
-    let obj_text = load_string(file_name).await?;
-    let obj_cursor = Cursor::new(obj_text);
-    let mut obj_reader = BufReader::new(obj_cursor);
-
-    let (models, obj_materials) = tobj::load_obj_buf_async(
-        &mut obj_reader,
-        &tobj::LoadOptions {
-            triangulate: true,
-            single_index: true,
-            ..Default::default()
-        },
-        |p| {
-            async move {
-                let mat_text = load_string(&p).await.unwrap();
-                tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
-            }
-        },
-    )
-    .await?;
+    if gltf_loader::is_gltf(file_name) {
+        return gltf_loader::load_gltf(file_name, device, queue, layout, assets).await;
+    }
+    let recording = vfs::Recording::start();
+    let (obj_meshes, obj_materials) = mesh_cache::load_obj(file_name).await?;
+    // The OBJ and MTL files
+    let source_files = recording.finish();
 
     let mut materials = Vec::new();
-    for m in obj_materials? {
-        let diffuse_texture = load_texture(&m.diffuse_texture, device, queue).await?;
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+    for (index, m) in obj_materials.into_iter().enumerate() {
+        let key = format!("{file_name}#material{index}");
+        if let Some(material) = assets.material(&key) {
+            materials.push(material);
+            continue;
+        }
+        let recording = vfs::Recording::start();
+        let diffuse_texture = if m.diffuse_texture.is_empty() {
+            assets.white_texture(device, queue)
+        } else {
+            assets
+                .load_texture(&m.diffuse_texture, device, queue)
+                .await?
+        };
+        let files = recording.finish().into_iter().chain(source_files.clone());
+        let material = model::Material::new(
+            device,
             layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                },
-            ],
-            label: None,
-        });
-
-        materials.push(model::Material {
-            name: m.name,
+            &m.name,
             diffuse_texture,
-            bind_group,
-        })
+            model::MaterialFactors::from_mtl(&m),
+        );
+        materials.push(assets.insert_material(&key, material, files.collect()));
     }
 
-    let meshes = models
-        .into_iter()
-        .map(|m| {
-            let vertices = (0..m.mesh.positions.len() / 3)
-                .map(|i| {
-                    model::ModelVertex {
-                        position: [
-                            m.mesh.positions[i * 3],
-                            m.mesh.positions[i * 3 + 1],
-                            m.mesh.positions[i * 3 + 2],
-                        ],
-                        tex_coords: [m.mesh.texcoords[i * 2], 1.0 - m.mesh.texcoords[i * 2 + 1]],
-                        normal: [
-                            m.mesh.normals[i * 3],
-                            m.mesh.normals[i * 3 + 1],
-                            m.mesh.normals[i * 3 + 2],
-                        ],
-                    }
-                })
-                .collect::<Vec<_>>();
+    let meshes = create_meshes(device, assets, file_name, obj_meshes, materials.len());
+    if meshes.iter().any(|mesh| mesh.material == materials.len()) {
+        materials.push(default_material(device, queue, layout, assets));
+    }
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", file_name)),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", file_name)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
-            });
+    Ok(model::Model {
+        meshes,
+        materials,
+        lods: Vec::new(),
+    })
+}
 
-            model::Mesh {
-                name: file_name.to_string(),
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
-            }
-        })
-        .collect::<Vec<_>>();
+// Material of the meshes without one, the same for every model
+fn default_material(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    assets: &AssetManager,
+) -> Handle<model::Material> {
+    const KEY: &str = "#default";
+    assets.material(KEY).unwrap_or_else(|| {
+        let material = model::Material::new(
+            device,
+            layout,
+            "default",
+            assets.white_texture(device, queue),
+            model::MaterialFactors::default(),
+        );
+        assets.insert_material(KEY, material, Vec::new())
+    })
+}
 
-    Ok(model::Model { meshes, materials })
+/// Loads a model with one LOD per ratio. Level `i` comes from the file written by
+/// the `simplify_obj` tool (e.g. `cube_lod1.obj`) when there is one, otherwise
+/// the meshes are simplified on load.
+pub async fn load_model_with_lods(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    ratios: &[f32],
+    assets: &AssetManager,
+) -> anyhow::Result<model::Model> {
+    let mut model = load_model(file_name, device, queue, layout, assets).await?;
+    for (index, &ratio) in ratios.iter().enumerate() {
+        let lod_file_name = simplify::lod_file_name(file_name, index + 1);
+        let lod_meshes = async {
+            let (meshes, materials) = mesh_cache::load_obj(&lod_file_name).await?;
+            anyhow::Ok(create_meshes(
+                device,
+                assets,
+                &lod_file_name,
+                meshes,
+                materials.len(),
+            ))
+        };
+        let meshes = match lod_meshes.await {
+            Ok(meshes) => meshes,
+            Err(err) => {
+                log::debug!("No {lod_file_name} ({err}), simplifying {file_name}");
+                let files: Vec<PathBuf> = vfs::resolve(file_name).into_iter().collect();
+                model
+                    .meshes
+                    .iter()
+                    .enumerate()
+                    .map(|(mesh_index, mesh)| {
+                        let key = format!("{file_name}#mesh{mesh_index}@{}x{ratio}", mesh.material);
+                        assets.mesh(&key).unwrap_or_else(|| {
+                            let simplified = simplify::simplify_mesh(device, mesh, ratio);
+                            assets.insert_mesh(&key, simplified, files.clone())
+                        })
+                    })
+                    .collect()
+            }
+        };
+        model.lods.push(meshes);
+    }
+    Ok(model)
 }
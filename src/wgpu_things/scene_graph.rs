@@ -0,0 +1,394 @@
+use super::{assets::Handle, instance_draw::InstanceRaw, model::Model, Instance, InstanceBuffer};
+use cgmath::prelude::*;
+
+/// Position, rotation and scale of a node relative to its parent.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NodeTransform {
+    pub position: cgmath::Vector3<f32>,
+    pub rotation: cgmath::Quaternion<f32>,
+    pub scale: cgmath::Vector3<f32>,
+}
+
+impl NodeTransform {
+    pub const IDENTITY: Self = Self {
+        position: cgmath::Vector3::new(0.0, 0.0, 0.0),
+        rotation: cgmath::Quaternion::new(1.0, 0.0, 0.0, 0.0),
+        scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+    };
+
+    pub fn from_position(position: cgmath::Vector3<f32>) -> Self {
+        Self {
+            position,
+            ..Self::IDENTITY
+        }
+    }
+
+    pub fn with_rotation(mut self, rotation: cgmath::Quaternion<f32>) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: cgmath::Vector3<f32>) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn to_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.position)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+impl Default for NodeTransform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ModelId(usize);
+
+pub struct Node {
+    pub name: String,
+    local: NodeTransform,
+    world: cgmath::Matrix4<f32>,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    /// Model drawn with the world transform of the node.
+    pub model: Option<ModelId>,
+    pub tint: [f32; 4],
+    // The local transform or the parent changed since the last update
+    dirty: bool,
+}
+
+impl Node {
+    pub fn local(&self) -> &NodeTransform {
+        &self.local
+    }
+
+    /// World transform as of the last `SceneGraph::update`.
+    pub fn world(&self) -> cgmath::Matrix4<f32> {
+        self.world
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+}
+
+/// Tree of nodes with transforms relative to their parent. Nodes without a
+/// parent are placed in world space. World matrices are only recomputed for
+/// nodes whose transform, or the transform of one of their ancestors, changed.
+#[derive(Default)]
+pub struct SceneGraph {
+    // Removed nodes leave a hole so the ids of the others stay valid
+    nodes: Vec<Option<Node>>,
+    roots: Vec<NodeId>,
+    pub models: Vec<Handle<Model>>,
+    // Bumped whenever the instances drawn by the scene change
+    version: u64,
+}
+
+impl SceneGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The same model can be added to several scenes, see `AssetManager::load_model`.
+    pub fn add_model(&mut self, model: Handle<Model>) -> ModelId {
+        self.models.push(model);
+        self.version += 1;
+        ModelId(self.models.len() - 1)
+    }
+
+    pub fn model(&self, id: ModelId) -> &Model {
+        &self.models[id.0]
+    }
+
+    /// Replaces a model, e.g. a placeholder once the real one is loaded. The
+    /// nodes using it draw the new one.
+    pub fn set_model(&mut self, id: ModelId, model: Handle<Model>) {
+        self.models[id.0] = model;
+        self.version += 1;
+    }
+
+    /// The model is copied first if it's shared with other scenes, copies
+    /// are cheap since they share their meshes and materials.
+    pub fn model_mut(&mut self, id: ModelId) -> &mut Model {
+        Handle::make_mut(&mut self.models[id.0])
+    }
+
+    pub fn add_node(&mut self, name: &str, local: NodeTransform, parent: Option<NodeId>) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Some(Node {
+            name: name.to_string(),
+            local,
+            world: cgmath::Matrix4::identity(),
+            parent,
+            children: Vec::new(),
+            model: None,
+            tint: Instance::WHITE,
+            dirty: true,
+        }));
+        match parent {
+            Some(parent) => self.node_mut(parent).children.push(id),
+            None => self.roots.push(id),
+        }
+        id
+    }
+
+    /// Panics if the node was removed.
+    pub fn node(&self, id: NodeId) -> &Node {
+        self.nodes[id.0].as_ref().expect("node was removed")
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        self.nodes[id.0].as_mut().expect("node was removed")
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.nodes.get(id.0).is_some_and(Option::is_some)
+    }
+
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.iter()
+            .find(|(_, node)| node.name == name)
+            .map(|(id, _)| id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Node)> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, node)| Some((NodeId(index), node.as_ref()?)))
+    }
+
+    pub fn attach_model(&mut self, id: NodeId, model: Option<ModelId>) {
+        self.node_mut(id).model = model;
+        self.version += 1;
+    }
+
+    pub fn set_tint(&mut self, id: NodeId, tint: [f32; 4]) {
+        self.node_mut(id).tint = tint;
+        self.version += 1;
+    }
+
+    pub fn set_local(&mut self, id: NodeId, local: NodeTransform) {
+        let node = self.node_mut(id);
+        node.local = local;
+        node.dirty = true;
+    }
+
+    pub fn update_local(&mut self, id: NodeId, f: impl FnOnce(&mut NodeTransform)) {
+        let node = self.node_mut(id);
+        f(&mut node.local);
+        node.dirty = true;
+    }
+
+    /// Moves `id` under `parent`, or to the top level with `None`. The local
+    /// transform is kept, so the node moves along with its new parent.
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) -> anyhow::Result<()> {
+        if let Some(parent) = parent {
+            if self.is_ancestor(id, parent) {
+                anyhow::bail!(
+                    "{:?} can't become a child of its descendant {:?}",
+                    self.node(id).name,
+                    self.node(parent).name
+                );
+            }
+        }
+
+        self.detach(id);
+        let node = self.node_mut(id);
+        node.parent = parent;
+        node.dirty = true;
+        match parent {
+            Some(parent) => self.node_mut(parent).children.push(id),
+            None => self.roots.push(id),
+        }
+        Ok(())
+    }
+
+    /// True if `ancestor` is `id` or one of its ancestors.
+    pub fn is_ancestor(&self, ancestor: NodeId, id: NodeId) -> bool {
+        let mut current = Some(id);
+        while let Some(node) = current {
+            if node == ancestor {
+                return true;
+            }
+            current = self.node(node).parent;
+        }
+        false
+    }
+
+    fn detach(&mut self, id: NodeId) {
+        let siblings = match self.node(id).parent {
+            Some(parent) => &mut self.node_mut(parent).children,
+            None => &mut self.roots,
+        };
+        siblings.retain(|&sibling| sibling != id);
+    }
+
+    /// Removes the node and all of its descendants.
+    pub fn remove(&mut self, id: NodeId) {
+        self.detach(id);
+        let mut stack = vec![id];
+        while let Some(current) = stack.pop() {
+            if let Some(node) = self.nodes[current.0].take() {
+                stack.extend(node.children);
+            }
+        }
+        self.version += 1;
+    }
+
+    /// Recomputes the world matrices of the dirty nodes and their descendants.
+    pub fn update(&mut self) {
+        let mut stack = self
+            .roots
+            .iter()
+            .map(|&root| (root, cgmath::Matrix4::identity(), false))
+            .collect::<Vec<_>>();
+        while let Some((id, parent_world, parent_changed)) = stack.pop() {
+            let node = self.nodes[id.0].as_mut().expect("node was removed");
+            let changed = node.dirty || parent_changed;
+            if changed {
+                node.world = parent_world * node.local.to_matrix();
+                node.dirty = false;
+                if node.model.is_some() {
+                    self.version += 1;
+                }
+            }
+            let world = node.world;
+            stack.extend(node.children.iter().map(|&child| (child, world, changed)));
+        }
+    }
+
+    /// Changes whenever `instances_by_model` does, as of the last `update`.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// World transforms of the nodes with a model, grouped by model.
+    pub fn instances_by_model(&self) -> Vec<Vec<InstanceRaw>> {
+        let mut instances = vec![Vec::new(); self.models.len()];
+        for (_, node) in self.iter() {
+            if let Some(model) = node.model {
+                instances[model.0].push(InstanceRaw::new(node.world, node.tint, [0.0; 4]));
+            }
+        }
+        instances
+    }
+}
+
+/// GPU copy of the scene graph nodes, one instance buffer per model.
+#[derive(Default)]
+pub struct SceneInstances {
+    buffers: Vec<(InstanceBuffer, u32)>,
+    // `SceneGraph::version` of the uploaded instances
+    uploaded: Option<u64>,
+}
+
+impl SceneInstances {
+    /// Writes the instances again when the scene changed since the last upload.
+    pub fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, scene: &SceneGraph) {
+        if self.uploaded == Some(scene.version()) {
+            return;
+        }
+        self.uploaded = Some(scene.version());
+        let instances = scene.instances_by_model();
+        self.buffers.resize_with(instances.len(), || {
+            (InstanceBuffer::new(device, 0, "Scene Instance Buffer"), 0)
+        });
+        for ((buffer, count), instances) in self.buffers.iter_mut().zip(instances) {
+            buffer.reserve(device, instances.len());
+            buffer.write(queue, 0, &instances);
+            *count = instances.len() as u32;
+        }
+    }
+
+    /// Instance buffer and instance count of every model, in `SceneGraph::models` order.
+    pub fn buffers(&self) -> impl Iterator<Item = (&InstanceBuffer, u32)> {
+        self.buffers.iter().map(|(buffer, count)| (buffer, *count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translation(world: cgmath::Matrix4<f32>) -> cgmath::Vector3<f32> {
+        world.w.truncate()
+    }
+
+    #[test]
+    fn children_follow_their_parent() {
+        let mut scene = SceneGraph::new();
+        let root = scene.add_node(
+            "root",
+            NodeTransform::from_position(cgmath::Vector3::new(1.0, 0.0, 0.0))
+                .with_scale(cgmath::Vector3::new(2.0, 2.0, 2.0)),
+            None,
+        );
+        let child = scene.add_node(
+            "child",
+            NodeTransform::from_position(cgmath::Vector3::new(0.0, 1.0, 0.0)),
+            Some(root),
+        );
+        scene.update();
+        assert_eq!(
+            translation(scene.node(child).world()),
+            cgmath::Vector3::new(1.0, 2.0, 0.0)
+        );
+
+        scene.update_local(root, |local| local.position.x = 5.0);
+        scene.update();
+        assert_eq!(
+            translation(scene.node(child).world()),
+            cgmath::Vector3::new(5.0, 2.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn version_only_changes_with_drawn_nodes() {
+        let mut scene = SceneGraph::new();
+        let root = scene.add_node("root", NodeTransform::IDENTITY, None);
+        let arm = scene.add_node("arm", NodeTransform::IDENTITY, Some(root));
+        scene.attach_model(arm, Some(ModelId(0)));
+        scene.update();
+
+        let clean = scene.version();
+        scene.update();
+        assert_eq!(scene.version(), clean);
+
+        // Moving the parent moves the drawn child
+        scene.update_local(root, |local| local.position.y = 1.0);
+        assert_eq!(scene.version(), clean);
+        scene.update();
+        assert_ne!(scene.version(), clean);
+
+        let moved = scene.version();
+        scene.set_tint(arm, [1.0, 0.0, 0.0, 1.0]);
+        assert_ne!(scene.version(), moved);
+    }
+
+    #[test]
+    fn nodes_cant_become_their_own_descendants() {
+        let mut scene = SceneGraph::new();
+        let root = scene.add_node("root", NodeTransform::IDENTITY, None);
+        let child = scene.add_node("child", NodeTransform::IDENTITY, Some(root));
+        assert!(scene.set_parent(root, Some(child)).is_err());
+        assert!(scene.set_parent(root, Some(root)).is_err());
+
+        scene.remove(root);
+        assert!(!scene.contains(child));
+        assert_eq!(scene.find("root"), None);
+    }
+}
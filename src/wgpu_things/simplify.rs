@@ -0,0 +1,479 @@
+//! Mesh simplification by quadric error metric edge collapses (Garland and
+//! Heckbert), used to build LOD chains.
+//!
+//! Vertices are collapsed onto one of their neighbours, so the remaining ones
+//! keep their exact position, UVs and normal. Vertices split on UV seams are
+//! only collapsed along the seam, onto a vertex split the same way, which keeps
+//! the texture layout intact.
+
+use super::{
+    assets::Handle,
+    mesh_processing,
+    model::{Mesh, Model, ModelVertex},
+};
+use cgmath::prelude::*;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BinaryHeap, HashMap},
+};
+
+// Open borders are held in place by planes this much stiffer than the faces
+const BORDER_WEIGHT: f64 = 100.0;
+
+/// Symmetric 4x4 matrix summing the squared distances to a set of planes.
+#[derive(Debug, Copy, Clone, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn from_plane(normal: cgmath::Vector3<f64>, distance: f64, weight: f64) -> Self {
+        let [a, b, c, d] = [normal.x, normal.y, normal.z, distance];
+        Self(
+            [
+                a * a,
+                a * b,
+                a * c,
+                a * d,
+                b * b,
+                b * c,
+                b * d,
+                c * c,
+                c * d,
+                d * d,
+            ]
+            .map(|v| v * weight),
+        )
+    }
+
+    fn add(&self, other: &Quadric) -> Quadric {
+        let mut sum = *self;
+        for (a, b) in sum.0.iter_mut().zip(other.0) {
+            *a += b;
+        }
+        sum
+    }
+
+    fn error(&self, p: cgmath::Point3<f64>) -> f64 {
+        let [a2, ab, ac, ad, b2, bc, bd, c2, cd, d2] = self.0;
+        let (x, y, z) = (p.x, p.y, p.z);
+        a2 * x * x
+            + b2 * y * y
+            + c2 * z * z
+            + d2
+            + 2.0 * (ab * x * y + ac * x * z + bc * y * z + ad * x + bd * y + cd * z)
+    }
+}
+
+/// Moving every vertex at position `from` onto position `to`.
+#[derive(Debug, Copy, Clone)]
+struct Collapse {
+    cost: f64,
+    from: u32,
+    to: u32,
+    // Versions of the positions when the cost was computed
+    from_version: u32,
+    to_version: u32,
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Collapse {
+    // Reversed so the cheapest collapse is on top of the heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+struct Simplifier<'a> {
+    vertices: &'a [ModelVertex],
+    // Position of each vertex, vertices split on seams share one
+    position_of: Vec<u32>,
+    points: Vec<cgmath::Point3<f64>>,
+    quadrics: Vec<Quadric>,
+    // Triangles touching each position, removed ones are dropped lazily
+    incident: Vec<Vec<u32>>,
+    removed: Vec<bool>,
+    versions: Vec<u32>,
+    triangles: Vec<[u32; 3]>,
+    live: Vec<bool>,
+    live_count: usize,
+    heap: BinaryHeap<Collapse>,
+}
+
+impl<'a> Simplifier<'a> {
+    fn new(vertices: &'a [ModelVertex], indices: &[u32]) -> Self {
+        let mut positions = HashMap::new();
+        let mut points = Vec::new();
+        let position_of = vertices
+            .iter()
+            .map(|vertex| {
+                *positions
+                    .entry(vertex.position.map(f32::to_bits))
+                    .or_insert_with(|| {
+                        points.push(cgmath::Point3::from(vertex.position).cast().unwrap());
+                        points.len() as u32 - 1
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        let triangles = indices
+            .chunks_exact(3)
+            .map(|tri| [tri[0], tri[1], tri[2]])
+            .filter(|tri| {
+                let [a, b, c] = tri.map(|i| position_of[i as usize]);
+                a != b && b != c && a != c
+            })
+            .collect::<Vec<_>>();
+
+        let mut simplifier = Self {
+            vertices,
+            quadrics: vec![Quadric::default(); points.len()],
+            incident: vec![Vec::new(); points.len()],
+            removed: vec![false; points.len()],
+            versions: vec![0; points.len()],
+            position_of,
+            points,
+            live: vec![true; triangles.len()],
+            live_count: triangles.len(),
+            triangles,
+            heap: BinaryHeap::new(),
+        };
+        simplifier.init_quadrics();
+        simplifier
+    }
+
+    fn corners(&self, triangle: usize) -> [u32; 3] {
+        self.triangles[triangle].map(|i| self.position_of[i as usize])
+    }
+
+    fn init_quadrics(&mut self) {
+        // How many triangles use each edge, to find the open borders
+        let mut edges = BTreeMap::<(u32, u32), Vec<usize>>::new();
+        for triangle in 0..self.triangles.len() {
+            let corners = self.corners(triangle);
+            let [a, b, c] = corners.map(|p| self.points[p as usize]);
+            let normal = (b - a).cross(c - a);
+            let area = normal.magnitude() * 0.5;
+            if area > 0.0 {
+                let normal = normal.normalize();
+                let quadric = Quadric::from_plane(normal, -normal.dot(a.to_vec()), area);
+                for p in corners {
+                    self.quadrics[p as usize] = self.quadrics[p as usize].add(&quadric);
+                }
+            }
+            for (i, &p) in corners.iter().enumerate() {
+                self.incident[p as usize].push(triangle as u32);
+                let q = corners[(i + 1) % 3];
+                edges
+                    .entry((p.min(q), p.max(q)))
+                    .or_default()
+                    .push(triangle);
+            }
+        }
+
+        for (&(p, q), triangles) in &edges {
+            if triangles.len() != 1 {
+                continue;
+            }
+            let [a, b, c] = self.corners(triangles[0]).map(|p| self.points[p as usize]);
+            let face_normal = (b - a).cross(c - a);
+            let edge = self.points[q as usize] - self.points[p as usize];
+            // Plane going through the edge, perpendicular to the face
+            let normal = edge.cross(face_normal);
+            if normal.magnitude2() == 0.0 {
+                continue;
+            }
+            let normal = normal.normalize();
+            let point = self.points[p as usize].to_vec();
+            let quadric = Quadric::from_plane(
+                normal,
+                -normal.dot(point),
+                edge.magnitude2() * BORDER_WEIGHT,
+            );
+            for p in [p, q] {
+                self.quadrics[p as usize] = self.quadrics[p as usize].add(&quadric);
+            }
+        }
+
+        for (p, q) in edges.into_keys() {
+            self.push(p, q);
+            self.push(q, p);
+        }
+    }
+
+    fn push(&mut self, from: u32, to: u32) {
+        let cost = self.quadrics[from as usize]
+            .add(&self.quadrics[to as usize])
+            .error(self.points[to as usize]);
+        self.heap.push(Collapse {
+            cost,
+            from,
+            to,
+            from_version: self.versions[from as usize],
+            to_version: self.versions[to as usize],
+        });
+    }
+
+    fn live_incident(&self, position: u32) -> impl Iterator<Item = usize> + '_ {
+        self.incident[position as usize]
+            .iter()
+            .map(|&t| t as usize)
+            .filter(|&t| self.live[t])
+    }
+
+    /// Which vertex of `to` replaces each vertex of `from`, `None` when the
+    /// collapse would tear a seam or fold triangles over.
+    fn vertex_map(&self, from: u32, to: u32) -> Option<Vec<(u32, u32)>> {
+        let mut map = Vec::<(u32, u32)>::new();
+        // Triangles on the collapsed edge tell which vertices belong together
+        for t in self.live_incident(from) {
+            let tri = self.triangles[t];
+            let corners = self.corners(t);
+            let Some(j) = corners.iter().position(|&p| p == to) else {
+                continue;
+            };
+            let i = corners.iter().position(|&p| p == from)?;
+            match map.iter().find(|(v, _)| *v == tri[i]) {
+                Some(&(_, w)) if w != tri[j] => return None,
+                Some(_) => {}
+                None => map.push((tri[i], tri[j])),
+            }
+        }
+        if map.is_empty() {
+            return None;
+        }
+
+        let to_point = self.points[to as usize];
+        for t in self.live_incident(from) {
+            let tri = self.triangles[t];
+            let corners = self.corners(t);
+            if corners.contains(&to) {
+                continue;
+            }
+            let i = corners.iter().position(|&p| p == from)?;
+            // A vertex that doesn't share a triangle with `to` is across a seam
+            map.iter().find(|(v, _)| *v == tri[i])?;
+
+            let points = corners.map(|p| self.points[p as usize]);
+            let mut moved = points;
+            moved[i] = to_point;
+            let before = (points[1] - points[0]).cross(points[2] - points[0]);
+            let after = (moved[1] - moved[0]).cross(moved[2] - moved[0]);
+            if before.dot(after) <= 0.0 {
+                return None;
+            }
+        }
+        Some(map)
+    }
+
+    fn collapse(&mut self, from: u32, to: u32, map: &[(u32, u32)]) {
+        let incident = std::mem::take(&mut self.incident[from as usize]);
+        for &t in &incident {
+            let t = t as usize;
+            if !self.live[t] {
+                continue;
+            }
+            if self.corners(t).contains(&to) {
+                self.live[t] = false;
+                self.live_count -= 1;
+                continue;
+            }
+            for vertex in &mut self.triangles[t] {
+                if let Some(&(_, replacement)) = map.iter().find(|(v, _)| v == vertex) {
+                    *vertex = replacement;
+                }
+            }
+        }
+
+        self.removed[from as usize] = true;
+        self.quadrics[to as usize] = self.quadrics[to as usize].add(&self.quadrics[from as usize]);
+        self.versions[to as usize] += 1;
+        let live = &self.live;
+        let to_incident = &mut self.incident[to as usize];
+        to_incident.extend(incident);
+        to_incident.retain(|&t| live[t as usize]);
+
+        let mut neighbours = self
+            .live_incident(to)
+            .flat_map(|t| self.corners(t))
+            .filter(|&p| p != to)
+            .collect::<Vec<_>>();
+        neighbours.sort_unstable();
+        neighbours.dedup();
+        for p in neighbours {
+            self.push(to, p);
+            self.push(p, to);
+        }
+    }
+
+    fn run(&mut self, target_triangles: usize) {
+        while self.live_count > target_triangles {
+            let Some(collapse) = self.heap.pop() else {
+                break;
+            };
+            let (from, to) = (collapse.from as usize, collapse.to as usize);
+            if self.removed[from]
+                || self.removed[to]
+                || self.versions[from] != collapse.from_version
+                || self.versions[to] != collapse.to_version
+            {
+                continue;
+            }
+            if let Some(map) = self.vertex_map(collapse.from, collapse.to) {
+                self.collapse(collapse.from, collapse.to, &map);
+            }
+        }
+    }
+
+    fn finish(self) -> (Vec<ModelVertex>, Vec<u32>) {
+        let mut remap = HashMap::new();
+        let mut vertices = Vec::new();
+        let mut indices = Vec::with_capacity(self.live_count * 3);
+        for (triangle, live) in self.triangles.iter().zip(&self.live) {
+            if !live {
+                continue;
+            }
+            for &vertex in triangle {
+                let index = *remap.entry(vertex).or_insert_with(|| {
+                    vertices.push(self.vertices[vertex as usize]);
+                    vertices.len() as u32 - 1
+                });
+                indices.push(index);
+            }
+        }
+        (vertices, indices)
+    }
+}
+
+/// Collapses edges until at most `target_ratio` of the triangles are left, or
+/// no more edge can go without tearing a seam or folding triangles over.
+/// Unused vertices are dropped from the result.
+pub fn simplify(
+    vertices: &[ModelVertex],
+    indices: &[u32],
+    target_ratio: f32,
+) -> (Vec<ModelVertex>, Vec<u32>) {
+    let mut simplifier = Simplifier::new(vertices, indices);
+    let target = (simplifier.live_count as f32 * target_ratio.clamp(0.0, 1.0)).ceil() as usize;
+    simplifier.run(target);
+    simplifier.finish()
+}
+
+pub fn simplify_mesh(device: &wgpu::Device, mesh: &Mesh, target_ratio: f32) -> Mesh {
+    let (vertices, indices) = simplify(&mesh.vertices, &mesh.indices, target_ratio);
+    let name = format!("{} ({:.0}%)", mesh.name, target_ratio * 100.0);
+    let (vertices, indices) = mesh_processing::process(&name, &vertices, indices);
+    Mesh::new(device, &name, vertices, indices, mesh.material)
+}
+
+/// Replaces the LODs of the model with simplified copies of its meshes, one
+/// level per ratio of the original triangle count.
+pub fn generate_lods(device: &wgpu::Device, model: &mut Model, ratios: &[f32]) {
+    model.lods = ratios
+        .iter()
+        .map(|&ratio| {
+            model
+                .meshes
+                .iter()
+                .map(|mesh| Handle::new(simplify_mesh(device, mesh, ratio)))
+                .collect()
+        })
+        .collect();
+}
+
+/// Name of the file holding the LOD `level` of a model, `cube.obj` gives
+/// `cube_lod1.obj` for level 1.
+pub fn lod_file_name(file_name: &str, level: usize) -> String {
+    let path = std::path::Path::new(file_name);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(file_name);
+    let name = match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => format!("{stem}_lod{level}.{extension}"),
+        None => format!("{stem}_lod{level}"),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wgpu_things::primitives;
+
+    fn triangle_normals(vertices: &[ModelVertex], indices: &[u32]) -> Vec<cgmath::Vector3<f32>> {
+        indices
+            .chunks_exact(3)
+            .map(|tri| {
+                let [a, b, c] = [tri[0], tri[1], tri[2]]
+                    .map(|i| cgmath::Vector3::from(vertices[i as usize].position));
+                (b - a).cross(c - a) * 0.5
+            })
+            .collect()
+    }
+
+    fn contains(vertices: &[ModelVertex], vertex: &ModelVertex) -> bool {
+        let key = bytemuck::bytes_of(vertex);
+        vertices.iter().any(|v| bytemuck::bytes_of(v) == key)
+    }
+
+    #[test]
+    fn flat_grids_keep_their_outline() {
+        let plane = primitives::plane(2.0, 2.0, 16, 16);
+        assert_eq!(plane.indices.len() / 3, 512);
+        let (vertices, indices) = simplify(&plane.vertices, &plane.indices, 0.1);
+
+        assert!(indices.len() / 3 <= 52, "{} triangles", indices.len() / 3);
+        let normals = triangle_normals(&vertices, &indices);
+        // Nothing folded over and the area is still covered once
+        assert!(normals
+            .iter()
+            .all(|n| n.y > 0.0 && n.x == 0.0 && n.z == 0.0));
+        let area: f32 = normals.iter().map(|n| n.y).sum();
+        assert!((area - 4.0).abs() < 1e-4, "area {area}");
+    }
+
+    #[test]
+    fn kept_vertices_are_unchanged() {
+        let sphere = primitives::uv_sphere(1.0, 24, 12);
+        let (vertices, indices) = simplify(&sphere.vertices, &sphere.indices, 0.25);
+
+        let before = sphere.indices.len() / 3;
+        assert!(indices.len() / 3 <= before.div_ceil(4));
+        assert!(indices.len() / 3 > 0);
+        assert!(vertices.iter().all(|v| contains(&sphere.vertices, v)));
+        // Every vertex is used and no triangle is degenerate
+        let mut used = vec![false; vertices.len()];
+        for tri in indices.chunks_exact(3) {
+            assert!(tri[0] != tri[1] && tri[1] != tri[2] && tri[0] != tri[2]);
+            tri.iter().for_each(|&i| used[i as usize] = true);
+        }
+        assert!(used.into_iter().all(|used| used));
+    }
+
+    #[test]
+    fn full_ratio_keeps_every_triangle() {
+        let cube = primitives::cube(1.0);
+        let (vertices, indices) = simplify(&cube.vertices, &cube.indices, 1.0);
+        assert_eq!(indices.len(), cube.indices.len());
+        assert_eq!(vertices.len(), cube.vertices.len());
+    }
+
+    #[test]
+    fn lod_files_are_named_after_the_model() {
+        assert_eq!(lod_file_name("cube.obj", 1), "cube_lod1.obj");
+        assert_eq!(lod_file_name("res/arm.v2.obj", 3), "res/arm.v2_lod3.obj");
+        assert_eq!(lod_file_name("mesh", 2), "mesh_lod2");
+    }
+}
@@ -18,6 +18,18 @@ impl Texture {
         Self::from_image(device, queue, &img, Some(label))
     }
 
+    /// 1x1 texture of a single sRGB colour, stands in for missing textures.
+    pub fn from_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        rgba: [u8; 4],
+        label: &str,
+    ) -> Self {
+        let img =
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(rgba)));
+        Self::from_image(device, queue, &img, Some(label)).expect("1x1 texture")
+    }
+
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
@@ -83,6 +95,7 @@ impl Texture {
         device: &wgpu::Device,
         config: &wgpu::SurfaceConfiguration,
         label: &str,
+        depth_mode: super::camera::DepthMode,
     ) -> Self {
         let size = wgpu::Extent3d {
             // 2.
@@ -112,7 +125,7 @@ impl Texture {
             mag_filter: wgpu::FilterMode::Linear,
             min_filter: wgpu::FilterMode::Linear,
             mipmap_filter: wgpu::FilterMode::Nearest,
-            compare: Some(wgpu::CompareFunction::LessEqual), // 5.
+            compare: Some(depth_mode.sampler_compare_function()), // 5.
             lod_min_clamp: 0.0,
             lod_max_clamp: 100.0,
             ..Default::default()
@@ -0,0 +1,202 @@
+//! Where asset files are read from.
+//!
+//! Files are looked up in the search paths in order, the first one being the
+//! asset root, then in the copy of `res/` compiled into the binary when the
+//! `embed-assets` feature is enabled. Files on disk win over embedded ones so
+//! a shipped binary can still have some of its assets replaced.
+//!
+//! All the loaders go through the global `Vfs`, set it with `configure`
+//! before loading anything.
+
+use anyhow::Context;
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+    sync::{OnceLock, RwLock},
+};
+
+#[cfg(feature = "embed-assets")]
+static EMBEDDED: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/embedded_assets.rs"));
+#[cfg(not(feature = "embed-assets"))]
+static EMBEDDED: &[(&str, &[u8])] = &[];
+
+#[derive(Debug, Clone)]
+pub struct Vfs {
+    search_paths: Vec<PathBuf>,
+    use_embedded: bool,
+}
+
+impl Default for Vfs {
+    /// `res` next to the executable, then `res` in the working directory.
+    fn default() -> Self {
+        let mut vfs = Self::new();
+        if let Some(exe_dir) = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_path_buf))
+        {
+            vfs.add_search_path(exe_dir.join("res"));
+        }
+        vfs.add_search_path("res");
+        vfs
+    }
+}
+
+impl Vfs {
+    /// No search paths, only the embedded files if there are any.
+    pub fn new() -> Self {
+        Self {
+            search_paths: Vec::new(),
+            use_embedded: true,
+        }
+    }
+
+    /// Makes `root` the first search path.
+    pub fn set_asset_root(&mut self, root: impl Into<PathBuf>) {
+        self.search_paths.insert(0, root.into());
+    }
+
+    pub fn add_search_path(&mut self, path: impl Into<PathBuf>) {
+        self.search_paths.push(path.into());
+    }
+
+    pub fn search_paths(&self) -> &[PathBuf] {
+        &self.search_paths
+    }
+
+    /// Whether the files compiled in with `embed-assets` are used.
+    pub fn set_use_embedded(&mut self, use_embedded: bool) {
+        self.use_embedded = use_embedded;
+    }
+
+    /// Path of the file on disk. Absolute paths are used as they are.
+    pub fn resolve(&self, file_name: &str) -> Option<PathBuf> {
+        let path = Path::new(file_name);
+        if path.is_absolute() {
+            return path.is_file().then(|| path.to_path_buf());
+        }
+        self.search_paths
+            .iter()
+            .map(|dir| dir.join(path))
+            .find(|path| path.is_file())
+    }
+
+    fn embedded(&self, file_name: &str) -> Option<&'static [u8]> {
+        if !self.use_embedded {
+            return None;
+        }
+        // Embedded names always use forward slashes and no `./`
+        let name = file_name.replace('\\', "/");
+        let name = name.trim_start_matches("./");
+        EMBEDDED
+            .iter()
+            .find(|(embedded, _)| *embedded == name)
+            .map(|(_, data)| *data)
+    }
+
+    pub fn exists(&self, file_name: &str) -> bool {
+        self.resolve(file_name).is_some() || self.embedded(file_name).is_some()
+    }
+
+    pub fn read(&self, file_name: &str) -> anyhow::Result<Vec<u8>> {
+        if let Some(path) = self.resolve(file_name) {
+            let data =
+                std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+            note_reads(std::slice::from_ref(&path));
+            return Ok(data);
+        }
+        if let Some(data) = self.embedded(file_name) {
+            return Ok(data.to_vec());
+        }
+        anyhow::bail!(
+            "{file_name} not found in {:?}{}",
+            self.search_paths,
+            if self.use_embedded && !EMBEDDED.is_empty() {
+                " or the embedded assets"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+thread_local! {
+    // One list per `Recording` alive on the thread, innermost last
+    static RECORDINGS: RefCell<Vec<Vec<PathBuf>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Collects the files read from disk on this thread while it's alive, e.g. to
+/// know which files to watch for changes. Embedded files aren't included.
+/// Recordings can be nested, the outer ones also get the files of the inner
+/// ones. Works across `.await` as long as the future stays on this thread.
+pub struct Recording {
+    depth: usize,
+}
+
+impl Recording {
+    pub fn start() -> Self {
+        let depth = RECORDINGS.with_borrow_mut(|recordings| {
+            recordings.push(Vec::new());
+            recordings.len()
+        });
+        Self { depth }
+    }
+
+    /// The files read since `start`, sorted and without duplicates.
+    pub fn finish(self) -> Vec<PathBuf> {
+        let mut files = RECORDINGS.with_borrow_mut(|recordings| {
+            debug_assert_eq!(
+                recordings.len(),
+                self.depth,
+                "recordings finished out of order"
+            );
+            recordings.pop().unwrap_or_default()
+        });
+        std::mem::forget(self);
+        files.sort();
+        files.dedup();
+        note_reads(&files);
+        files
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        RECORDINGS.with_borrow_mut(|recordings| recordings.truncate(self.depth - 1));
+    }
+}
+
+/// Adds files to the current recording, for assets that come from a cache
+/// instead of being read again.
+pub fn note_reads(files: &[PathBuf]) {
+    RECORDINGS.with_borrow_mut(|recordings| {
+        if let Some(recording) = recordings.last_mut() {
+            recording.extend(files.iter().cloned());
+        }
+    });
+}
+
+fn global() -> &'static RwLock<Vfs> {
+    static VFS: OnceLock<RwLock<Vfs>> = OnceLock::new();
+    VFS.get_or_init(|| RwLock::new(Vfs::default()))
+}
+
+/// Replaces the global `Vfs`.
+pub fn configure(vfs: Vfs) {
+    *global().write().unwrap() = vfs;
+}
+
+/// Copy of the global `Vfs`.
+pub fn current() -> Vfs {
+    global().read().unwrap().clone()
+}
+
+/// Reads a file through the global `Vfs`.
+pub fn read(file_name: &str) -> anyhow::Result<Vec<u8>> {
+    global().read().unwrap().read(file_name)
+}
+
+/// Path on disk of a file through the global `Vfs`, `None` for embedded or
+/// missing files.
+pub fn resolve(file_name: &str) -> Option<PathBuf> {
+    global().read().unwrap().resolve(file_name)
+}
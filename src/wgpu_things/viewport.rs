@@ -0,0 +1,250 @@
+use super::{
+    camera::{Camera, CameraController, DepthMode},
+    culling::InstanceCuller,
+    gpu_culling::GpuCullTarget,
+    lod::LodBatcher,
+    Texture,
+};
+
+/// Area of the surface covered by a viewport, in fractions of the surface size
+/// with the origin at the top left corner.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ViewportRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// Area of the surface covered by a viewport, in pixels.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PixelRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ViewportRect {
+    pub const FULL: ViewportRect = ViewportRect::new(0.0, 0.0, 1.0, 1.0);
+
+    pub const fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The part of the rect inside the surface, `None` when it doesn't cover any
+    /// pixel since wgpu rejects zero sized viewports.
+    pub fn to_pixels(&self, surface_width: u32, surface_height: u32) -> Option<PixelRect> {
+        // Both edges are clamped to the surface, rounding them instead of the
+        // size also lets neighbouring viewports share their border
+        let to_px = |fraction: f32, size: u32| ((fraction * size as f32).round() as u32).min(size);
+        let x = to_px(self.x, surface_width);
+        let y = to_px(self.y, surface_height);
+        let right = to_px(self.x + self.width, surface_width);
+        let bottom = to_px(self.y + self.height, surface_height);
+        (right > x && bottom > y).then(|| PixelRect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        })
+    }
+}
+
+impl PixelRect {
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x as f32
+            && y >= self.y as f32
+            && x < (self.x + self.width) as f32
+            && y < (self.y + self.height) as f32
+    }
+}
+
+/// Predefined ways of splitting the window between cameras.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ViewportLayout {
+    #[default]
+    Single,
+    /// Two cameras, each one using half of the window.
+    SideBySide,
+    /// A top down camera in the bottom right corner, over the main one.
+    Inset,
+}
+
+impl ViewportLayout {
+    pub fn rects(self) -> Vec<ViewportRect> {
+        match self {
+            ViewportLayout::Single => vec![ViewportRect::FULL],
+            ViewportLayout::SideBySide => vec![
+                ViewportRect::new(0.0, 0.0, 0.5, 1.0),
+                ViewportRect::new(0.5, 0.0, 0.5, 1.0),
+            ],
+            ViewportLayout::Inset => vec![
+                ViewportRect::FULL,
+                ViewportRect::new(0.72, 0.72, 0.25, 0.25),
+            ],
+        }
+    }
+}
+
+/// A camera drawing into a part of the surface. Each viewport has its own depth
+/// texture so overlapping viewports, like insets, start with a cleared depth.
+pub struct Viewport {
+    pub rect: ViewportRect,
+    pub camera_controller: CameraController,
+    pub camera_bind_group: wgpu::BindGroup,
+    pub depth_texture: Texture,
+    /// Instances visible from this viewport's camera.
+    pub culler: InstanceCuller,
+    /// Created on the first frame culled with `CullingMode::Gpu`.
+    pub gpu_cull_target: Option<GpuCullTarget>,
+    /// Instances sorted by detail level, used when the model has LODs.
+    pub lod_batcher: LodBatcher,
+}
+
+impl Viewport {
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        rect: ViewportRect,
+        camera: Camera,
+    ) -> Self {
+        let mut camera_controller = CameraController::new(0.1, camera);
+        if let Some(pixels) = rect.to_pixels(config.width, config.height) {
+            camera_controller.camera.resize(pixels.width, pixels.height);
+        }
+        let depth_mode = camera_controller.camera.depth_mode;
+
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: camera_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_controller.camera.create_binding_resource(device),
+            }],
+            label: Some("camera_bind_group"),
+        });
+        let depth_texture =
+            Texture::create_depth_texture(device, config, "viewport_depth_texture", depth_mode);
+
+        Self {
+            rect,
+            camera_controller,
+            camera_bind_group,
+            depth_texture,
+            culler: InstanceCuller::new(device),
+            gpu_cull_target: None,
+            lod_batcher: LodBatcher::new(),
+        }
+    }
+
+    pub fn depth_mode(&self) -> DepthMode {
+        self.camera_controller.camera.depth_mode
+    }
+
+    pub fn pixel_rect(&self, config: &wgpu::SurfaceConfiguration) -> Option<PixelRect> {
+        self.rect.to_pixels(config.width, config.height)
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        if let Some(pixels) = self.pixel_rect(config) {
+            self.camera_controller
+                .camera
+                .resize(pixels.width, pixels.height);
+        }
+        self.depth_texture = Texture::create_depth_texture(
+            device,
+            config,
+            "viewport_depth_texture",
+            self.depth_mode(),
+        );
+    }
+
+    /// Restricts the drawing of the render pass to this viewport. Returns false
+    /// when the viewport is empty and nothing should be drawn.
+    pub fn apply<'a>(
+        &self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        config: &wgpu::SurfaceConfiguration,
+    ) -> bool {
+        let Some(pixels) = self.pixel_rect(config) else {
+            return false;
+        };
+        render_pass.set_viewport(
+            pixels.x as f32,
+            pixels.y as f32,
+            pixels.width as f32,
+            pixels.height as f32,
+            0.0,
+            1.0,
+        );
+        render_pass.set_scissor_rect(pixels.x, pixels.y, pixels.width, pixels.height);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixels(x: u32, y: u32, width: u32, height: u32) -> Option<PixelRect> {
+        Some(PixelRect {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    #[test]
+    fn layouts_cover_the_surface() {
+        assert_eq!(
+            ViewportRect::FULL.to_pixels(800, 600),
+            pixels(0, 0, 800, 600)
+        );
+        let halves: Vec<_> = ViewportLayout::SideBySide
+            .rects()
+            .iter()
+            .map(|rect| rect.to_pixels(801, 600))
+            .collect();
+        // Odd widths don't leave a gap between the halves
+        assert_eq!(halves, [pixels(0, 0, 401, 600), pixels(401, 0, 400, 600)]);
+    }
+
+    #[test]
+    fn rects_are_clamped_to_the_surface() {
+        let surface = (200, 100);
+        let clamped = |x, y, width, height| {
+            ViewportRect::new(x, y, width, height).to_pixels(surface.0, surface.1)
+        };
+        assert_eq!(clamped(0.5, 0.5, 1.0, 1.0), pixels(100, 50, 100, 50));
+        assert_eq!(clamped(-0.5, -0.5, 1.0, 1.0), pixels(0, 0, 100, 50));
+        assert_eq!(clamped(0.0, 0.0, 2.0, 2.0), pixels(0, 0, 200, 100));
+        for (x, y, width, height) in [(1.0, 0.0, 0.5, 1.0), (0.0, 1.0, 1.0, 0.5)] {
+            assert_eq!(clamped(x, y, width, height), None);
+        }
+    }
+
+    #[test]
+    fn empty_rects_have_no_pixels() {
+        assert_eq!(
+            ViewportRect::new(0.2, 0.2, 0.0, 0.5).to_pixels(200, 100),
+            None
+        );
+        // Less than half a pixel
+        assert_eq!(
+            ViewportRect::new(0.0, 0.0, 0.002, 1.0).to_pixels(200, 100),
+            None
+        );
+        assert_eq!(ViewportRect::FULL.to_pixels(0, 0), None);
+        assert_eq!(
+            ViewportRect::new(0.5, 0.0, -0.2, 1.0).to_pixels(200, 100),
+            None
+        );
+    }
+}